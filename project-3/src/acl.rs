@@ -0,0 +1,129 @@
+use crate::{Error, Request, Result};
+use std::{fmt, path::Path, str::FromStr};
+
+/// What a rule lets a user do with the keys under its prefix. Each level
+/// includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+    /// Also the requests about the whole server, such as backups,
+    /// compaction and replication, when granted on every key.
+    Admin,
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Access::Read),
+            "write" => Ok(Access::Write),
+            "admin" => Ok(Access::Admin),
+            _ => Err(format!(
+                "unknown access {}, expected read, write or admin",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Rules granting authenticated users access to key prefixes. Anything
+/// not granted is denied, including everything to clients that did not
+/// authenticate.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    user: String,
+    prefix: String,
+    access: Access,
+}
+
+impl Acl {
+    /// Grants `user` `access` to the keys starting with `prefix`.
+    pub fn allow(
+        mut self,
+        user: impl Into<String>,
+        prefix: impl Into<String>,
+        access: Access,
+    ) -> Self {
+        self.rules.push(Rule {
+            user: user.into(),
+            prefix: prefix.into(),
+            access,
+        });
+        self
+    }
+
+    /// Reads a file of `USER ACCESS [PREFIX]` lines, where a missing prefix
+    /// stands for every key. Blank lines and lines starting with `#` are
+    /// skipped.
+    pub fn load(path: &Path) -> Result<Acl> {
+        let mut acl = Acl::default();
+        for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid =
+                |message: String| Error::Auth(format!("{}:{}: {}", path.display(), i + 1, message));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (user, access, prefix) = match fields[..] {
+                [user, access] => (user, access, ""),
+                [user, access, prefix] => (user, access, prefix),
+                _ => {
+                    return Err(invalid(
+                        "expected a user, an access and an optional prefix".to_owned(),
+                    ))
+                }
+            };
+            acl = acl.allow(user, prefix, access.parse().map_err(invalid)?);
+        }
+        Ok(acl)
+    }
+
+    /// Whether `user` may access every key starting with `key` this way.
+    pub fn allows(&self, user: &str, key: &str, access: Access) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.user == user && key.starts_with(&rule.prefix) && rule.access >= access)
+    }
+
+    /// Fails with `Error::PermissionDenied` unless `user` may access `key`.
+    pub(crate) fn check(&self, user: Option<&str>, key: &str, access: Access) -> Result<()> {
+        if user.is_some_and(|user| self.allows(user, key, access)) {
+            return Ok(());
+        }
+        let user = user.unwrap_or("anonymous");
+        Err(Error::PermissionDenied(if key.is_empty() {
+            format!("{} has no {} access", user, access)
+        } else {
+            format!("{} has no {} access to {:?}", user, access, key)
+        }))
+    }
+}
+
+/// The key, or prefix of keys, that `request` touches and the access it
+/// needs to them.
+pub(crate) fn required(request: &Request) -> (&str, Access) {
+    match request {
+        Request::Get { key } => (key, Access::Read),
+        Request::Set { key, .. } | Request::Remove { key } => (key, Access::Write),
+        Request::Scan { prefix } | Request::Watch { prefix, .. } => (prefix, Access::Read),
+        _ => ("", Access::Admin),
+    }
+}
@@ -0,0 +1,193 @@
+use crate::client::{no_leader, ELECTION_WAIT, REDIRECT_LIMIT};
+use crate::protocol::{self, Frame, OP_ERROR, OP_REQUEST, OP_RESPONSE};
+use crate::{Error as KvsError, Request, Response, Result, Stats};
+use std::io;
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time;
+
+/// The async counterpart of `Client`, speaking the binary protocol.
+pub struct AsyncClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+    /// Id of the last request sent.
+    id: u32,
+    /// Presented again after a redirect reconnects.
+    token: Option<String>,
+}
+
+impl AsyncClient {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        protocol::connect_async(&mut reader, &mut writer).await?;
+
+        Ok(AsyncClient {
+            reader,
+            writer,
+            id: 0,
+            token: None,
+        })
+    }
+
+    /// Connects like `connect`, then authenticates with `token`.
+    pub async fn connect_with_auth<A: ToSocketAddrs>(addr: A, token: String) -> Result<Self> {
+        let mut client = AsyncClient::connect(addr).await?;
+        client.auth(token).await?;
+        Ok(client)
+    }
+
+    /// Authenticates the connection with `token`.
+    pub async fn auth(&mut self, token: String) -> Result<()> {
+        self.send(&Request::Auth {
+            token: token.clone(),
+        })
+        .await?;
+        self.receive_ok().await?;
+        self.token = Some(token);
+        Ok(())
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        for _ in 0..REDIRECT_LIMIT {
+            self.send(&Request::Get { key: key.clone() }).await?;
+
+            match self.receive().await? {
+                Response::Value(value) => return Ok(value),
+                other => self.redirect_on(other.unexpected("a value")).await?,
+            }
+        }
+        Err(no_leader())
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        for _ in 0..REDIRECT_LIMIT {
+            let request = Request::Set {
+                key: key.clone(),
+                value: value.clone(),
+            };
+            self.send(&request).await?;
+
+            match self.receive().await? {
+                Response::Ok => return Ok(()),
+                other => self.redirect_on(other.unexpected("OK")).await?,
+            }
+        }
+        Err(no_leader())
+    }
+
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        for _ in 0..REDIRECT_LIMIT {
+            self.send(&Request::Remove { key: key.clone() }).await?;
+
+            match self.receive().await? {
+                Response::Ok => return Ok(()),
+                other => self.redirect_on(other.unexpected("OK")).await?,
+            }
+        }
+        Err(no_leader())
+    }
+
+    /// Reconnects to the cluster leader, or waits for one to be elected,
+    /// when `err` says this server is not the leader; fails with it otherwise.
+    async fn redirect_on(&mut self, err: KvsError) -> Result<()> {
+        let leader = match err {
+            KvsError::NotLeader(leader) => leader,
+            err => return Err(err),
+        };
+        let client = match leader {
+            Some(leader) => AsyncClient::connect(leader).await.ok(),
+            None => None,
+        };
+        match client {
+            Some(client) => {
+                let token = self.token.take();
+                *self = client;
+                if let Some(token) = token {
+                    self.auth(token).await?;
+                }
+            }
+            None => time::sleep(ELECTION_WAIT).await,
+        }
+        Ok(())
+    }
+
+    /// Reads a response that carries nothing but success.
+    async fn receive_ok(&mut self) -> Result<()> {
+        match self.receive().await? {
+            Response::Ok => Ok(()),
+            other => Err(other.unexpected("OK")),
+        }
+    }
+
+    /// Asks the server to back up its data into `path` on the server host.
+    pub async fn backup(&mut self, path: String) -> Result<()> {
+        self.send(&Request::Backup { path }).await?;
+
+        self.receive_ok().await
+    }
+
+    pub async fn stats(&mut self) -> Result<Stats> {
+        self.send(&Request::Stats).await?;
+
+        match self.receive().await? {
+            Response::Stats(stats) => Ok(stats),
+            other => Err(other.unexpected("statistics")),
+        }
+    }
+
+    pub async fn compact(&mut self) -> Result<()> {
+        self.send(&Request::Compact).await?;
+
+        self.receive_ok().await
+    }
+
+    /// Stops (`true`) or resumes (`false`) automatic compaction on the server.
+    pub async fn pause_compaction(&mut self, paused: bool) -> Result<()> {
+        self.send(&Request::PauseCompaction { paused }).await?;
+
+        self.receive_ok().await
+    }
+
+    /// Collects every pair whose key starts with `prefix`.
+    pub async fn scan(&mut self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.send(&Request::Scan { prefix }).await?;
+
+        let mut pairs = Vec::new();
+        loop {
+            match self.receive().await? {
+                Response::Entry { key, value } => pairs.push((key, value)),
+                Response::End => return Ok(pairs),
+                other => return Err(other.unexpected("a scan entry")),
+            }
+        }
+    }
+
+    async fn send(&mut self, request: &Request) -> Result<()> {
+        self.id = self.id.wrapping_add(1);
+        let frame = Frame::encode(OP_REQUEST, self.id, request)?;
+        frame.write_async(&mut self.writer).await?;
+        Ok(self.writer.flush().await?)
+    }
+
+    async fn receive(&mut self) -> Result<Response> {
+        let frame = Frame::read_async(&mut self.reader).await?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection")
+        })?;
+        if frame.id != self.id {
+            return Err(KvsError::Protocol(format!(
+                "response to request {} while waiting for {}",
+                frame.id, self.id
+            )));
+        }
+        match frame.opcode {
+            OP_RESPONSE => frame.decode(),
+            OP_ERROR => Err(KvsError::Protocol(
+                String::from_utf8_lossy(&frame.payload).into_owned(),
+            )),
+            opcode => Err(KvsError::Protocol(format!("unexpected opcode {}", opcode))),
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use crate::acl;
+use crate::engine::KeyBatches;
+use crate::limits::{timed_out, too_large};
+use crate::protocol::{self, Frame, OP_ERROR, OP_REQUEST, OP_RESPONSE};
+use crate::server::{
+    too_many_clients, DRAIN_TIMEOUT, MAINTENANCE_INTERVAL, MAX_REFUSALS, REFUSAL_WAIT,
+    SHUTDOWN_POLL,
+};
+use crate::{
+    backup, Acl, Auth, Engine, Error, Limits, Manifest, Request, Response, Result, ShutdownHandle,
+};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::{task, time};
+
+/// Requests of one connection answered at once. The next request is only
+/// read once one of them is done.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// A server that serves every connection as a task instead of a thread.
+///
+/// It speaks the binary protocol only, and answers the requests that need
+/// a replica, a cluster or a dedicated stream with an `OP_ERROR` frame.
+/// Engine calls run on tokio's blocking pool.
+pub struct AsyncServer<T: Engine> {
+    engine: Arc<Mutex<T>>,
+    manifest: Option<Manifest>,
+    auth: Option<Arc<Auth>>,
+    acl: Option<Arc<Acl>>,
+    backup_dir: Option<PathBuf>,
+    limits: Limits,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+}
+
+impl<T: Engine + 'static> AsyncServer<T> {
+    pub fn new(engine: T) -> Self {
+        AsyncServer {
+            engine: Arc::new(Mutex::new(engine)),
+            manifest: None,
+            auth: None,
+            acl: None,
+            backup_dir: None,
+            limits: Limits::default(),
+            shutdown: ShutdownHandle::default(),
+            drain_timeout: DRAIN_TIMEOUT,
+        }
+    }
+
+    /// Sets the manifest describing the served data, which is required for backups.
+    pub fn manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// Requires clients to authenticate with one of the tokens of `auth`
+    /// before anything else.
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    /// Only lets authenticated users do what `acl` grants them.
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Lets clients back up into new directories under `dir`, which is
+    /// required for backups over the network.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the limits put on clients.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how long a shutdown waits for the requests in flight.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Returns a handle that makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves every connection as a task until shut down, then lets the
+    /// requests in flight finish and syncs the engine.
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        // Connections stop reading once `stop` is sent, and `closed` ends
+        // when the last handler holding an `open` sender is gone.
+        let (stop, stopping) = watch::channel(false);
+        let (open, mut closed) = mpsc::channel::<()>(1);
+        // Cloned by every connection task, so their strong counts tell how
+        // many connections are served and how many are being refused.
+        let served = Arc::new(());
+        let refusing = Arc::new(());
+        let maintenance = tokio::spawn(maintain(Arc::clone(&self.engine), stopping.clone()));
+
+        while !self.shutdown.is_shutdown() {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = time::sleep(SHUTDOWN_POLL) => continue,
+            };
+            match accepted {
+                Ok((stream, peer)) => {
+                    let refused = self
+                        .limits
+                        .max_connections
+                        .is_some_and(|max| Arc::strong_count(&served) > max);
+                    let count = if !refused {
+                        Arc::clone(&served)
+                    } else if Arc::strong_count(&refusing) <= MAX_REFUSALS {
+                        warn!("refusing connection: too many clients");
+                        Arc::clone(&refusing)
+                    } else {
+                        warn!("dropping connection: too many clients");
+                        continue;
+                    };
+                    let handler = AsyncHandler {
+                        engine: Arc::clone(&self.engine),
+                        manifest: self.manifest.clone(),
+                        auth: self.auth.clone(),
+                        acl: self.acl.clone(),
+                        backup_dir: self.backup_dir.clone(),
+                        limits: self.limits.clone(),
+                        open: open.clone(),
+                        stopping: stopping.clone(),
+                    };
+                    tokio::spawn(async move {
+                        let _count = count;
+                        match handler.serve(stream, peer, refused).await {
+                            Ok(()) => {}
+                            Err(Error::IO(err)) if timed_out(&err) => {
+                                debug!("closed connection that timed out")
+                            }
+                            Err(err) => error!("serving failed: {}", err),
+                        }
+                    });
+                }
+                Err(err) => {
+                    error!("connection failed: {}", err);
+                }
+            }
+        }
+
+        info!("shutting down");
+        drop(listener);
+        let _ = stop.send(true);
+        let _ = maintenance.await;
+        drop(open);
+        if time::timeout(self.drain_timeout, closed.recv())
+            .await
+            .is_err()
+        {
+            warn!("closed connections with requests still in flight");
+        }
+        let engine = Arc::clone(&self.engine);
+        task::spawn_blocking(move || engine.lock().expect("engine lock poisoned").sync())
+            .await
+            .map_err(|err| Error::WithMessage(format!("engine task failed: {}", err)))??;
+        info!("engine synced");
+        Ok(())
+    }
+}
+
+struct AsyncHandler<T: Engine> {
+    engine: Arc<Mutex<T>>,
+    manifest: Option<Manifest>,
+    auth: Option<Arc<Auth>>,
+    acl: Option<Arc<Acl>>,
+    backup_dir: Option<PathBuf>,
+    limits: Limits,
+    /// Held while a connection or request is being served.
+    open: mpsc::Sender<()>,
+    stopping: watch::Receiver<bool>,
+}
+
+impl<T: Engine> Clone for AsyncHandler<T> {
+    fn clone(&self) -> Self {
+        AsyncHandler {
+            engine: Arc::clone(&self.engine),
+            manifest: self.manifest.clone(),
+            auth: self.auth.clone(),
+            acl: self.acl.clone(),
+            backup_dir: self.backup_dir.clone(),
+            limits: self.limits.clone(),
+            open: self.open.clone(),
+            stopping: self.stopping.clone(),
+        }
+    }
+}
+
+impl<T: Engine + 'static> AsyncHandler<T> {
+    /// Runs every request as its own task, so that responses go out as soon
+    /// as they are ready rather than in the order of the requests.
+    ///
+    /// `Auth` is answered before the next request is read, so that the
+    /// requests pipelined behind it are already authenticated. Once the
+    /// server shuts down no further request is read.
+    ///
+    /// A `refused` connection only gets its first request answered, with
+    /// `Error::Limit`.
+    async fn serve(&self, stream: TcpStream, peer: SocketAddr, refused: bool) -> Result<()> {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let mut limits = self.limits.clone();
+        if refused {
+            limits.idle_timeout = Some(REFUSAL_WAIT);
+        }
+
+        let handshake = protocol::accept_async(&mut reader, &mut writer);
+        let version = match within(limits.idle_timeout, handshake).await? {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        let conn = AsyncConnection {
+            writer: Arc::new(AsyncMutex::new(writer)),
+            version,
+            write_timeout: limits.write_timeout,
+        };
+        if refused {
+            return match next_frame(&mut reader, &limits).await? {
+                Some(Ok(frame)) => {
+                    let resp = Response::Err(too_many_clients().into());
+                    conn.send(frame.id, &resp).await
+                }
+                Some(Err((id, _))) => {
+                    let resp = Response::Err(too_many_clients().into());
+                    conn.send(id, &resp).await
+                }
+                None => Ok(()),
+            };
+        }
+
+        let mut authenticated = self.auth.is_none();
+        let mut user = None;
+        let mut stopping = self.stopping.clone();
+        let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+        loop {
+            let frame = tokio::select! {
+                frame = next_frame(&mut reader, &limits) => frame?,
+                _ = stopping.changed() => None,
+            };
+            let frame = match frame {
+                Some(Ok(frame)) => frame,
+                // The frame was skipped, so the connection is still usable.
+                Some(Err((id, len))) => {
+                    debug!("refused frame {} of {} bytes", id, len);
+                    let resp = Response::Err(too_large(limits.max_request_size).into());
+                    conn.send(id, &resp).await?;
+                    continue;
+                }
+                None => break,
+            };
+            let request = if frame.version != version {
+                Err(format!("expected version {}", version))
+            } else if frame.opcode != OP_REQUEST {
+                Err(format!("unexpected opcode {}", frame.opcode))
+            } else {
+                frame
+                    .decode::<Request>()
+                    .map_err(|err| format!("invalid request: {}", err))
+            };
+
+            let id = frame.id;
+            match request {
+                Ok(Request::Auth { token }) => match self.authenticate(&token, peer) {
+                    Ok(found) => {
+                        authenticated = true;
+                        user = found;
+                        conn.send(id, &Response::Ok).await?;
+                    }
+                    Err(err) => return conn.send(id, &Response::Err(err.into())).await,
+                },
+                Ok(_) if !authenticated => {
+                    let err = Error::Auth("authentication required".to_owned());
+                    conn.send(id, &Response::Err(err.into())).await?;
+                }
+                Ok(request) => match self.authorize(user.as_deref(), &request) {
+                    Ok(()) => {
+                        let permit = Arc::clone(&in_flight)
+                            .acquire_owned()
+                            .await
+                            .expect("in-flight semaphore closed");
+                        let handler = self.clone();
+                        let conn = conn.clone();
+                        tokio::spawn(async move {
+                            let _permit = permit;
+                            if let Err(err) = handler.handle(request, id, &conn).await {
+                                error!("answering request {} failed: {}", id, err)
+                            }
+                        });
+                    }
+                    Err(err) => conn.send(id, &Response::Err(err.into())).await?,
+                },
+                Err(message) => conn.reject(id, message).await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle<W>(&self, request: Request, id: u32, conn: &AsyncConnection<W>) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match request {
+            Request::Get { key } => {
+                let result = self.blocking(move |engine| engine.get(key)).await;
+                let resp = Response::from_result(result, Response::Value);
+                conn.send(id, &resp).await
+            }
+            Request::Set { key, value } => {
+                let result = self.blocking(move |engine| engine.set(key, value)).await;
+                let resp = Response::from_result(result, |()| Response::Ok);
+                conn.send(id, &resp).await
+            }
+            Request::Remove { key } => {
+                let result = self.blocking(move |engine| engine.remove(key)).await;
+                let resp = Response::from_result(result, |()| Response::Ok);
+                conn.send(id, &resp).await
+            }
+            Request::Scan { prefix } => {
+                let mut batches = KeyBatches::new(&prefix);
+                loop {
+                    let batch = self
+                        .blocking(move |engine| {
+                            let entries = batches.next_entries(engine)?;
+                            Ok((batches, entries))
+                        })
+                        .await;
+                    let entries = match batch {
+                        Ok((rest, Some(entries))) => {
+                            batches = rest;
+                            entries
+                        }
+                        Ok((_, None)) => break,
+                        Err(err) => return conn.send(id, &Response::Err(err.into())).await,
+                    };
+                    let responses: Vec<_> = entries
+                        .into_iter()
+                        .map(|(key, value)| Response::Entry { key, value })
+                        .collect();
+                    conn.send_all(id, &responses).await?;
+                }
+                conn.send(id, &Response::End).await
+            }
+            Request::Stats => {
+                let result = self.blocking(|engine| engine.stats()).await;
+                let resp = Response::from_result(result, Response::Stats);
+                conn.send(id, &resp).await
+            }
+            Request::Compact => {
+                info!("compacting on request");
+                let result = self.blocking(|engine| engine.compact()).await;
+                let resp = Response::from_result(result, |()| Response::Ok);
+                conn.send(id, &resp).await
+            }
+            Request::PauseCompaction { paused } => {
+                info!("compaction {}", if paused { "paused" } else { "resumed" });
+                let result = self
+                    .blocking(move |engine| engine.pause_compaction(paused))
+                    .await;
+                let resp = Response::from_result(result, |()| Response::Ok);
+                conn.send(id, &resp).await
+            }
+            Request::Backup { path } => {
+                let result = self.backup(&path).await;
+                let resp = Response::from_result(result, |()| Response::Ok);
+                conn.send(id, &resp).await
+            }
+            _ => {
+                let message = "request not supported by the async server".to_owned();
+                conn.reject(id, message).await
+            }
+        }
+    }
+
+    fn authenticate(&self, token: &str, peer: SocketAddr) -> Result<Option<String>> {
+        match self.auth.as_ref().map(|auth| auth.authenticate(token)) {
+            None => Ok(None),
+            Some(Some(user)) => {
+                debug!("{} authenticated as {}", peer, user);
+                Ok(Some(user.to_owned()))
+            }
+            Some(None) => {
+                warn!("failed authentication from {}", peer);
+                Err(Error::Auth("invalid token".to_owned()))
+            }
+        }
+    }
+
+    /// Fails with `Error::PermissionDenied` unless `user` may make `request`.
+    ///
+    /// Without an ACL to grant admin access, authenticated users cannot
+    /// take backups.
+    fn authorize(&self, user: Option<&str>, request: &Request) -> Result<()> {
+        if let Request::Backup { .. } = request {
+            if self.acl.is_none() && self.auth.is_some() {
+                return Err(Error::PermissionDenied(format!(
+                    "{} has no admin access",
+                    user.unwrap_or("anonymous")
+                )));
+            }
+        }
+        let (key, access) = acl::required(request);
+        match &self.acl {
+            Some(acl) => acl.check(user, key, access),
+            None => Ok(()),
+        }
+    }
+
+    async fn backup(&self, name: &str) -> Result<()> {
+        let path = backup::target(self.backup_dir.as_deref(), name)?;
+        let manifest = self
+            .manifest
+            .clone()
+            .ok_or_else(|| Error::Backup("server has no manifest".to_owned()))?;
+
+        info!("backing up to {}", path.display());
+        self.blocking(move |engine| backup(engine, &manifest, &path))
+            .await
+    }
+
+    /// Runs `f` on the engine from the blocking pool.
+    async fn blocking<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut T) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let engine = Arc::clone(&self.engine);
+        task::spawn_blocking(move || f(&mut engine.lock().expect("engine lock poisoned")))
+            .await
+            .map_err(|err| Error::WithMessage(format!("engine task failed: {}", err)))?
+    }
+}
+
+/// The writing half of a connection after the handshake agreed on
+/// `version`, shared by the tasks answering its requests.
+struct AsyncConnection<W: AsyncWrite + Unpin> {
+    writer: Arc<AsyncMutex<W>>,
+    version: u8,
+    write_timeout: Option<Duration>,
+}
+
+impl<W: AsyncWrite + Unpin> Clone for AsyncConnection<W> {
+    fn clone(&self) -> Self {
+        AsyncConnection {
+            writer: Arc::clone(&self.writer),
+            version: self.version,
+            write_timeout: self.write_timeout,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncConnection<W> {
+    async fn send(&self, id: u32, response: &Response) -> Result<()> {
+        self.send_all(id, std::slice::from_ref(response)).await
+    }
+
+    /// Sends the responses to request `id` without others in between.
+    async fn send_all(&self, id: u32, responses: &[Response]) -> Result<()> {
+        let mut frames = Vec::with_capacity(responses.len());
+        for response in responses {
+            let mut frame = Frame::encode(OP_RESPONSE, id, response)?;
+            frame.version = self.version;
+            frames.push(frame);
+        }
+        self.write_frames(&frames).await
+    }
+
+    /// Tells the client that frame `id` was skipped.
+    async fn reject(&self, id: u32, message: String) -> Result<()> {
+        debug!("rejected frame {}: {}", id, message);
+        let frame = Frame {
+            version: self.version,
+            opcode: OP_ERROR,
+            id,
+            payload: message.into_bytes(),
+        };
+        self.write_frames(&[frame]).await
+    }
+
+    async fn write_frames(&self, frames: &[Frame]) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        let write = async {
+            for frame in frames {
+                frame.write_async(&mut *writer).await?;
+            }
+            Ok::<_, Error>(writer.flush().await?)
+        };
+        within(self.write_timeout, write).await
+    }
+}
+
+/// Calls `Engine::maintain` every `MAINTENANCE_INTERVAL` until `stopping`
+/// changes.
+async fn maintain<T: Engine + 'static>(engine: Arc<Mutex<T>>, mut stopping: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = time::sleep(MAINTENANCE_INTERVAL) => {}
+            _ = stopping.changed() => return,
+        }
+        let engine = Arc::clone(&engine);
+        let maintained =
+            task::spawn_blocking(move || engine.lock().expect("engine lock poisoned").maintain());
+        if let Ok(Err(err)) = maintained.await {
+            error!("engine maintenance failed: {}", err);
+        }
+    }
+}
+
+/// Reads the next frame, waiting up to the idle timeout for it to start
+/// arriving and up to the read timeout for the rest. Frames over the
+/// request size limit are skipped like `Frame::read_limited` does.
+async fn next_frame<R>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<Option<std::result::Result<Frame, (u32, usize)>>>
+where
+    R: AsyncBufRead + AsyncRead + Unpin,
+{
+    if within(limits.idle_timeout, reader.fill_buf())
+        .await?
+        .is_empty()
+    {
+        return Ok(None);
+    }
+    let read = Frame::read_limited_async(reader, limits.max_request_size);
+    within(limits.read_timeout, read).await
+}
+
+/// Fails with a timed out I/O error if `future` takes longer than `timeout`.
+async fn within<F, T, E>(timeout: Option<Duration>, future: F) -> Result<T>
+where
+    F: Future<Output = std::result::Result<T, E>>,
+    Error: From<E>,
+{
+    match timeout {
+        None => Ok(future.await?),
+        Some(timeout) => match time::timeout(timeout, future).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+        },
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{Error, Result};
+use std::path::Path;
+
+/// The tokens a server accepts, each authenticating a user.
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    users: Vec<(String, String)>,
+}
+
+impl Auth {
+    /// Accepts `token` as authenticating `user`.
+    pub fn user(mut self, user: impl Into<String>, token: impl Into<String>) -> Self {
+        self.users.push((user.into(), token.into()));
+        self
+    }
+
+    /// Reads a file of `USER TOKEN` lines. Blank lines and lines starting
+    /// with `#` are skipped.
+    pub fn load(path: &Path) -> Result<Auth> {
+        let mut auth = Auth::default();
+        for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [user, token] => auth = auth.user(user, token),
+                _ => {
+                    return Err(Error::Auth(format!(
+                        "{}:{}: expected a user and a token",
+                        path.display(),
+                        i + 1
+                    )))
+                }
+            }
+        }
+        Ok(auth)
+    }
+
+    /// The user `token` authenticates.
+    ///
+    /// Every token is compared in full, so that the time taken does not
+    /// tell how much of a token was guessed right.
+    pub fn authenticate(&self, token: &str) -> Option<&str> {
+        let mut found = None;
+        for (user, known) in &self.users {
+            if constant_time_eq(known.as_bytes(), token.as_bytes()) && found.is_none() {
+                found = Some(user.as_str());
+            }
+        }
+        found
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,118 @@
+use crate::{summarize, Engine, EngineConfig, Error, Manifest, Registry, Result, Summary};
+use std::path::{Component, Path, PathBuf};
+
+const SKIPPED_ON_RESTORE: &[&str] = &["MANIFEST", "MANIFEST.tmp", "LOCK"];
+
+/// Copies the data of a running `engine` into the empty directory `target`.
+///
+/// The manifest of the source directory is written last, so a backup without
+/// a manifest is incomplete.
+pub fn backup<E: Engine + ?Sized>(
+    engine: &mut E,
+    manifest: &Manifest,
+    target: &Path,
+) -> Result<()> {
+    ensure_empty(target)?;
+    std::fs::create_dir_all(target)?;
+
+    engine.backup(target)?;
+    manifest.save(target)
+}
+
+/// Installs the backup in `source` into the empty directory `data_dir`.
+///
+/// The backup's manifest is checked for a known engine and a compatible
+/// format, the copied data is opened and read back in full, and only then is
+/// the manifest installed.
+pub fn restore(registry: &Registry, source: &Path, data_dir: &Path) -> Result<Summary> {
+    let manifest = Manifest::load(source)?
+        .ok_or_else(|| Error::Backup(format!("no manifest in {}", source.display())))?;
+
+    if !registry.has_engine(&manifest.engine) {
+        return Err(Error::UnknownEngine(manifest.engine));
+    }
+
+    ensure_empty(data_dir)?;
+    std::fs::create_dir_all(data_dir)?;
+    copy_dir(source, data_dir)?;
+
+    let summary = {
+        let mut engine = registry.open(&manifest.engine, &EngineConfig::new(data_dir))?;
+        summarize(&mut engine)?
+    };
+
+    manifest.save(data_dir)?;
+    Ok(summary)
+}
+
+/// Resolves the directory a client asked to back up into, which must be a
+/// relative path inside the server's backup directory `dir`, also once
+/// symbolic links are followed.
+pub(crate) fn target(dir: Option<&Path>, name: &str) -> Result<PathBuf> {
+    let dir = dir
+        .ok_or_else(|| Error::Backup("this server takes no backups over the network".to_owned()))?;
+    let name = Path::new(name);
+    let inside = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !inside
+        || !name
+            .components()
+            .any(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(Error::Backup(format!(
+            "{} is not a relative path inside the backup directory",
+            name.display()
+        )));
+    }
+
+    // The backup directory itself may not exist before the first backup.
+    std::fs::create_dir_all(dir)?;
+    let target = dir.join(name);
+    // The part of the target that exists already decides where the backup
+    // lands; what is missing is created below it.
+    let mut existing = target.as_path();
+    while existing.symlink_metadata().is_err() {
+        existing = match existing.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+    if !existing.canonicalize()?.starts_with(dir.canonicalize()?) {
+        return Err(Error::Backup(format!(
+            "{} leads outside the backup directory",
+            name.display()
+        )));
+    }
+    Ok(target)
+}
+
+fn ensure_empty(dir: &Path) -> Result<()> {
+    if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+        return Err(Error::Backup(format!(
+            "directory {} is not empty",
+            dir.display()
+        )));
+    }
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+
+        if SKIPPED_ON_RESTORE.iter().any(|skipped| name == *skipped) {
+            continue;
+        }
+
+        let target = to.join(&name);
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,167 @@
+use project_3::{
+    export, import, read_entries, restore, EngineConfig, EntryWriter, Error, Format, Manifest,
+    Progress, Registry, Result,
+};
+use std::fs::File;
+use std::io::{stdin, stdout, Read, Write};
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-admin",
+    about = "offline maintenance of kvs-server data directories"
+)]
+struct Opt {
+    #[structopt(flatten)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "restore",
+        about = "validate a backup and install it into an empty data directory"
+    )]
+    Restore {
+        #[structopt(name = "BACKUP-DIR", parse(from_os_str))]
+        backup: PathBuf,
+        #[structopt(long, value_name = "DIR", parse(from_os_str))]
+        data_dir: PathBuf,
+    },
+    #[structopt(
+        name = "export",
+        about = "write all key/value pairs of a data directory as JSON Lines or CSV"
+    )]
+    Export {
+        #[structopt(long, value_name = "DIR", parse(from_os_str))]
+        data_dir: PathBuf,
+        #[structopt(long, default_value = "jsonl", possible_values = &["jsonl", "csv"])]
+        format: Format,
+        #[structopt(long, default_value = "", help = "only export keys with this prefix")]
+        prefix: String,
+        #[structopt(
+            long,
+            value_name = "FILE",
+            parse(from_os_str),
+            help = "write to a file instead of stdout"
+        )]
+        output: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "import",
+        about = "set key/value pairs read from JSON Lines or CSV in a data directory"
+    )]
+    Import {
+        #[structopt(
+            name = "FILE",
+            parse(from_os_str),
+            help = "read from a file instead of stdin"
+        )]
+        input: Option<PathBuf>,
+        #[structopt(long, value_name = "DIR", parse(from_os_str))]
+        data_dir: PathBuf,
+        #[structopt(
+            long,
+            value_name = "ENGINE-NAME",
+            default_value = "kvs",
+            help = "engine used when the data directory has no manifest yet"
+        )]
+        engine: String,
+        #[structopt(long, default_value = "jsonl", possible_values = &["jsonl", "csv"])]
+        format: Format,
+        #[structopt(long, default_value = "", help = "only import keys with this prefix")]
+        prefix: String,
+    },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let registry = Registry::default();
+
+    match opt.command {
+        Command::Restore { backup, data_dir } => {
+            let summary = restore(&registry, &backup, &data_dir)?;
+            println!(
+                "restored {} keys into {} (checksum {:016x})",
+                summary.keys,
+                data_dir.display(),
+                summary.checksum
+            );
+        }
+        Command::Export {
+            data_dir,
+            format,
+            prefix,
+            output,
+        } => {
+            let manifest = Manifest::load(&data_dir)?.ok_or_else(|| {
+                Error::WithMessage(format!("no manifest in {}", data_dir.display()))
+            })?;
+            let config = EngineConfig::new(&data_dir).read_only(true);
+            let mut engine = registry.open(&manifest.engine, &config)?;
+
+            let output: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(stdout()),
+            };
+            let mut writer = EntryWriter::new(format, output);
+            let mut progress = progress("export");
+
+            export(&mut engine, &prefix, &mut writer, &mut progress)?;
+
+            writer.finish()?;
+            progress.finish();
+        }
+        Command::Import {
+            input,
+            data_dir,
+            engine,
+            format,
+            prefix,
+        } => {
+            std::fs::create_dir_all(&data_dir)?;
+            let manifest = match Manifest::load(&data_dir)? {
+                Some(manifest) => manifest,
+                None => Manifest::new(&engine),
+            };
+            let mut engine = registry.open(&manifest.engine, &EngineConfig::new(&data_dir))?;
+            manifest.save(&data_dir)?;
+
+            let input: Box<dyn Read> = match input {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(stdin()),
+            };
+            let mut progress = progress("import");
+
+            import(
+                &mut engine,
+                &prefix,
+                read_entries(format, input),
+                &mut progress,
+            )?;
+
+            progress.finish();
+        }
+    }
+    Ok(())
+}
+
+/// Prints the progress of `label` on stderr.
+fn progress(label: &'static str) -> Progress {
+    Progress::new(move |count, done| {
+        if done {
+            eprintln!("{}: {} entries, done", label, count);
+        } else {
+            eprintln!("{}: {} entries", label, count);
+        }
+    })
+}
@@ -1,10 +1,51 @@
-use project_3::{Client, Result};
+use project_3::{
+    read_entries, rebalance, Addr, Client, ClientTls, Connector, Entry, EntryWriter, Format,
+    Position, Progress, Result,
+};
+use std::fs::File;
+use std::io::{stdin, stdout, Read, Write};
+use std::path::PathBuf;
 use std::process::exit;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-client")]
 struct Opt {
+    #[structopt(
+        long,
+        global = true,
+        value_name = "TOKEN",
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        help = "authenticate with this token"
+    )]
+    token: Option<String>,
+    #[structopt(
+        long,
+        global = true,
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "connect over TLS, trusting only servers certified by a CA in this PEM file"
+    )]
+    ca: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        value_name = "FILE",
+        parse(from_os_str),
+        requires_all = &["ca", "key"],
+        help = "present this PEM certificate chain to servers requiring one"
+    )]
+    cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        global = true,
+        value_name = "FILE",
+        parse(from_os_str),
+        requires = "cert",
+        help = "PEM private key of the client certificate"
+    )]
+    key: Option<PathBuf>,
     #[structopt(flatten)]
     command: Command,
 }
@@ -15,8 +56,12 @@ enum Command {
     Get {
         #[structopt(name = "KEY")]
         key: String,
-        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
-        addr: String,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
     },
     #[structopt(name = "set", about = "set the value of a string key to a string")]
     Set {
@@ -24,18 +69,225 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE")]
         value: String,
-        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
-        addr: String,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
     },
     #[structopt(name = "rm", about = "remove a given string key")]
     Remove {
         #[structopt(name = "KEY")]
         key: String,
-        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
-        addr: String,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(
+        name = "backup",
+        about = "back up the server data into a new directory under the server's --backup-dir"
+    )]
+    Backup {
+        #[structopt(name = "DIR")]
+        path: String,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(
+        name = "compact",
+        about = "compact the server storage now, or pause/resume automatic compaction"
+    )]
+    Compact {
+        #[structopt(long, conflicts_with = "resume", help = "pause automatic compaction")]
+        pause: bool,
+        #[structopt(long, help = "resume automatic compaction")]
+        resume: bool,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(
+        name = "promote",
+        about = "make a replica stop following its primary and accept writes"
+    )]
+    Promote {
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(name = "cluster", about = "inspect or change the Raft cluster")]
+    Cluster {
+        #[structopt(subcommand)]
+        command: ClusterCommand,
+    },
+    #[structopt(
+        name = "rebalance",
+        about = "move keys between sharded servers after adding or removing one"
+    )]
+    Rebalance {
+        #[structopt(
+            long,
+            value_name = "IP:PORT,...",
+            use_delimiter = true,
+            required = true,
+            help = "servers the keys are sharded across now"
+        )]
+        from: Vec<String>,
+        #[structopt(
+            long,
+            value_name = "IP:PORT,...",
+            use_delimiter = true,
+            required = true,
+            help = "servers the keys should be sharded across"
+        )]
+        to: Vec<String>,
+        #[structopt(long, default_value = "160", help = "virtual nodes per server")]
+        vnodes: usize,
+    },
+    #[structopt(name = "stats", about = "print engine statistics as JSON")]
+    Stats {
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(
+        name = "export",
+        about = "write all key/value pairs as JSON Lines or CSV"
+    )]
+    Export {
+        #[structopt(long, default_value = "jsonl", possible_values = &["jsonl", "csv"])]
+        format: Format,
+        #[structopt(long, default_value = "", help = "only export keys with this prefix")]
+        prefix: String,
+        #[structopt(
+            long,
+            value_name = "FILE",
+            parse(from_os_str),
+            help = "write to a file instead of stdout"
+        )]
+        output: Option<PathBuf>,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(
+        name = "import",
+        about = "set key/value pairs read from JSON Lines or CSV"
+    )]
+    Import {
+        #[structopt(
+            name = "FILE",
+            parse(from_os_str),
+            help = "read from a file instead of stdin"
+        )]
+        input: Option<PathBuf>,
+        #[structopt(long, default_value = "jsonl", possible_values = &["jsonl", "csv"])]
+        format: Format,
+        #[structopt(long, default_value = "", help = "only import keys with this prefix")]
+        prefix: String,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(
+        name = "watch",
+        about = "print changes to keys as JSON lines while they happen"
+    )]
+    Watch {
+        #[structopt(long, default_value = "", help = "only watch keys with this prefix")]
+        prefix: String,
+        #[structopt(
+            long,
+            value_name = "FEED:SEQ",
+            help = "first replay the changes after this position, as printed when a watch starts"
+        )]
+        after: Option<Position>,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum ClusterCommand {
+    #[structopt(
+        name = "status",
+        about = "print the server's view of the cluster as JSON"
+    )]
+    Status {
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(name = "add", about = "add a server to the cluster")]
+    Add {
+        #[structopt(name = "MEMBER", help = "address of the server to add")]
+        member: String,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
+    },
+    #[structopt(name = "remove", about = "remove a server from the cluster")]
+    Remove {
+        #[structopt(name = "MEMBER", help = "address of the server to remove")]
+        member: String,
+        #[structopt(
+            long,
+            value_name = "IP:PORT|unix:PATH",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: Addr,
     },
 }
 
+/// Pairs imported with one pipeline.
+const IMPORT_BATCH: usize = 1000;
+
+fn import_batch(
+    client: &mut Client,
+    batch: &mut Vec<(String, String)>,
+    progress: &mut Progress,
+) -> Result<()> {
+    let count = batch.len();
+    client.set_many(std::mem::take(batch))?;
+    for _ in 0..count {
+        progress.inc();
+    }
+    Ok(())
+}
+
 fn main() {
     let opt = Opt::from_args();
     if let Err(e) = run(opt) {
@@ -44,10 +296,24 @@ fn main() {
     }
 }
 
+/// How to reach the servers named on the command line.
+fn connector(opt: &Opt) -> Result<Connector> {
+    let mut connector = Connector::new();
+    if let Some(token) = &opt.token {
+        connector = connector.token(token.clone());
+    }
+    if let Some(ca) = &opt.ca {
+        let identity = opt.cert.as_deref().zip(opt.key.as_deref());
+        connector = connector.tls(ClientTls::load(ca, identity)?);
+    }
+    Ok(connector)
+}
+
 fn run(opt: Opt) -> Result<()> {
+    let connector = connector(&opt)?;
     match opt.command {
         Command::Get { key, addr } => {
-            let mut client = Client::connect(addr)?;
+            let mut client = connector.connect(&addr)?;
 
             if let Some(value) = client.get(key)? {
                 println!("{}", value)
@@ -55,8 +321,121 @@ fn run(opt: Opt) -> Result<()> {
                 println!("Key not found")
             }
         }
-        Command::Set { key, value, addr } => Client::connect(addr)?.set(key, value)?,
-        Command::Remove { key, addr } => Client::connect(addr)?.remove(key)?,
+        Command::Set { key, value, addr } => connector.connect(&addr)?.set(key, value)?,
+        Command::Remove { key, addr } => connector.connect(&addr)?.remove(key)?,
+        Command::Backup { path, addr } => connector.connect(&addr)?.backup(path)?,
+        Command::Compact {
+            pause,
+            resume,
+            addr,
+        } => {
+            let mut client = connector.connect(&addr)?;
+            if pause || resume {
+                client.pause_compaction(pause)?
+            } else {
+                client.compact()?
+            }
+        }
+        Command::Promote { addr } => connector.connect(&addr)?.promote()?,
+        Command::Cluster { command } => {
+            let status = match command {
+                ClusterCommand::Status { addr } => connector.connect(&addr)?.cluster_status()?,
+                ClusterCommand::Add { member, addr } => {
+                    connector.connect(&addr)?.add_member(member)?
+                }
+                ClusterCommand::Remove { member, addr } => {
+                    connector.connect(&addr)?.remove_member(member)?
+                }
+            };
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        Command::Rebalance { from, to, vnodes } => {
+            let report = rebalance(&from, &to, vnodes, &connector)?;
+            eprintln!(
+                "rebalance: {} keys scanned, {} moved",
+                report.scanned, report.moved
+            );
+        }
+        Command::Stats { addr } => {
+            let stats = connector.connect(&addr)?.stats()?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        Command::Export {
+            format,
+            prefix,
+            output,
+            addr,
+        } => {
+            let output: Box<dyn Write> = match output {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(stdout()),
+            };
+            let mut writer = EntryWriter::new(format, output);
+            let mut progress = progress("export");
+
+            let mut client = connector.connect(&addr)?;
+            for entry in client.scan(prefix)? {
+                let (key, value) = entry?;
+                writer.write(&Entry { key, value })?;
+                progress.inc();
+            }
+
+            writer.finish()?;
+            progress.finish();
+        }
+        Command::Import {
+            input,
+            format,
+            prefix,
+            addr,
+        } => {
+            let input: Box<dyn Read> = match input {
+                Some(path) => Box::new(File::open(path)?),
+                None => Box::new(stdin()),
+            };
+            let mut progress = progress("import");
+
+            let mut client = connector.connect(&addr)?;
+            let mut batch = Vec::with_capacity(IMPORT_BATCH);
+            for entry in read_entries(format, input) {
+                let Entry { key, value } = entry?;
+                if key.starts_with(&prefix) {
+                    batch.push((key, value));
+                }
+                if batch.len() == IMPORT_BATCH {
+                    import_batch(&mut client, &mut batch, &mut progress)?;
+                }
+            }
+            import_batch(&mut client, &mut batch, &mut progress)?;
+
+            progress.finish();
+        }
+        Command::Watch {
+            prefix,
+            after,
+            addr,
+        } => {
+            let stdout = stdout();
+            let watch = connector.connect(&addr)?.watch(prefix, after)?;
+            eprintln!("watching from {}", watch.position());
+            for event in watch {
+                let mut out = stdout.lock();
+                serde_json::to_writer(&mut out, &event?)?;
+                writeln!(out)?;
+                out.flush()?;
+            }
+        }
     }
     Ok(())
 }
+
+/// Prints the progress of `label` on stderr.
+fn progress(label: &'static str) -> Progress {
+    Progress::new(move |count, done| {
+        if done {
+            eprintln!("{}: {} entries, done", label, count);
+        } else {
+            eprintln!("{}: {} entries", label, count);
+        }
+    })
+}
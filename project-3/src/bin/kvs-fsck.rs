@@ -0,0 +1,83 @@
+use project_3::fsck::{self, RecordProblem};
+use project_3::{Error, Manifest, Result};
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-fsck",
+    about = "check the logs of a kvs engine data directory"
+)]
+struct Opt {
+    #[structopt(long, value_name = "DIR", default_value = ".", parse(from_os_str))]
+    data_dir: PathBuf,
+    #[structopt(
+        long,
+        help = "rewrite the live records into a new generation, dropping unreadable data"
+    )]
+    repair: bool,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    match run(opt) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    }
+}
+
+fn run(opt: Opt) -> Result<bool> {
+    if let Some(manifest) = Manifest::load(&opt.data_dir)? {
+        if manifest.engine != "kvs" {
+            return Err(Error::WithMessage(format!(
+                "{} holds {} data, only kvs data can be checked",
+                opt.data_dir.display(),
+                manifest.engine
+            )));
+        }
+    }
+
+    let report = fsck::check(&opt.data_dir)?;
+
+    for gen in &report.generations {
+        print!(
+            "generation {}: {} bytes, {} records",
+            gen.gen, gen.size, gen.records
+        );
+        if gen.problems.is_empty() {
+            println!(", ok");
+            continue;
+        }
+        println!(", {} problems", gen.problems.len());
+        for problem in &gen.problems {
+            match problem {
+                (offset, RecordProblem::Truncated) => {
+                    println!("  truncated record at offset {}", offset)
+                }
+                (offset, RecordProblem::Corrupt(message)) => {
+                    println!("  corrupt record at offset {}: {}", offset, message)
+                }
+            }
+        }
+    }
+    println!(
+        "{} live keys, {} live bytes, {} dead bytes",
+        report.live_keys, report.live_bytes, report.dead_bytes
+    );
+
+    if opt.repair {
+        let repaired = fsck::repair(&opt.data_dir)?;
+        println!(
+            "repaired: wrote {} live keys into generation {}, removed {} generations",
+            repaired.live_keys, repaired.gen, repaired.removed_generations
+        );
+        return Ok(true);
+    }
+
+    Ok(report.is_clean())
+}
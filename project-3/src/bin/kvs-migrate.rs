@@ -0,0 +1,36 @@
+use project_3::{migrate, Registry, Result};
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-migrate",
+    about = "copy the data of a kvs-server directory into a new directory for another engine"
+)]
+struct Opt {
+    #[structopt(long, value_name = "DIR", default_value = ".", parse(from_os_str))]
+    from: PathBuf,
+    #[structopt(long, value_name = "DIR", parse(from_os_str))]
+    to: PathBuf,
+    #[structopt(long, value_name = "ENGINE-NAME")]
+    engine: String,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let report = migrate(&Registry::default(), &opt.from, &opt.to, &opt.engine)?;
+
+    println!(
+        "migrated {} keys from {} to {} (checksum {:016x})",
+        report.summary.keys, report.from_engine, report.to_engine, report.summary.checksum
+    );
+    Ok(())
+}
@@ -1,58 +1,386 @@
-#[macro_use]
-extern crate clap;
-
 #[macro_use]
 extern crate log;
 
+use project_3::raft::RaftConfig;
 use project_3::{
-    Error as KvsError, Result as KvsResult, Server as KvsServer, Sled as KvsSled, Store,
+    Acl, Addr, AsyncServer, Auth, CompactionPolicy, CompactionWindow, EngineConfig,
+    Error as KvsError, Limits, Manifest, Protocol, Registry, Result as KvsResult,
+    Server as KvsServer, ServerTls,
 };
 use std::env::current_dir;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
-const DEFAULT_ENGINE: Engine = Engine::Kvs;
+const DEFAULT_ENGINE: &str = "kvs";
 
 #[derive(StructOpt)]
 #[structopt(name = "kvs-server")]
 struct Opt {
-    #[structopt(long, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
-    #[structopt(long)]
-    engine: Option<Engine>,
+    #[structopt(
+        long,
+        value_name = "IP:PORT|unix:PATH",
+        default_value = "127.0.0.1:4000",
+        help = "address to serve clients on, or the path of a Unix socket to create"
+    )]
+    addr: Addr,
+    #[structopt(long, value_name = "ENGINE-NAME")]
+    engine: Option<String>,
+    #[structopt(
+        long,
+        default_value = "kvs",
+        possible_values = &["kvs", "resp"],
+        help = "protocol spoken to clients; resp serves redis clients"
+    )]
+    protocol: Protocol,
+    #[structopt(
+        long,
+        value_name = "IP:PORT",
+        help = "also serve the HTTP/JSON gateway on this address"
+    )]
+    http: Option<SocketAddr>,
+    #[structopt(
+        long = "async",
+        conflicts_with_all = &["replica-of", "cluster", "http"],
+        help = "serve connections as tasks on a tokio runtime, with the binary protocol only"
+    )]
+    async_server: bool,
+    #[structopt(long = "wrapper", value_name = "WRAPPER-NAME", number_of_values = 1)]
+    wrappers: Vec<String>,
+    #[structopt(
+        long,
+        value_name = "DIR",
+        parse(from_os_str),
+        help = "directory holding the manifest and engine data [default: current directory]"
+    )]
+    data_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "serve the data directory without writing to it, next to a writer (kvs engine only)"
+    )]
+    read_only: bool,
+    #[structopt(
+        long,
+        value_name = "IP:PORT",
+        conflicts_with = "read-only",
+        help = "follow the primary at this address and serve reads until promoted"
+    )]
+    replica_of: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["read-only", "replica-of"],
+        requires = "token",
+        help = "run as a member of a Raft cluster, authenticating to the other members with --token"
+    )]
+    cluster: bool,
+    #[structopt(
+        long = "peer",
+        value_name = "IP:PORT",
+        number_of_values = 1,
+        requires = "cluster",
+        help = "another initial cluster member"
+    )]
+    peers: Vec<SocketAddr>,
+    #[structopt(
+        long,
+        requires = "cluster",
+        conflicts_with = "peers",
+        help = "start without members and wait to be added to a cluster"
+    )]
+    join: bool,
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        help = "compact once more than this many bytes are dead [default: 1048576]"
+    )]
+    compaction_dead_bytes: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "RATIO",
+        help = "compact only once dead bytes reach this multiple of live bytes"
+    )]
+    compaction_ratio: Option<f64>,
+    #[structopt(
+        long,
+        value_name = "START-END",
+        help = "compact automatically only between these UTC hours, e.g. 22-6"
+    )]
+    compaction_window: Option<CompactionWindow>,
+    #[structopt(long, help = "start with automatic compaction paused")]
+    compaction_paused: bool,
+    #[structopt(
+        long,
+        value_name = "SECONDS",
+        default_value = "10",
+        help = "how long to wait for requests in flight when shutting down"
+    )]
+    drain_timeout: u64,
+    #[structopt(
+        long,
+        value_name = "N",
+        help = "refuse clients beyond this many connections, 0 for no limit [default: 1024]"
+    )]
+    max_connections: Option<usize>,
+    #[structopt(
+        long,
+        value_name = "SECONDS",
+        help = "close connections whose request takes longer to arrive, 0 for never [default: 30]"
+    )]
+    read_timeout: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "SECONDS",
+        help = "close connections not reading their responses, 0 for never [default: 30]"
+    )]
+    write_timeout: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "SECONDS",
+        help = "close connections idle between requests, 0 for never [default: 300]"
+    )]
+    idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        value_name = "BYTES",
+        help = "refuse requests larger than this [default: 67108864]"
+    )]
+    max_request_size: Option<usize>,
+    #[structopt(
+        long,
+        value_name = "TOKEN",
+        env = "KVS_TOKEN",
+        hide_env_values = true,
+        help = "require clients to present this token, and present it to the primary and peers \
+                (in clear text, as replication and cluster traffic is not encrypted)"
+    )]
+    token: Option<String>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        help = "require clients to present a token listed as `USER TOKEN` lines in this file"
+    )]
+    token_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        requires = "token-file",
+        help = "grant the users of --token-file read, write or admin access to key prefixes, \
+                as `USER ACCESS [PREFIX]` lines in this file"
+    )]
+    acl: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "DIR",
+        parse(from_os_str),
+        help = "let clients with admin access back up into new directories under this one"
+    )]
+    backup_dir: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        requires = "tls-key",
+        conflicts_with_all = &["async-server", "replica-of", "cluster", "http"],
+        help = "serve clients over TLS with this PEM certificate chain"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        requires = "tls-cert",
+        help = "PEM private key of the TLS certificate"
+    )]
+    tls_key: Option<PathBuf>,
+    #[structopt(
+        long,
+        value_name = "FILE",
+        parse(from_os_str),
+        requires = "tls-cert",
+        help = "require clients to present a certificate signed by a CA in this PEM file"
+    )]
+    tls_client_ca: Option<PathBuf>,
 }
 
 impl Opt {
-    fn run(self) -> KvsResult<()> {
-        let engine = self.engine.unwrap_or(DEFAULT_ENGINE);
+    /// The tokens clients must present, if any.
+    fn auth(&self) -> KvsResult<Option<Auth>> {
+        let auth = match &self.token_file {
+            Some(path) => Some(Auth::load(path)?),
+            None => None,
+        };
+        Ok(match &self.token {
+            Some(token) => Some(auth.unwrap_or_default().user("default", token.clone())),
+            None => auth,
+        })
+    }
+
+    /// The certificate clients are served with, if any.
+    fn tls(&self) -> KvsResult<Option<ServerTls>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(ServerTls::load(
+                cert,
+                key,
+                self.tls_client_ca.as_deref(),
+            )?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn run(self, registry: &Registry, data_dir: PathBuf, manifest: Manifest) -> KvsResult<()> {
         info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-        info!("Storage engine: {}", engine);
-        info!("Listening on {}", self.addr);
-
-        let write_path = current_dir()?.join("engine");
-        let write_contents = format!("{}", engine);
-        std::fs::write(write_path, write_contents)?;
-        let path = current_dir()?;
-
-        match engine {
-            Engine::Kvs => {
-                let kvs_engine = Store::open(path)?;
-                KvsServer::new(kvs_engine).run(self.addr)
+        info!("Storage engine: {}", manifest.engine);
+        if !self.wrappers.is_empty() {
+            info!("Engine wrappers: {}", self.wrappers.join(", "));
+        }
+        info!("Data directory: {}", data_dir.display());
+        info!("Store id: {}", manifest.store_id);
+        info!("Listening on {} ({})", self.addr, self.protocol);
+        if let Some(http) = &self.http {
+            info!("HTTP gateway on {}", http);
+        }
+        if let Some(primary) = &self.replica_of {
+            info!("Replica of {}", primary);
+        }
+
+        let default_policy = CompactionPolicy::default();
+        let policy = CompactionPolicy {
+            min_dead_bytes: self
+                .compaction_dead_bytes
+                .unwrap_or(default_policy.min_dead_bytes),
+            min_dead_ratio: self.compaction_ratio,
+            window: self.compaction_window,
+            paused: self.compaction_paused,
+        };
+        let config = EngineConfig::new(&data_dir)
+            .read_only(self.read_only)
+            .compaction(policy);
+        let kvs_engine = registry.build(&manifest.engine, &self.wrappers, &config)?;
+
+        if !self.read_only {
+            manifest.save(&data_dir)?;
+        }
+        let auth = self.auth()?;
+        if auth.is_some() {
+            info!("Clients must authenticate");
+        }
+        let acl = match &self.acl {
+            Some(path) => Some(Acl::load(path)?),
+            None => None,
+        };
+        let tls = self.tls()?;
+        if tls.is_some() {
+            info!("Serving clients over TLS");
+        }
+
+        let default_limits = Limits::default();
+        let timeout = |seconds: Option<u64>, default| match seconds {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => default,
+        };
+        let limits = Limits {
+            max_connections: match self.max_connections {
+                Some(0) => None,
+                Some(max) => Some(max),
+                None => default_limits.max_connections,
+            },
+            read_timeout: timeout(self.read_timeout, default_limits.read_timeout),
+            write_timeout: timeout(self.write_timeout, default_limits.write_timeout),
+            idle_timeout: timeout(self.idle_timeout, default_limits.idle_timeout),
+            max_request_size: self
+                .max_request_size
+                .unwrap_or(default_limits.max_request_size),
+        };
+
+        if self.async_server {
+            if self.protocol != Protocol::Kvs {
+                return Err(KvsError::WithMessage(
+                    "the async server only speaks the kvs protocol".to_owned(),
+                ));
+            }
+            let addr = match &self.addr {
+                Addr::Tcp(addr) => addr.clone(),
+                Addr::Unix(_) => {
+                    return Err(KvsError::WithMessage(
+                        "the async server only listens on TCP".to_owned(),
+                    ))
+                }
+            };
+            let mut server = AsyncServer::new(kvs_engine)
+                .manifest(manifest)
+                .limits(limits)
+                .drain_timeout(Duration::from_secs(self.drain_timeout));
+            if let Some(auth) = auth {
+                server = server.auth(auth);
+            }
+            if let Some(acl) = acl {
+                server = server.acl(acl);
             }
-            Engine::Sled => {
-                let db = sled::open(path)?;
-                let kvs_engine = KvsSled::new(db);
-                KvsServer::new(kvs_engine).run(self.addr)
+            if let Some(dir) = self.backup_dir {
+                server = server.backup_dir(dir);
             }
+            server.shutdown_handle().on_signals()?;
+            return tokio::runtime::Runtime::new()?.block_on(server.run(addr));
         }
-    }
-}
 
-arg_enum! {
-    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-    enum Engine {
-        Kvs,
-        Sled,
+        let engine = manifest.engine.clone();
+        let mut server = KvsServer::new(kvs_engine)
+            .manifest(manifest)
+            .protocol(self.protocol)
+            .limits(limits)
+            .drain_timeout(Duration::from_secs(self.drain_timeout));
+        if let Some(http) = self.http {
+            server = server.http(http.to_string());
+        }
+        if let Some(auth) = auth {
+            server = server.auth(auth);
+        }
+        if let Some(acl) = acl {
+            server = server.acl(acl);
+        }
+        if let Some(dir) = self.backup_dir {
+            server = server.backup_dir(dir);
+        }
+        if let Some(token) = self.token {
+            server = server.token(token);
+        }
+        if let Some(tls) = tls {
+            server = server.tls(tls);
+        }
+        if let Some(primary) = self.replica_of {
+            server = server.replica_of(primary);
+        }
+        if self.cluster {
+            let addr = match &self.addr {
+                Addr::Tcp(addr) => addr.clone(),
+                Addr::Unix(_) => {
+                    return Err(KvsError::WithMessage(
+                        "cluster members listen on TCP".to_owned(),
+                    ))
+                }
+            };
+            let mut members = Vec::new();
+            if !self.join {
+                members.push(addr.clone());
+                members.extend(self.peers.iter().map(SocketAddr::to_string));
+            }
+            info!("Cluster members: {}", members.join(", "));
+
+            let config = RaftConfig::new(addr, data_dir.join("raft"), move |dir| {
+                let config = EngineConfig::new(dir).read_only(true);
+                Registry::default().build::<&str>(&engine, &[], &config)
+            });
+            server = server.cluster(config.members(members));
+        }
+        server.shutdown_handle().on_signals()?;
+        match self.addr {
+            Addr::Tcp(addr) => server.run(addr),
+            Addr::Unix(path) => server.run_unix(path),
+        }
     }
 }
 
@@ -61,26 +389,56 @@ fn main() {
         .filter_level(log::LevelFilter::Debug)
         .init();
 
-    let mut opt: Opt = Opt::from_args();
+    let opt: Opt = Opt::from_args();
+    let registry = Registry::default();
 
     let res: Result<(), KvsError> = {
-        let wrapped_engine = current_engine();
+        let wrapped_manifest = data_dir(&opt).and_then(|dir| {
+            let manifest = Manifest::load(&dir)?;
+            Ok((dir, manifest))
+        });
+
+        match wrapped_manifest {
+            Ok((dir, manifest)) => {
+                // Engine names were case-insensitive before the registry.
+                let engine = opt
+                    .engine
+                    .as_deref()
+                    .map(str::to_ascii_lowercase)
+                    .or_else(|| manifest.as_ref().map(|m| m.engine.clone()))
+                    .unwrap_or_else(|| DEFAULT_ENGINE.to_owned());
 
-        match wrapped_engine {
-            Ok(engine) => {
-                if opt.engine.is_none() {
-                    opt.engine = engine;
+                if !registry.has_engine(&engine) {
+                    error!(
+                        "unknown engine {}, expected one of: {}",
+                        engine,
+                        registry.engine_names().join(", ")
+                    );
+                    std::process::exit(1);
                 }
 
-                if engine.is_some() && opt.engine != engine {
-                    error!("wrong engine");
+                if let Some(manifest) = &manifest {
+                    if manifest.engine != engine {
+                        error!("wrong engine");
+                        std::process::exit(1);
+                    }
+                }
+
+                if opt.read_only && engine == "sled" {
+                    error!("sled locks its data directory and cannot be served --read-only");
+                    std::process::exit(1);
+                }
+
+                if opt.compaction_paused && engine == "sled" {
+                    error!("sled compacts on its own and cannot start --compaction-paused");
                     std::process::exit(1);
                 }
 
-                opt.run()
+                let manifest = manifest.unwrap_or_else(|| Manifest::new(&engine));
+                opt.run(&registry, dir, manifest)
             }
             Err(err) => {
-                error!("engine error: {}", err);
+                error!("manifest error: {}", err);
                 Err(err)
             }
         }
@@ -93,18 +451,11 @@ fn main() {
     info!("stop!");
 }
 
-fn current_engine() -> KvsResult<Option<Engine>> {
-    let engine_path = current_dir()?.join("engine");
-
-    if !engine_path.exists() {
-        return Ok(None);
-    }
-
-    match std::fs::read_to_string(engine_path)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(err) => {
-            warn!("invalid engine file: {}", err);
-            Ok(None)
-        }
-    }
+fn data_dir(opt: &Opt) -> KvsResult<PathBuf> {
+    let dir = match &opt.data_dir {
+        Some(dir) => dir.clone(),
+        None => current_dir()?,
+    };
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
 }
@@ -1,55 +1,695 @@
-use crate::{Error as KvsError, GetResponse, RemoveResponse, Request, Result, SetResponse};
+use crate::legacy::{self, Kind};
+use crate::protocol::{self, Frame, OP_ERROR, OP_REQUEST, OP_RESPONSE};
+use crate::raft::ClusterStatus;
+use crate::stream::Stream;
+use crate::{
+    Addr, ChangeEvent, ClientTls, Error as KvsError, Position, Request, Response, Result, Stats,
+};
+use serde::Deserialize;
 use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
-use serde::Deserialize;
+/// How often a request follows a redirect or waits for a cluster election.
+pub(crate) const REDIRECT_LIMIT: usize = 20;
+pub(crate) const ELECTION_WAIT: Duration = Duration::from_millis(100);
+/// Most requests a pipeline sends before reading responses back.
+const PIPELINE_WINDOW: usize = 1024;
 
 pub struct Client {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    transport: Transport,
+    /// Presented again after a redirect reconnects.
+    token: Option<String>,
+    tls: Option<ClientTls>,
 }
 
 impl Client {
-    pub fn connect<T: std::net::ToSocketAddrs>(addr: T) -> Result<Self> {
-        let tcp_stream_reader = TcpStream::connect(addr)?;
-        let tcp_stream_writer = tcp_stream_reader.try_clone()?;
+    /// Connects with the binary protocol, or with JSON if the server predates it.
+    pub fn connect<T: ToSocketAddrs>(addr: T) -> Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        match Transport::binary(Stream::Tcp(TcpStream::connect(&addrs[..])?)) {
+            Ok(transport) => Ok(Client {
+                transport,
+                token: None,
+                tls: None,
+            }),
+            Err(err) => {
+                debug!("binary handshake failed, falling back to JSON: {}", err);
+                Client::connect_json(&addrs[..])
+            }
+        }
+    }
+
+    /// Connects with the original JSON protocol.
+    pub fn connect_json<T: ToSocketAddrs>(addr: T) -> Result<Self> {
+        let transport = Transport::json(Stream::Tcp(TcpStream::connect(addr)?))?;
+        Ok(Client {
+            transport,
+            token: None,
+            tls: None,
+        })
+    }
 
-        let reader = Deserializer::from_reader(BufReader::new(tcp_stream_reader));
-        let writer = BufWriter::new(tcp_stream_writer);
+    /// Connects like `connect`, over TLS.
+    pub fn connect_tls(addr: &str, tls: &ClientTls) -> Result<Self> {
+        let transport = Transport::open(|| tls.connect(TcpStream::connect(addr)?, addr))?;
+        Ok(Client {
+            transport,
+            token: None,
+            tls: Some(tls.clone()),
+        })
+    }
 
-        Ok(Client { reader, writer })
+    /// Connects like `connect`, to the Unix domain socket at `path`.
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let transport = Transport::open(|| Ok(Stream::connect_unix(path.as_ref())?))?;
+        Ok(Client {
+            transport,
+            token: None,
+            tls: None,
+        })
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
+    /// Connects like `connect`, then authenticates with `token`.
+    pub fn connect_with_auth<T: ToSocketAddrs>(addr: T, token: String) -> Result<Self> {
+        let mut client = Client::connect(addr)?;
+        client.auth(token)?;
+        Ok(client)
+    }
+
+    /// Authenticates the connection with `token`, which a server that
+    /// requires authentication closes the connection on when it is wrong.
+    pub fn auth(&mut self, token: String) -> Result<()> {
+        self.transport.send(&Request::Auth {
+            token: token.clone(),
+        })?;
+        self.receive_ok()?;
+        self.token = Some(token);
+        Ok(())
+    }
 
-        let resp: GetResponse = GetResponse::deserialize(&mut self.reader)?;
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        for _ in 0..REDIRECT_LIMIT {
+            self.transport.send(&Request::Get { key: key.clone() })?;
 
-        match resp {
-            GetResponse::Ok(data) => Ok(data),
-            GetResponse::Err(message) => Err(KvsError::WithMessage(message)),
+            match self.transport.receive()? {
+                Response::Value(value) => return Ok(value),
+                other => self.redirect_on(other.unexpected("a value"))?,
+            }
         }
+        Err(no_leader())
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
+        for _ in 0..REDIRECT_LIMIT {
+            let request = Request::Set {
+                key: key.clone(),
+                value: value.clone(),
+            };
+            self.transport.send(&request)?;
+
+            match self.transport.receive()? {
+                Response::Ok => return Ok(()),
+                other => self.redirect_on(other.unexpected("OK"))?,
+            }
+        }
+        Err(no_leader())
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        for _ in 0..REDIRECT_LIMIT {
+            self.transport.send(&Request::Remove { key: key.clone() })?;
+
+            match self.transport.receive()? {
+                Response::Ok => return Ok(()),
+                other => self.redirect_on(other.unexpected("OK"))?,
+            }
+        }
+        Err(no_leader())
+    }
+
+    pub fn cluster_status(&mut self) -> Result<ClusterStatus> {
+        self.cluster(Request::ClusterStatus)
+    }
+
+    /// Adds the server at `addr` to the cluster, through the leader.
+    pub fn add_member(&mut self, addr: String) -> Result<ClusterStatus> {
+        self.cluster(Request::AddMember { addr })
+    }
+
+    /// Removes the server at `addr` from the cluster, through the leader.
+    pub fn remove_member(&mut self, addr: String) -> Result<ClusterStatus> {
+        self.cluster(Request::RemoveMember { addr })
+    }
+
+    fn cluster(&mut self, request: Request) -> Result<ClusterStatus> {
+        for _ in 0..REDIRECT_LIMIT {
+            self.transport.send(&request)?;
+
+            match self.transport.receive()? {
+                Response::Cluster(status) => return Ok(status),
+                other => self.redirect_on(other.unexpected("a cluster status"))?,
+            }
+        }
+        Err(no_leader())
+    }
+
+    /// Reconnects to the cluster leader, or waits for one to be elected,
+    /// when `err` says this server is not the leader; fails with it otherwise.
+    fn redirect_on(&mut self, err: KvsError) -> Result<()> {
+        match err {
+            KvsError::NotLeader(leader) => {
+                let reconnect = |addr: String| match &self.tls {
+                    Some(tls) => Client::connect_tls(&addr, tls),
+                    None => Client::connect(addr),
+                };
+                match leader.map(reconnect) {
+                    Some(Ok(client)) => {
+                        let token = self.token.take();
+                        *self = client;
+                        if let Some(token) = token {
+                            self.auth(token)?;
+                        }
+                    }
+                    _ => thread::sleep(ELECTION_WAIT),
+                }
+                Ok(())
+            }
+            err => Err(err),
+        }
+    }
+
+    /// Reads a response that carries nothing but success.
+    fn receive_ok(&mut self) -> Result<()> {
+        match self.transport.receive()? {
+            Response::Ok => Ok(()),
+            other => Err(other.unexpected("OK")),
+        }
+    }
+
+    /// Asks the server to back up its data into `path` on the server host.
+    pub fn backup(&mut self, path: String) -> Result<()> {
+        self.transport.send(&Request::Backup { path })?;
+
+        self.receive_ok()
+    }
+
+    pub fn stats(&mut self) -> Result<Stats> {
+        self.transport.send(&Request::Stats)?;
+
+        match self.transport.receive()? {
+            Response::Stats(stats) => Ok(stats),
+            other => Err(other.unexpected("statistics")),
+        }
+    }
+
+    pub fn compact(&mut self) -> Result<()> {
+        self.transport.send(&Request::Compact)?;
+
+        self.receive_ok()
+    }
+
+    /// Stops (`true`) or resumes (`false`) automatic compaction on the server.
+    pub fn pause_compaction(&mut self, paused: bool) -> Result<()> {
+        self.transport.send(&Request::PauseCompaction { paused })?;
+
+        self.receive_ok()
+    }
+
+    /// Makes a replica stop following its primary and accept writes.
+    pub fn promote(&mut self) -> Result<()> {
+        self.transport.send(&Request::Promote)?;
+
+        self.receive_ok()
+    }
+
+    /// Starts a batch of requests that are sent without waiting for responses.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            transport: &mut self.transport,
+            in_flight: HashMap::new(),
+            results: Vec::new(),
+        }
+    }
+
+    /// Gets every key in one pipeline.
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut pipeline = self.pipeline();
+        for key in keys {
+            pipeline.get(key)?;
+        }
+        pipeline.finish()?.into_iter().collect()
+    }
+
+    /// Sets every pair in one pipeline, failing if any of them failed.
+    ///
+    /// Only the last value of a key that appears several times is sent,
+    /// because pipelined requests may run in any order.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut last = HashMap::new();
+        for (i, (key, _)) in pairs.iter().enumerate() {
+            last.insert(key.clone(), i);
+        }
+
+        let mut pipeline = self.pipeline();
+        for (i, (key, value)) in pairs.into_iter().enumerate() {
+            if last[&key] == i {
+                pipeline.set(key, value)?;
+            }
+        }
+        for result in pipeline.finish()? {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Streams every pair whose key starts with `prefix`.
+    ///
+    /// The client cannot send other requests until the returned iterator is dropped.
+    pub fn scan(&mut self, prefix: String) -> Result<Scan<'_>> {
+        self.transport.send(&Request::Scan { prefix })?;
+
+        Ok(Scan {
+            transport: &mut self.transport,
+            done: false,
+        })
+    }
+
+    /// Streams changes to keys starting with `prefix` as they are applied.
+    ///
+    /// With `after`, changes following that position are replayed first, so
+    /// a watcher can resume where `Watch::position` said it stopped. Fails
+    /// with `Error::SequenceUnavailable` if the position is no longer in the
+    /// server's feed, or belongs to a feed before a restart. The connection
+    /// is dedicated to the stream from then on.
+    pub fn watch(mut self, prefix: String, after: Option<Position>) -> Result<Watch> {
+        self.transport.send(&Request::Watch { prefix, after })?;
+        let position = match self.transport.receive()? {
+            Response::Watching(position) => after.unwrap_or(position),
+            other => return Err(other.unexpected("the start of a watch")),
+        };
+
+        Ok(Watch {
+            transport: self.transport,
+            done: false,
+            position,
+        })
+    }
+}
+
+/// How to reach servers that may require a token or TLS, for code that
+/// opens connections of its own like `ShardedClient`.
+#[derive(Clone, Default)]
+pub struct Connector {
+    token: Option<String>,
+    tls: Option<ClientTls>,
+}
+
+impl Connector {
+    pub fn new() -> Self {
+        Connector::default()
+    }
+
+    /// Authenticates every connection with `token`.
+    pub fn token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Connects over TLS.
+    pub fn tls(mut self, tls: ClientTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
 
-        match SetResponse::deserialize(&mut self.reader)? {
-            SetResponse::Ok(data) => Ok(data),
-            SetResponse::Err(message) => Err(KvsError::WithMessage(message)),
+    pub fn connect(&self, addr: &Addr) -> Result<Client> {
+        let mut client = match (addr, &self.tls) {
+            (Addr::Tcp(addr), Some(tls)) => Client::connect_tls(addr, tls)?,
+            (Addr::Tcp(addr), None) => Client::connect(addr.as_str())?,
+            (Addr::Unix(_), Some(_)) => {
+                return Err(KvsError::Tls(
+                    "TLS is only used over TCP, not on a Unix socket".to_owned(),
+                ))
+            }
+            (Addr::Unix(path), None) => Client::connect_unix(path)?,
+        };
+        if let Some(token) = &self.token {
+            client.auth(token.clone())?;
         }
+        Ok(client)
+    }
+}
+
+/// Pairs streamed by the server in answer to `Client::scan`.
+pub struct Scan<'a> {
+    transport: &'a mut Transport,
+    done: bool,
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.transport.receive() {
+            Ok(Response::Entry { key, value }) => Some(Ok((key, value))),
+            Ok(Response::End) => {
+                self.done = true;
+                None
+            }
+            Ok(other) => {
+                self.done = true;
+                Some(Err(other.unexpected("a scan entry")))
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl Drop for Scan<'_> {
+    // Consume the rest of the stream so the connection can be reused.
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+/// Changes streamed by the server in answer to `Client::watch`.
+pub struct Watch {
+    transport: Transport,
+    done: bool,
+    position: Position,
+}
+
+impl Watch {
+    /// Where the watch stands: after the last change it returned, or where
+    /// it started.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.transport.next() {
+                Ok(Some(Response::Event(event))) => {
+                    self.position.seq = event.seq;
+                    return Some(Ok(event));
+                }
+                Ok(Some(Response::Heartbeat { .. })) => continue,
+                Ok(None) => self.done = true,
+                Ok(Some(other)) => {
+                    self.done = true;
+                    return Some(Err(other.unexpected("a change event")));
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The connection to the server, in whichever protocol was negotiated.
+///
+/// JSON responses are shaped by the kind of request they answer, so the
+/// kinds of the unanswered requests are kept.
+enum Transport {
+    Json {
+        reader: Deserializer<IoRead<BufReader<Stream>>>,
+        writer: BufWriter<Stream>,
+        /// Number of requests sent and answered; JSON answers come in order.
+        sent: u32,
+        answered: u32,
+        kinds: HashMap<u32, Kind>,
+    },
+    Binary {
+        reader: BufReader<Stream>,
+        writer: BufWriter<Stream>,
+        /// Id of the last request sent.
+        id: u32,
+    },
+}
+
+/// A response that has not been decoded yet, with the kind of request it
+/// answers when its shape depends on it.
+enum Payload {
+    Json(serde_json::Value, Option<Kind>),
+    Binary(Frame),
+}
+
+impl Payload {
+    fn decode(self) -> Result<Response> {
+        match self {
+            Payload::Json(value, kind) => legacy::decode(kind, value),
+            Payload::Binary(frame) => match frame.opcode {
+                OP_RESPONSE => frame.decode(),
+                OP_ERROR => Err(KvsError::Protocol(
+                    String::from_utf8_lossy(&frame.payload).into_owned(),
+                )),
+                opcode => Err(KvsError::Protocol(format!("unexpected opcode {}", opcode))),
+            },
+        }
+    }
+}
+
+impl Transport {
+    /// Opens a connection with the binary protocol, or opens another with
+    /// JSON if the server predates it.
+    fn open(open: impl Fn() -> Result<Stream>) -> Result<Transport> {
+        match Transport::binary(open()?) {
+            Ok(transport) => Ok(transport),
+            Err(err) => {
+                debug!("binary handshake failed, falling back to JSON: {}", err);
+                Transport::json(open()?)
+            }
+        }
+    }
+
+    fn json(stream: Stream) -> Result<Transport> {
+        let writer = BufWriter::new(stream.try_clone()?);
+        let reader = Deserializer::from_reader(BufReader::new(stream));
+        Ok(Transport::Json {
+            reader,
+            writer,
+            sent: 0,
+            answered: 0,
+            kinds: HashMap::new(),
+        })
+    }
+
+    fn binary(stream: Stream) -> Result<Transport> {
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        let mut reader = BufReader::new(stream);
+        protocol::connect(&mut reader, &mut writer)?;
+        Ok(Transport::Binary {
+            reader,
+            writer,
+            id: 0,
+        })
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        self.write(request)?;
+        self.flush()
+    }
+
+    /// Buffers a request, returning the id its response will carry.
+    fn write(&mut self, request: &Request) -> Result<u32> {
+        match self {
+            Transport::Json {
+                writer,
+                sent,
+                kinds,
+                ..
+            } => {
+                serde_json::to_writer(&mut *writer, request)?;
+                *sent = sent.wrapping_add(1);
+                if let Some(kind) = Kind::of(request) {
+                    kinds.insert(*sent, kind);
+                }
+                Ok(*sent)
+            }
+            Transport::Binary { writer, id, .. } => {
+                *id = id.wrapping_add(1);
+                Frame::encode(OP_REQUEST, *id, request)?.write(writer)?;
+                Ok(*id)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Transport::Json { writer, .. } | Transport::Binary { writer, .. } => {
+                Ok(writer.flush()?)
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Response> {
+        self.next()?.ok_or_else(closed)
+    }
+
+    /// Reads a response to the last request, which may be answered by a
+    /// stream of them, or `None` if the server closed the connection.
+    fn next(&mut self) -> Result<Option<Response>> {
+        match self {
+            Transport::Json {
+                reader,
+                sent,
+                answered,
+                kinds,
+                ..
+            } => match serde_json::Value::deserialize(reader) {
+                Ok(value) => {
+                    *answered = *sent;
+                    kinds.retain(|id, _| id == sent);
+                    let kind = kinds.get(sent).copied();
+                    Payload::Json(value, kind).decode().map(Some)
+                }
+                Err(err) if err.is_eof() => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            Transport::Binary { reader, id, .. } => match Frame::read(reader)? {
+                Some(frame) if frame.id == *id => Payload::Binary(frame).decode().map(Some),
+                Some(frame) => Err(KvsError::Protocol(format!(
+                    "response to request {} while waiting for {}",
+                    frame.id, id
+                ))),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Reads the next response to a request answered by a single response,
+    /// with the id of that request.
+    fn next_tagged(&mut self) -> Result<Option<(u32, Payload)>> {
+        match self {
+            Transport::Json {
+                reader,
+                answered,
+                kinds,
+                ..
+            } => match serde_json::Value::deserialize(reader) {
+                Ok(value) => {
+                    *answered = answered.wrapping_add(1);
+                    let kind = kinds.remove(answered);
+                    Ok(Some((*answered, Payload::Json(value, kind))))
+                }
+                Err(err) if err.is_eof() => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            Transport::Binary { reader, .. } => {
+                Ok(Frame::read(reader)?.map(|frame| (frame.id, Payload::Binary(frame))))
+            }
+        }
+    }
+}
+
+fn closed() -> KvsError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection").into()
+}
+
+/// Requests sent by `Client::pipeline` ahead of their responses.
+///
+/// The responses are matched to the requests by id, so servers may answer
+/// out of order; requests on the same key should therefore not share a
+/// pipeline if their order matters. Requests are not redirected to the
+/// cluster leader, but fail with `Error::NotLeader`.
+pub struct Pipeline<'a> {
+    transport: &'a mut Transport,
+    /// Index in `results` and kind of every unanswered request, by id.
+    in_flight: HashMap<u32, (usize, Kind)>,
+    results: Vec<Option<Result<Option<String>>>>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: String) -> Result<()> {
+        self.push(Request::Get { key }, Kind::Get)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.push(Request::Set { key, value }, Kind::Set)
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
+        self.push(Request::Remove { key }, Kind::Remove)
+    }
 
-        match RemoveResponse::deserialize(&mut self.reader)? {
-            RemoveResponse::Ok(data) => Ok(data),
-            RemoveResponse::Err(message) => Err(KvsError::WithMessage(message)),
+    /// Waits for every response and returns them in the order the requests
+    /// were made: the value for a get and `None` for a set or a remove.
+    ///
+    /// Fails as a whole only if the connection failed.
+    pub fn finish(mut self) -> Result<Vec<Result<Option<String>>>> {
+        self.drain()?;
+        let results = std::mem::take(&mut self.results);
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
+
+    fn push(&mut self, request: Request, kind: Kind) -> Result<()> {
+        // Read some responses back before the server blocks on a full socket.
+        if self.in_flight.len() >= PIPELINE_WINDOW {
+            self.transport.flush()?;
+            while self.in_flight.len() >= PIPELINE_WINDOW / 2 {
+                self.read_one()?;
+            }
         }
+
+        let id = self.transport.write(&request)?;
+        self.in_flight.insert(id, (self.results.len(), kind));
+        self.results.push(None);
+        Ok(())
     }
+
+    fn drain(&mut self) -> Result<()> {
+        self.transport.flush()?;
+        while !self.in_flight.is_empty() {
+            self.read_one()?;
+        }
+        Ok(())
+    }
+
+    fn read_one(&mut self) -> Result<()> {
+        let (id, payload) = self.transport.next_tagged()?.ok_or_else(closed)?;
+        let (index, kind) = self
+            .in_flight
+            .remove(&id)
+            .ok_or_else(|| KvsError::Protocol(format!("response to unknown request {}", id)))?;
+
+        let result = payload
+            .decode()
+            .and_then(|response| match (kind, response) {
+                (Kind::Get, Response::Value(value)) => Ok(value),
+                (Kind::Set, Response::Ok) | (Kind::Remove, Response::Ok) => Ok(None),
+                (Kind::Get, other) => Err(other.unexpected("a value")),
+                (_, other) => Err(other.unexpected("OK")),
+            });
+        self.results[index] = Some(result);
+        Ok(())
+    }
+}
+
+impl Drop for Pipeline<'_> {
+    // Read the outstanding responses so the connection can be reused.
+    fn drop(&mut self) {
+        let _ = self.drain();
+    }
+}
+
+pub(crate) fn no_leader() -> KvsError {
+    KvsError::Raft("no cluster leader found".to_owned())
 }
@@ -1,26 +1,256 @@
+use crate::raft::{ClusterStatus, RaftMessage, RaftResponse};
+use crate::{ChangeEvent, Error, Position, Result, Stats};
 use serde::{Deserialize, Serialize};
+use std::io;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    Backup {
+        path: String,
+    },
+    Scan {
+        prefix: String,
+    },
+    Stats,
+    Compact,
+    PauseCompaction {
+        paused: bool,
+    },
+    /// Turns the connection into a stream of changes to keys starting with
+    /// `prefix`, resuming after `after` when given, which fails when it names
+    /// the feed of an earlier server run. Answered with `Watching` first.
+    Watch {
+        prefix: String,
+        after: Option<Position>,
+    },
+    /// Turns the connection into a replication stream: a snapshot followed by
+    /// every change, or only the missed changes when `resume` is still in the
+    /// primary's feed.
+    Replicate {
+        resume: Option<Position>,
+    },
+    /// Makes a replica stop following its primary and accept writes.
+    Promote,
+    /// Sent between members of a Raft cluster.
+    Raft(RaftMessage),
+    ClusterStatus,
+    AddMember {
+        addr: String,
+    },
+    RemoveMember {
+        addr: String,
+    },
+    /// Authenticates the connection, which a server with `Auth` requires
+    /// before any other request.
+    Auth {
+        token: String,
+    },
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String),
+/// The answer to a request. Streaming requests are answered by several.
+///
+/// JSON clients get the response type of `Get`, `Set` and `Remove` instead.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum Response {
+    /// A request without a result succeeded.
+    Ok,
+    Value(Option<String>),
+    Stats(Stats),
+    Cluster(ClusterStatus),
+    Raft(RaftResponse),
+    /// One pair of a scan or of a replication snapshot.
+    Entry {
+        key: String,
+        value: String,
+    },
+    /// Ends a scan.
+    End,
+    /// A change streamed to a watcher or a replica.
+    Event(ChangeEvent),
+    /// Sent on a stream while no change arrives, carrying the latest sequence number.
+    Heartbeat {
+        last_seq: u64,
+    },
+    SnapshotStart,
+    /// Ends a replication snapshot; changes after `Position` follow.
+    SnapshotEnd(Position),
+    Err(ErrorResponse),
+    /// Starts a watch, with the position of the feed when it began.
+    Watching(Position),
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub enum RemoveResponse {
-    Ok(()),
-    Err(String),
+impl Response {
+    pub(crate) fn from_result<V, F>(result: Result<V>, ok: F) -> Response
+    where
+        F: FnOnce(V) -> Response,
+    {
+        match result {
+            Ok(value) => ok(value),
+            Err(err) => Response::Err(err.into()),
+        }
+    }
+
+    /// Fails with the error the server sent, or if the response is not one
+    /// `expected` was looking for.
+    pub(crate) fn unexpected(self, expected: &str) -> Error {
+        match self {
+            Response::Err(err) => err.into(),
+            other => Error::Protocol(format!("expected {}, got {:?}", expected, other)),
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub enum SetResponse {
-    Ok(()),
-    Err(String),
+/// An `Error` sent over the wire, which `Client` turns back into the same
+/// variant. Variants wrapping an error of another crate come back wrapping
+/// an error with the same message.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// The message of the variant, or of the error it wraps.
+    pub message: String,
+}
+
+/// Mirrors the variants of `Error`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    KeyNotFound,
+    IO,
+    Serde,
+    UnexpectedCommand,
+    WithMessage,
+    Sled,
+    UTF8,
+    UnknownEngine,
+    UnknownWrapper,
+    ReadOnly,
+    Migration,
+    IncompatibleFormat {
+        found: u32,
+        supported: u32,
+    },
+    Locked,
+    Backup,
+    Replication,
+    NotLeader(Option<String>),
+    Raft,
+    Sharding,
+    Protocol,
+    Http,
+    Bincode,
+    Csv,
+    SequenceUnavailable {
+        requested: u64,
+        oldest: u64,
+        last: u64,
+    },
+    Limit,
+    Auth,
+    PermissionDenied,
+    Tls,
+}
+
+impl From<Error> for ErrorResponse {
+    fn from(err: Error) -> Self {
+        let message = err.to_string();
+        let (code, message) = match err {
+            Error::KeyNotFound => (ErrorCode::KeyNotFound, message),
+            Error::IO(err) => (ErrorCode::IO, err.to_string()),
+            Error::Serde(err) => (ErrorCode::Serde, err.to_string()),
+            Error::UnexpectedCommand => (ErrorCode::UnexpectedCommand, message),
+            Error::WithMessage(message) => (ErrorCode::WithMessage, message),
+            Error::Sled(err) => (ErrorCode::Sled, err.to_string()),
+            Error::UTF8(err) => (ErrorCode::UTF8, err.to_string()),
+            Error::UnknownEngine(name) => (ErrorCode::UnknownEngine, name),
+            Error::UnknownWrapper(name) => (ErrorCode::UnknownWrapper, name),
+            Error::ReadOnly => (ErrorCode::ReadOnly, message),
+            Error::Migration(message) => (ErrorCode::Migration, message),
+            Error::IncompatibleFormat { found, supported } => {
+                (ErrorCode::IncompatibleFormat { found, supported }, message)
+            }
+            Error::Locked(dir) => (ErrorCode::Locked, dir),
+            Error::Backup(message) => (ErrorCode::Backup, message),
+            Error::Replication(message) => (ErrorCode::Replication, message),
+            Error::NotLeader(leader) => (ErrorCode::NotLeader(leader), message),
+            Error::Raft(message) => (ErrorCode::Raft, message),
+            Error::Sharding(message) => (ErrorCode::Sharding, message),
+            Error::Protocol(message) => (ErrorCode::Protocol, message),
+            Error::Http(message) => (ErrorCode::Http, message),
+            Error::Bincode(err) => (ErrorCode::Bincode, err.to_string()),
+            Error::Csv(err) => (ErrorCode::Csv, err.to_string()),
+            Error::SequenceUnavailable {
+                requested,
+                oldest,
+                last,
+            } => (
+                ErrorCode::SequenceUnavailable {
+                    requested,
+                    oldest,
+                    last,
+                },
+                message,
+            ),
+            Error::Limit(message) => (ErrorCode::Limit, message),
+            Error::Auth(message) => (ErrorCode::Auth, message),
+            Error::PermissionDenied(message) => (ErrorCode::PermissionDenied, message),
+            Error::Tls(message) => (ErrorCode::Tls, message),
+        };
+        ErrorResponse { code, message }
+    }
+}
+
+impl From<ErrorResponse> for Error {
+    fn from(err: ErrorResponse) -> Self {
+        let ErrorResponse { code, message } = err;
+        let io_error = io::Error::other;
+        match code {
+            ErrorCode::KeyNotFound => Error::KeyNotFound,
+            ErrorCode::IO => Error::IO(io_error(message)),
+            ErrorCode::Serde => Error::Serde(serde::de::Error::custom(message)),
+            ErrorCode::UnexpectedCommand => Error::UnexpectedCommand,
+            ErrorCode::WithMessage => Error::WithMessage(message),
+            ErrorCode::Sled => Error::Sled(sled::Error::Io(io_error(message))),
+            // A `FromUtf8Error` cannot be built from a message.
+            ErrorCode::UTF8 => Error::WithMessage(message),
+            ErrorCode::UnknownEngine => Error::UnknownEngine(message),
+            ErrorCode::UnknownWrapper => Error::UnknownWrapper(message),
+            ErrorCode::ReadOnly => Error::ReadOnly,
+            ErrorCode::Migration => Error::Migration(message),
+            ErrorCode::IncompatibleFormat { found, supported } => {
+                Error::IncompatibleFormat { found, supported }
+            }
+            ErrorCode::Locked => Error::Locked(message),
+            ErrorCode::Backup => Error::Backup(message),
+            ErrorCode::Replication => Error::Replication(message),
+            ErrorCode::NotLeader(leader) => Error::NotLeader(leader),
+            ErrorCode::Raft => Error::Raft(message),
+            ErrorCode::Sharding => Error::Sharding(message),
+            ErrorCode::Protocol => Error::Protocol(message),
+            ErrorCode::Http => Error::Http(message),
+            ErrorCode::Bincode => Error::Bincode(Box::new(bincode::ErrorKind::Custom(message))),
+            ErrorCode::Csv => Error::Csv(io_error(message).into()),
+            ErrorCode::SequenceUnavailable {
+                requested,
+                oldest,
+                last,
+            } => Error::SequenceUnavailable {
+                requested,
+                oldest,
+                last,
+            },
+            ErrorCode::Limit => Error::Limit(message),
+            ErrorCode::Auth => Error::Auth(message),
+            ErrorCode::PermissionDenied => Error::PermissionDenied(message),
+            ErrorCode::Tls => Error::Tls(message),
+        }
+    }
 }
@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Dead bytes that trigger a compaction under the default policy.
+pub const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Decides when a `Store` compacts on its own.
+///
+/// Automatic compaction runs once more than `min_dead_bytes` are dead, the
+/// dead-to-live ratio reaches `min_dead_ratio` (if set), the current UTC hour
+/// lies inside `window` (if set), and the policy is not paused. The store
+/// checks after every write and whenever a server calls `Engine::maintain`.
+/// Explicit `Engine::compact` calls ignore the policy.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CompactionPolicy {
+    pub min_dead_bytes: u64,
+    pub min_dead_ratio: Option<f64>,
+    pub window: Option<CompactionWindow>,
+    pub paused: bool,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            min_dead_bytes: COMPACTION_THRESHOLD,
+            min_dead_ratio: None,
+            window: None,
+            paused: false,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    pub fn should_compact(&self, dead_bytes: u64, live_bytes: u64) -> bool {
+        self.should_compact_at(dead_bytes, live_bytes, SystemTime::now())
+    }
+
+    pub fn should_compact_at(&self, dead_bytes: u64, live_bytes: u64, now: SystemTime) -> bool {
+        if self.paused || dead_bytes <= self.min_dead_bytes {
+            return false;
+        }
+
+        if let Some(ratio) = self.min_dead_ratio {
+            if (dead_bytes as f64) < ratio * live_bytes as f64 {
+                return false;
+            }
+        }
+
+        match &self.window {
+            Some(window) => window.contains(utc_hour(now)),
+            None => true,
+        }
+    }
+}
+
+/// Range of UTC hours, `start..end`, that may wrap around midnight (`22-6`).
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionWindow {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl CompactionWindow {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start_hour <= self.end_hour {
+            self.start_hour <= hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+impl FromStr for CompactionWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid compaction window {}, expected START-END hours", s);
+
+        let mut hours = s.splitn(2, '-').map(|hour| hour.trim().parse::<u8>());
+        match (hours.next(), hours.next()) {
+            (Some(Ok(start_hour)), Some(Ok(end_hour))) if start_hour < 24 && end_hour <= 24 => {
+                Ok(CompactionWindow {
+                    start_hour,
+                    end_hour,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for CompactionWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start_hour, self.end_hour)
+    }
+}
+
+fn utc_hour(now: SystemTime) -> u8 {
+    let secs = now
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    ((secs / 3600) % 24) as u8
+}
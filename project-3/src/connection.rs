@@ -0,0 +1,165 @@
+use crate::legacy::{self, Kind};
+use crate::limits::{too_large, LimitedReader};
+use crate::protocol::{Frame, OP_ERROR, OP_REQUEST, OP_RESPONSE};
+use crate::{Error, Request, Response, Result};
+use serde_json::de::{IoRead, StreamDeserializer};
+use serde_json::Deserializer;
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+/// The server side of a client connection, in either protocol.
+pub(crate) trait Connection {
+    /// Reads the next request, or `None` once the client closed the connection.
+    ///
+    /// Fails with `Error::Limit` for a request larger than the connection
+    /// accepts, which is not buffered.
+    fn read_request(&mut self) -> Result<Option<Request>>;
+
+    /// Buffers a response to the last request read.
+    fn write(&mut self, response: &Response) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+
+    fn send(&mut self, response: &Response) -> Result<()> {
+        self.write(response)?;
+        self.flush()
+    }
+}
+
+/// The original protocol: a stream of JSON values in each direction, with
+/// responses shaped as the `legacy` module does.
+pub(crate) struct JsonConnection<R: Read, W: Write> {
+    requests: StreamDeserializer<'static, IoRead<LimitedReader<R>>, Request>,
+    writer: W,
+    /// Kind of the last request read, if its response has a shape of its own.
+    kind: Option<Kind>,
+    /// Whether the last request could not be read.
+    unread: bool,
+    /// Bytes the request being read may still take.
+    budget: Rc<Cell<usize>>,
+    max_request_size: usize,
+}
+
+impl<R: Read, W: Write> JsonConnection<R, W> {
+    pub fn new(reader: R, writer: W, max_request_size: usize) -> Self {
+        let budget = Rc::new(Cell::new(max_request_size));
+        let reader = LimitedReader::new(reader, Rc::clone(&budget));
+        JsonConnection {
+            requests: Deserializer::from_reader(reader).into_iter(),
+            writer,
+            kind: None,
+            unread: false,
+            budget,
+            max_request_size,
+        }
+    }
+}
+
+impl<R: Read, W: Write> Connection for JsonConnection<R, W> {
+    fn read_request(&mut self) -> Result<Option<Request>> {
+        self.budget.set(self.max_request_size);
+        self.kind = None;
+        let request = match self.requests.next() {
+            Some(Err(_)) if self.budget.get() == 0 => Err(too_large(self.max_request_size)),
+            Some(Err(err)) if err.is_io() => Err(io::Error::from(err).into()),
+            request => request.transpose().map_err(Error::from),
+        };
+        self.kind = match &request {
+            Ok(request) => request.as_ref().and_then(Kind::of),
+            Err(_) => None,
+        };
+        self.unread = request.is_err();
+        request
+    }
+
+    fn write(&mut self, response: &Response) -> Result<()> {
+        let response = if self.unread {
+            legacy::encode_unread(response.clone())
+        } else {
+            legacy::encode(self.kind, response.clone())?
+        };
+        Ok(serde_json::to_writer(&mut self.writer, &response)?)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// The framed binary protocol, after the handshake agreed on `version`.
+pub(crate) struct BinaryConnection<R: Read, W: Write> {
+    reader: R,
+    writer: W,
+    version: u8,
+    /// Id of the request being answered.
+    id: u32,
+    max_request_size: usize,
+}
+
+impl<R: Read, W: Write> BinaryConnection<R, W> {
+    pub fn new(reader: R, writer: W, version: u8, max_request_size: usize) -> Self {
+        BinaryConnection {
+            reader,
+            writer,
+            version,
+            id: 0,
+            max_request_size,
+        }
+    }
+
+    /// Tells the client that frame `id` was skipped.
+    fn reject(&mut self, id: u32, message: String) -> Result<()> {
+        debug!("rejected frame {}: {}", id, message);
+        let frame = Frame {
+            version: self.version,
+            opcode: OP_ERROR,
+            id,
+            payload: message.into_bytes(),
+        };
+        frame.write(&mut self.writer)?;
+        Ok(self.writer.flush()?)
+    }
+}
+
+impl<R: Read, W: Write> Connection for BinaryConnection<R, W> {
+    fn read_request(&mut self) -> Result<Option<Request>> {
+        while let Some(frame) = Frame::read_limited(&mut self.reader, self.max_request_size)? {
+            let frame = match frame {
+                Ok(frame) => frame,
+                // The frame was skipped, so the connection is still usable.
+                Err((id, len)) => {
+                    debug!("refused frame {} of {} bytes", id, len);
+                    self.id = id;
+                    self.send(&Response::Err(too_large(self.max_request_size).into()))?;
+                    continue;
+                }
+            };
+            if frame.version != self.version {
+                let message = format!("expected version {}", self.version);
+                self.reject(frame.id, message)?;
+            } else if frame.opcode != OP_REQUEST {
+                self.reject(frame.id, format!("unexpected opcode {}", frame.opcode))?;
+            } else {
+                match frame.decode() {
+                    Ok(request) => {
+                        self.id = frame.id;
+                        return Ok(Some(request));
+                    }
+                    Err(err) => self.reject(frame.id, format!("invalid request: {}", err))?,
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn write(&mut self, response: &Response) -> Result<()> {
+        let mut frame = Frame::encode(OP_RESPONSE, self.id, response)?;
+        frame.version = self.version;
+        frame.write(&mut self.writer)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
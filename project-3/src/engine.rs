@@ -1,7 +1,150 @@
-use crate::Result;
+use crate::{Result, Stats};
+use std::path::Path;
 
-pub trait Engine {
+pub trait Engine: Send {
     fn set(&mut self, key: String, value: String) -> Result<()>;
     fn get(&mut self, key: String) -> Result<Option<String>>;
     fn remove(&mut self, key: String) -> Result<()>;
+    /// Sets every pair. The writes may only reach the disk at the next
+    /// `sync`, so bulk loads need not flush once per key.
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+    fn keys(&mut self) -> Result<Vec<String>>;
+    /// Up to `limit` keys from `start` on, in order. Engines that keep their
+    /// keys sorted answer without looking at the others.
+    fn keys_from(&mut self, start: &str, limit: usize) -> Result<Vec<String>> {
+        let mut keys = self.keys()?;
+        keys.retain(|key| key.as_str() >= start);
+        keys.sort_unstable();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+    /// Writes a consistent copy of the engine data into the empty directory `target`.
+    fn backup(&mut self, target: &Path) -> Result<()>;
+    fn stats(&mut self) -> Result<Stats>;
+    /// Reclaims space held by overwritten and removed values now.
+    fn compact(&mut self) -> Result<()>;
+    /// Stops or resumes compactions the engine would start on its own.
+    fn pause_compaction(&mut self, paused: bool) -> Result<()>;
+    /// Starts the work the engine would do on its own if it is due, such as
+    /// a compaction its policy only allows at certain hours. Servers call it
+    /// now and then, so that it does not wait for the next write.
+    fn maintain(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Forces acknowledged writes onto the disk.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<E: Engine + ?Sized> Engine for Box<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        (**self).set_many(pairs)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        (**self).keys()
+    }
+
+    fn keys_from(&mut self, start: &str, limit: usize) -> Result<Vec<String>> {
+        (**self).keys_from(start, limit)
+    }
+
+    fn backup(&mut self, target: &Path) -> Result<()> {
+        (**self).backup(target)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        (**self).stats()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        (**self).compact()
+    }
+
+    fn pause_compaction(&mut self, paused: bool) -> Result<()> {
+        (**self).pause_compaction(paused)
+    }
+
+    fn maintain(&mut self) -> Result<()> {
+        (**self).maintain()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+}
+
+/// Keys read from an engine at a time, so a walk over them never holds them all.
+const BATCH_KEYS: usize = 1024;
+
+/// Walks the keys that start with a prefix in order, `BATCH_KEYS` at a time.
+pub(crate) struct KeyBatches {
+    prefix: String,
+    /// Where the next batch starts, or `None` once the walk is over.
+    start: Option<String>,
+}
+
+impl KeyBatches {
+    pub(crate) fn new(prefix: &str) -> Self {
+        KeyBatches {
+            prefix: prefix.to_owned(),
+            start: Some(prefix.to_owned()),
+        }
+    }
+
+    /// The next keys of `engine`, none once the walk is over.
+    pub(crate) fn next_keys<E: Engine + ?Sized>(&mut self, engine: &mut E) -> Result<Vec<String>> {
+        let start = match self.start.take() {
+            Some(start) => start,
+            None => return Ok(Vec::new()),
+        };
+        let mut keys = engine.keys_from(&start, BATCH_KEYS)?;
+        let mut more = keys.len() == BATCH_KEYS;
+        if let Some(end) = keys.iter().position(|key| !key.starts_with(&self.prefix)) {
+            keys.truncate(end);
+            more = false;
+        }
+        if more {
+            // The smallest key after the last one.
+            self.start = keys.last().map(|last| format!("{}\0", last));
+        }
+        Ok(keys)
+    }
+
+    /// The pairs of the next keys that still have a value, or `None` once
+    /// the walk is over.
+    pub(crate) fn next_entries<E: Engine + ?Sized>(
+        &mut self,
+        engine: &mut E,
+    ) -> Result<Option<Vec<(String, String)>>> {
+        let keys = self.next_keys(engine)?;
+        if keys.is_empty() {
+            return Ok(None);
+        }
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = engine.get(key.clone())? {
+                entries.push((key, value));
+            }
+        }
+        Ok(Some(entries))
+    }
 }
@@ -0,0 +1,214 @@
+use super::store::{lock_dir, log_path, sorted_gen_list, Command};
+use crate::Result;
+use serde_json::Deserializer;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordProblem {
+    /// The log ends in the middle of a record.
+    Truncated,
+    /// The bytes at the offset are not a valid record.
+    Corrupt(String),
+}
+
+/// Result of checking one `N.log` file.
+#[derive(Debug, Clone)]
+pub struct GenerationReport {
+    pub gen: u64,
+    pub size: u64,
+    pub records: u64,
+    /// Length of the log before its first problem.
+    pub valid_bytes: u64,
+    /// Every unreadable record and its offset. Reading resumes at the next
+    /// record start found after each, so later records are still checked.
+    pub problems: Vec<(u64, RecordProblem)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FsckReport {
+    pub generations: Vec<GenerationReport>,
+    pub live_keys: u64,
+    pub live_bytes: u64,
+    /// Bytes of overwritten, removed or unreadable records.
+    pub dead_bytes: u64,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.generations.iter().all(|gen| gen.problems.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RepairReport {
+    pub gen: u64,
+    pub live_keys: u64,
+    pub removed_generations: u64,
+}
+
+struct Record {
+    gen: u64,
+    pos: u64,
+    len: u64,
+}
+
+/// Reads every generation of the `Store` in `dir` and validates each record.
+///
+/// Does not take the directory lock, so it can run next to a live server;
+/// a record being appended at that moment may be reported as truncated.
+pub fn check(dir: &Path) -> Result<FsckReport> {
+    let (report, _) = scan(dir)?;
+    Ok(report)
+}
+
+/// Rewrites the live records of the `Store` in `dir` into a new generation
+/// and removes every older generation, dropping unreadable data.
+///
+/// Fails with `Error::Locked` while a store has the directory open.
+pub fn repair(dir: &Path) -> Result<RepairReport> {
+    let _lock = lock_dir(dir)?;
+    let (report, index) = scan(dir)?;
+
+    let gen = report.generations.last().map_or(0, |last| last.gen) + 1;
+    let mut writer = BufWriter::new(
+        std::fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(log_path(dir, gen))?,
+    );
+
+    let mut readers = BTreeMap::<u64, BufReader<File>>::new();
+    for record in index.values() {
+        let reader = match readers.entry(record.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(BufReader::new(File::open(log_path(dir, record.gen))?))
+            }
+        };
+        reader.seek(SeekFrom::Start(record.pos))?;
+        std::io::copy(&mut reader.take(record.len), &mut writer)?;
+    }
+
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    sync_dir(dir)?;
+    drop(readers);
+
+    for stale in &report.generations {
+        std::fs::remove_file(log_path(dir, stale.gen))?;
+    }
+    sync_dir(dir)?;
+
+    Ok(RepairReport {
+        gen,
+        live_keys: index.len() as u64,
+        removed_generations: report.generations.len() as u64,
+    })
+}
+
+fn scan(dir: &Path) -> Result<(FsckReport, BTreeMap<String, Record>)> {
+    let mut index = BTreeMap::<String, Record>::new();
+    let mut generations = Vec::new();
+    let mut total_bytes: u64 = 0;
+
+    for gen in sorted_gen_list(dir)? {
+        let file = File::open(log_path(dir, gen))?;
+        let size = file.metadata()?.len();
+        total_bytes += size;
+
+        let mut report = GenerationReport {
+            gen,
+            size,
+            records: 0,
+            valid_bytes: size,
+            problems: Vec::new(),
+        };
+
+        let mut reader = BufReader::new(file);
+        let mut start = Some(0);
+        while let Some(segment) = start.take() {
+            reader.seek(SeekFrom::Start(segment))?;
+            let mut pos = segment;
+            let mut stream = Deserializer::from_reader(&mut reader).into_iter::<Command>();
+            while let Some(cmd) = stream.next() {
+                let new_pos = segment + stream.byte_offset() as u64;
+                match cmd {
+                    Ok(Command::Set { key, .. }) => {
+                        let len = new_pos - pos;
+                        index.insert(key, Record { gen, pos, len });
+                    }
+                    Ok(Command::Remove { key }) => {
+                        index.remove(&key);
+                    }
+                    Err(err) => {
+                        report.valid_bytes = report.valid_bytes.min(pos);
+                        if err.is_eof() {
+                            report.problems.push((pos, RecordProblem::Truncated));
+                        } else {
+                            report
+                                .problems
+                                .push((pos, RecordProblem::Corrupt(format!("{}", err))));
+                            start = Some(pos + 1);
+                        }
+                        break;
+                    }
+                }
+                report.records += 1;
+                pos = new_pos;
+            }
+            if let Some(from) = start {
+                start = next_record(&mut reader, from)?;
+            }
+        }
+
+        generations.push(report);
+    }
+
+    let live_bytes: u64 = index.values().map(|record| record.len).sum();
+    let report = FsckReport {
+        generations,
+        live_keys: index.len() as u64,
+        live_bytes,
+        dead_bytes: total_bytes - live_bytes,
+    };
+    Ok((report, index))
+}
+
+/// Offset of the first record start at or after `from`, found by the bytes
+/// every serialized `Command` begins with.
+fn next_record(reader: &mut BufReader<File>, from: u64) -> Result<Option<u64>> {
+    const STARTS: [&[u8]; 2] = [br#"{"Set":"#, br#"{"Remove":"#];
+    const KEEP: usize = 9;
+
+    reader.seek(SeekFrom::Start(from))?;
+    let mut window = Vec::new();
+    let mut window_pos = from;
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(None);
+        }
+        let read = chunk.len();
+        window.extend_from_slice(chunk);
+        reader.consume(read);
+
+        let found = (0..window.len())
+            .find(|&i| STARTS.iter().any(|marker| window[i..].starts_with(marker)));
+        if let Some(i) = found {
+            return Ok(Some(window_pos + i as u64));
+        }
+        // Keep enough bytes for a marker split across two reads.
+        let skip = window.len().saturating_sub(KEEP);
+        window.drain(..skip);
+        window_pos += skip as u64;
+    }
+}
+
+/// Makes file creations and removals in `dir` durable.
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
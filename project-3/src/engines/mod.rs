@@ -1,5 +1,8 @@
 pub use self::sled::Sled;
+pub use read_only::ReadOnly;
 pub use store::Store;
 
+pub mod fsck;
+mod read_only;
 mod sled;
 mod store;
@@ -0,0 +1,57 @@
+use crate::Engine as KvsEngine;
+use crate::Error as KvsError;
+use crate::{Result, Stats};
+use std::path::Path;
+
+/// Wraps an engine and rejects every mutation with `Error::ReadOnly`.
+pub struct ReadOnly<E: KvsEngine> {
+    inner: E,
+}
+
+impl<E: KvsEngine> ReadOnly<E> {
+    pub fn new(inner: E) -> Self {
+        ReadOnly { inner }
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for ReadOnly<E> {
+    fn set(&mut self, _key: String, _value: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn remove(&mut self, _key: String) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.inner.keys()
+    }
+
+    fn keys_from(&mut self, start: &str, limit: usize) -> Result<Vec<String>> {
+        self.inner.keys_from(start, limit)
+    }
+
+    fn backup(&mut self, target: &Path) -> Result<()> {
+        self.inner.backup(target)
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        self.inner.stats()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn pause_compaction(&mut self, paused: bool) -> Result<()> {
+        self.inner.pause_compaction(paused)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.inner.sync()
+    }
+}
@@ -1,7 +1,8 @@
 use crate::Engine as KvsEngine;
 use crate::Error as KvsError;
-use crate::Result;
+use crate::{Result, Stats};
 use sled::{Db, Tree};
+use std::path::Path;
 
 // #[derive(Clone)]
 pub struct Sled {
@@ -38,4 +39,63 @@ impl KvsEngine for Sled {
         tree.flush()?;
         Ok(())
     }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.into_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        let tree: &Tree = &self.db;
+        tree.iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn keys_from(&mut self, start: &str, limit: usize) -> Result<Vec<String>> {
+        let tree: &Tree = &self.db;
+        tree.range(start..)
+            .keys()
+            .take(limit)
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn backup(&mut self, target: &Path) -> Result<()> {
+        let copy = sled::open(target)?;
+        copy.import(self.db.export());
+        copy.flush()?;
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        Ok(Stats {
+            engine: "sled".to_owned(),
+            keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            ..Stats::default()
+        })
+    }
+
+    // sled reclaims space in its own threads, and cannot be told to start.
+    fn compact(&mut self) -> Result<()> {
+        Err(KvsError::WithMessage("sled compacts on its own".to_owned()))
+    }
+
+    // sled compacts in its own threads, which cannot be held back.
+    fn pause_compaction(&mut self, _paused: bool) -> Result<()> {
+        Err(KvsError::WithMessage(
+            "sled cannot pause its compaction".to_owned(),
+        ))
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
 }
@@ -1,4 +1,6 @@
-use crate::{Engine as KvsEngine, Error, Result};
+use crate::{
+    CompactionPolicy, CompactionStats, Engine as KvsEngine, Error, GenerationStats, Result, Stats,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeMap, HashMap},
@@ -6,24 +8,58 @@ use std::{
     io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
     ops::Range,
     path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
+use fs2::FileExt;
 use serde_json::Deserializer;
 
+const LOCK_FILE: &str = "LOCK";
+
 pub struct Store {
     path: PathBuf,
     readers: HashMap<u64, BufReaderWithPos<File>>,
-    writer: BufWriterWithPos<File>,
+    writer: Option<BufWriterWithPos<File>>,
     index: BTreeMap<String, CommandPosition>,
     uncompacted: u64,
+    live: u64,
     current_gen: u64,
+    policy: CompactionPolicy,
+    compaction: CompactionStats,
+    /// The exclusive lock on `LOCK` of a writable store, held so that no
+    /// other process opens the directory for writing.
+    _lock: Option<File>,
 }
 
 impl Store {
+    /// Opens the store in `dir` for reading and writing.
+    ///
+    /// Fails with `Error::Locked` while another writer holds the directory.
     pub fn open(dir: impl Into<PathBuf>) -> Result<Store> {
         let path = dir.into();
         std::fs::create_dir_all(&path)?;
 
+        let lock = lock_dir(&path)?;
+        let mut store = Store::load(path, Some(lock))?;
+
+        let writer = new_log_file(&store.path, store.current_gen, &mut store.readers)?;
+        store.writer = Some(writer);
+
+        Ok(store)
+    }
+
+    /// Opens the store in `dir` without taking the directory lock.
+    ///
+    /// The store sees the data present when it was opened and rejects
+    /// `set` and `remove` with `Error::ReadOnly`, so it may be used next to
+    /// a running writer.
+    pub fn open_read_only(dir: impl Into<PathBuf>) -> Result<Store> {
+        Store::load(dir.into(), None)
+    }
+
+    /// Reads the logs in `path`, read-only unless `lock` is held on it.
+    fn load(path: PathBuf, lock: Option<File>) -> Result<Store> {
+        let read_only = lock.is_none();
         let gen_list = sorted_gen_list(&path)?;
 
         let mut readers = HashMap::<u64, BufReaderWithPos<File>>::new();
@@ -36,30 +72,55 @@ impl Store {
             let log_file = File::open(file_path)?;
             let mut log_reader = BufReaderWithPos::new(log_file)?;
 
-            let uncompacted_log = load(gen, &mut log_reader, &mut index)?;
+            let uncompacted_log = load(gen, &mut log_reader, &mut index, read_only)?;
 
             uncompacted += uncompacted_log;
             readers.insert(gen, log_reader);
         }
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+        let live = index.values().map(|cmd| cmd.len).sum();
 
         Ok(Store {
             path,
             readers,
-            writer,
+            writer: None,
             index,
             uncompacted,
+            live,
             current_gen,
+            policy: CompactionPolicy::default(),
+            compaction: CompactionStats::default(),
+            _lock: lock,
         })
     }
 
-    fn compact(&mut self) -> Result<()> {
+    pub fn compaction_policy(&self) -> &CompactionPolicy {
+        &self.policy
+    }
+
+    /// Replaces the policy deciding when `set` compacts the logs on its own.
+    pub fn set_compaction_policy(&mut self, policy: CompactionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Compacts if the policy asks for it.
+    fn compact_if_due(&mut self) -> Result<()> {
+        if self.policy.should_compact(self.uncompacted, self.live) {
+            self.compact_logs()?;
+        }
+        Ok(())
+    }
+
+    fn compact_logs(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(Error::ReadOnly);
+        }
+
+        let started = Instant::now();
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
+        self.writer = Some(self.new_log_file(self.current_gen)?);
 
         let mut writer = self.new_log_file(compaction_gen)?;
 
@@ -106,6 +167,15 @@ impl Store {
         }
 
         self.uncompacted = 0;
+
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        self.compaction.count += 1;
+        self.compaction.total_duration_ms += elapsed_ms;
+        self.compaction.last_duration_ms = Some(elapsed_ms);
+        self.compaction.last_finished_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .ok();
         Ok(())
     }
 
@@ -116,31 +186,28 @@ impl Store {
 
 impl KvsEngine for Store {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        const COPMACTION_THRESHOLD: u64 = 1024 * 1024;
-
-        let pos: u64 = self.writer.pos;
+        let writer = self.writer.as_mut().ok_or(Error::ReadOnly)?;
+        let pos: u64 = writer.pos;
 
         {
             serde_json::to_writer(
-                &mut self.writer,
+                &mut *writer,
                 &Command::Set {
                     key: key.clone(),
                     value,
                 },
             )?;
-            self.writer.flush()?;
+            writer.flush()?;
         }
 
-        let to_insert_value: CommandPosition = (self.current_gen, (pos..self.writer.pos)).into();
+        let to_insert_value: CommandPosition = (self.current_gen, (pos..writer.pos)).into();
+        self.live += to_insert_value.len;
         if let Some(inserted) = self.index.insert(key, to_insert_value) {
             self.uncompacted += inserted.len;
+            self.live -= inserted.len;
         }
 
-        if self.uncompacted > COPMACTION_THRESHOLD {
-            self.compact()?;
-        }
-
-        Ok(())
+        self.compact_if_due()
     }
 
     fn get(&mut self, key: String) -> Result<Option<String>> {
@@ -160,23 +227,109 @@ impl KvsEngine for Store {
         }
     }
     fn remove(&mut self, key: String) -> Result<()> {
+        let writer = self.writer.as_mut().ok_or(Error::ReadOnly)?;
         if self.index.contains_key(&key) {
+            let pos = writer.pos;
             {
                 let cmd = Command::Remove { key: key.clone() };
-                serde_json::to_writer(&mut self.writer, &cmd)?;
+                serde_json::to_writer(&mut *writer, &cmd)?;
             }
-            self.writer.flush()?;
+            writer.flush()?;
+            // The removal itself is dead once written, as `load` counts it.
+            let record_len = writer.pos - pos;
 
             let removed = self
                 .index
                 .remove(&key)
                 .expect("key not found after index.contains_key");
-            self.uncompacted += removed.len;
-            Ok(())
+            self.uncompacted += removed.len + record_len;
+            self.live -= removed.len;
+            self.compact_if_due()
         } else {
             Err(Error::KeyNotFound)
         }
     }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.index.keys().cloned().collect())
+    }
+
+    fn keys_from(&mut self, start: &str, limit: usize) -> Result<Vec<String>> {
+        Ok(self
+            .index
+            .range(start.to_owned()..)
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect())
+    }
+
+    fn backup(&mut self, target: &Path) -> Result<()> {
+        // Copy only the live records, so that the backup comes out compacted
+        // and the logs of the store are left as they are.
+        let mut writer = BufWriter::new(File::create(log_path(target, 1))?);
+        for command in self.index.values() {
+            let reader = self
+                .readers
+                .get_mut(&command.gen)
+                .expect("cannot find log reader");
+
+            if command.pos != reader.pos {
+                reader.seek(SeekFrom::Start(command.pos))?;
+            }
+            std::io::copy(&mut reader.take(command.len), &mut writer)?;
+        }
+
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        self.compact_logs()
+    }
+
+    fn pause_compaction(&mut self, paused: bool) -> Result<()> {
+        self.policy.paused = paused;
+        Ok(())
+    }
+
+    fn maintain(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.compact_if_due()
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+            writer.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        let mut gens: Vec<u64> = self.readers.keys().cloned().collect();
+        gens.sort_unstable();
+
+        let generations = gens
+            .into_iter()
+            .map(|gen| {
+                let bytes = std::fs::metadata(log_path(&self.path, gen))?.len();
+                Ok(GenerationStats { gen, bytes })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Stats {
+            engine: "kvs".to_owned(),
+            keys: self.index.len() as u64,
+            disk_bytes: generations.iter().map(|gen| gen.bytes).sum(),
+            live_bytes: Some(self.live),
+            dead_bytes: Some(self.uncompacted),
+            generations,
+            compaction: self.compaction.clone(),
+        })
+    }
 }
 
 struct BufWriterWithPos<T: Write + Seek> {
@@ -186,7 +339,7 @@ struct BufWriterWithPos<T: Write + Seek> {
 
 impl<T: Write + Seek> BufWriterWithPos<T> {
     fn new(mut inner: T) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
 
         Ok(BufWriterWithPos {
             pos,
@@ -202,7 +355,7 @@ struct BufReaderWithPos<T: Read + Seek> {
 
 impl<T: Read + Seek> BufReaderWithPos<T> {
     fn new(mut inner: T) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
 
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
@@ -262,12 +415,12 @@ impl From<(u64, Range<u64>)> for CommandPosition {
 }
 
 #[derive(Deserialize, Serialize)]
-enum Command {
+pub(super) enum Command {
     Set { key: String, value: String },
     Remove { key: String },
 }
 
-fn sorted_gen_list<'a>(path: &'a Path) -> Result<Vec<u64>> {
+pub(super) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let dir: std::fs::ReadDir = std::fs::read_dir(path)?;
 
     let mut list: Vec<u64> = dir
@@ -291,10 +444,9 @@ fn new_log_file(
     gen: u64,
     readers: &mut HashMap<u64, BufReaderWithPos<File>>,
 ) -> Result<BufWriterWithPos<File>> {
-    let p = log_path(&path, gen);
+    let p = log_path(path, gen);
     let file = std::fs::OpenOptions::new()
         .create(true)
-        .write(true)
         .append(true)
         .open(&p)?;
     let file_to_read = File::open(&p)?;
@@ -303,7 +455,23 @@ fn new_log_file(
     Ok(writer)
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
+pub(super) fn lock_dir(dir: &Path) -> Result<File> {
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir.join(LOCK_FILE))?;
+
+    match lock.try_lock_exclusive() {
+        Ok(()) => Ok(lock),
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+            Err(Error::Locked(dir.display().to_string()))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub(super) fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
@@ -311,6 +479,7 @@ fn load(
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
     index: &mut BTreeMap<String, CommandPosition>,
+    allow_truncated_tail: bool,
 ) -> Result<u64> {
     let mut pos = reader.seek(SeekFrom::Start(0))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
@@ -318,7 +487,12 @@ fn load(
 
     while let Some(cmd) = stream.next() {
         let new_pos: u64 = stream.byte_offset() as u64;
-        match cmd? {
+        let cmd = match cmd {
+            // A live writer may be in the middle of appending the last record.
+            Err(err) if allow_truncated_tail && err.is_eof() => break,
+            cmd => cmd?,
+        };
+        match cmd {
             Command::Set { key, .. } => {
                 let value: CommandPosition = (gen, pos..new_pos).into();
                 if let Some(old) = index.insert(key, value) {
@@ -1,23 +1,82 @@
-use failure::Fail;
 use std::io::Error as ErrorIO;
 use std::string::FromUtf8Error;
 
-#[derive(Fail, Debug)]
-pub enum Error {
-    #[fail(display = "Key not found")]
-    KeyNotFound,
-    #[fail(display = "io error: {}", _0)]
-    IO(ErrorIO),
-    #[fail(display = "serde error")]
-    Serde(serde_json::Error),
-    #[fail(display = "unexpected command type")]
-    UnexpectedCommand,
-    #[fail(display = "error: {}", _0)]
-    WithMessage(String),
-    #[fail(display = "error: {}", _0)]
-    Sled(sled::Error),
-    #[fail(display = "UTF-8 error: {}", _0)]
-    UTF8(FromUtf8Error),
+pub use self::derived::Error;
+
+/// Holds only `Error`, whose `Fail` derive emits its impls inside a constant.
+mod derived {
+    #![allow(non_local_definitions)]
+
+    use super::{ErrorIO, FromUtf8Error};
+    use failure::Fail;
+
+    #[derive(Fail, Debug)]
+    pub enum Error {
+        #[fail(display = "Key not found")]
+        KeyNotFound,
+        #[fail(display = "io error: {}", _0)]
+        IO(ErrorIO),
+        #[fail(display = "serde error")]
+        Serde(serde_json::Error),
+        #[fail(display = "unexpected command type")]
+        UnexpectedCommand,
+        #[fail(display = "error: {}", _0)]
+        WithMessage(String),
+        #[fail(display = "error: {}", _0)]
+        Sled(sled::Error),
+        #[fail(display = "UTF-8 error: {}", _0)]
+        UTF8(FromUtf8Error),
+        #[fail(display = "unknown engine: {}", _0)]
+        UnknownEngine(String),
+        #[fail(display = "unknown engine wrapper: {}", _0)]
+        UnknownWrapper(String),
+        #[fail(display = "engine is read-only")]
+        ReadOnly,
+        #[fail(display = "migration error: {}", _0)]
+        Migration(String),
+        #[fail(
+            display = "incompatible data format version {} (supported: {})",
+            found, supported
+        )]
+        IncompatibleFormat { found: u32, supported: u32 },
+        #[fail(display = "data directory {} is locked by another process", _0)]
+        Locked(String),
+        #[fail(display = "backup error: {}", _0)]
+        Backup(String),
+        #[fail(display = "replication error: {}", _0)]
+        Replication(String),
+        #[fail(display = "not the cluster leader")]
+        NotLeader(Option<String>),
+        #[fail(display = "raft error: {}", _0)]
+        Raft(String),
+        #[fail(display = "sharding error: {}", _0)]
+        Sharding(String),
+        #[fail(display = "protocol error: {}", _0)]
+        Protocol(String),
+        #[fail(display = "http error: {}", _0)]
+        Http(String),
+        #[fail(display = "bincode error: {}", _0)]
+        Bincode(bincode::Error),
+        #[fail(display = "csv error: {}", _0)]
+        Csv(csv::Error),
+        #[fail(
+            display = "cannot resume after sequence {}, the feed holds {} to {}",
+            requested, oldest, last
+        )]
+        SequenceUnavailable {
+            requested: u64,
+            oldest: u64,
+            last: u64,
+        },
+        #[fail(display = "limit exceeded: {}", _0)]
+        Limit(String),
+        #[fail(display = "authentication error: {}", _0)]
+        Auth(String),
+        #[fail(display = "permission denied: {}", _0)]
+        PermissionDenied(String),
+        #[fail(display = "TLS error: {}", _0)]
+        Tls(String),
+    }
 }
 
 impl From<ErrorIO> for Error {
@@ -38,6 +97,18 @@ impl From<sled::Error> for Error {
     }
 }
 
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Self {
+        Error::Bincode(err)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(err: csv::Error) -> Self {
+        Error::Csv(err)
+    }
+}
+
 impl From<FromUtf8Error> for Error {
     fn from(err: FromUtf8Error) -> Self {
         Error::UTF8(err)
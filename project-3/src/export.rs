@@ -0,0 +1,178 @@
+use crate::engine::KeyBatches;
+use crate::{Engine, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+const PROGRESS_EVERY: u64 = 10_000;
+
+/// Serialization used by `export` and `import`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One `{"key": ..., "value": ...}` object per line.
+    JsonLines,
+    /// A `key,value` header followed by one record per pair.
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {}, expected jsonl or csv", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::JsonLines => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+}
+
+pub enum EntryWriter<W: Write> {
+    JsonLines(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> EntryWriter<W> {
+    pub fn new(format: Format, writer: W) -> Self {
+        match format {
+            Format::JsonLines => EntryWriter::JsonLines(writer),
+            Format::Csv => EntryWriter::Csv(Box::new(csv::Writer::from_writer(writer))),
+        }
+    }
+
+    pub fn write(&mut self, entry: &Entry) -> Result<()> {
+        match self {
+            EntryWriter::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, entry)?;
+                writer.write_all(b"\n")?;
+            }
+            EntryWriter::Csv(writer) => writer.serialize(entry)?,
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.into_inner()?.flush()?;
+        Ok(())
+    }
+
+    /// Flushes buffered records and returns the underlying writer.
+    pub fn into_inner(self) -> Result<W> {
+        match self {
+            EntryWriter::JsonLines(writer) => Ok(writer),
+            EntryWriter::Csv(writer) => writer.into_inner().map_err(|err| {
+                Error::from(std::io::Error::new(
+                    err.error().kind(),
+                    err.error().to_string(),
+                ))
+            }),
+        }
+    }
+}
+
+/// Parses the entries of `reader` lazily, one record at a time.
+pub fn read_entries<'a, R: Read + 'a>(
+    format: Format,
+    reader: R,
+) -> Box<dyn Iterator<Item = Result<Entry>> + 'a> {
+    match format {
+        Format::JsonLines => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ),
+        Format::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize()
+                .map(|entry| entry.map_err(Error::from)),
+        ),
+    }
+}
+
+/// Counts processed entries, handing the count to a callback every
+/// `PROGRESS_EVERY` entries and once more, marked done, when finished.
+pub struct Progress {
+    count: u64,
+    report: Box<dyn FnMut(u64, bool)>,
+}
+
+impl Progress {
+    pub fn new(report: impl FnMut(u64, bool) + 'static) -> Self {
+        Progress {
+            count: 0,
+            report: Box::new(report),
+        }
+    }
+
+    pub fn inc(&mut self) {
+        self.count += 1;
+        if self.count % PROGRESS_EVERY == 0 {
+            (self.report)(self.count, false);
+        }
+    }
+
+    pub fn finish(&mut self) {
+        (self.report)(self.count, true);
+    }
+}
+
+/// Reports nothing.
+impl Default for Progress {
+    fn default() -> Self {
+        Progress::new(|_, _| {})
+    }
+}
+
+/// Writes every pair of `engine` whose key starts with `prefix`.
+pub fn export<E, W>(
+    engine: &mut E,
+    prefix: &str,
+    writer: &mut EntryWriter<W>,
+    progress: &mut Progress,
+) -> Result<()>
+where
+    E: Engine + ?Sized,
+    W: Write,
+{
+    let mut batches = KeyBatches::new(prefix);
+    while let Some(entries) = batches.next_entries(engine)? {
+        for (key, value) in entries {
+            writer.write(&Entry { key, value })?;
+            progress.inc();
+        }
+    }
+    Ok(())
+}
+
+/// Sets every entry read from `entries` whose key starts with `prefix`.
+pub fn import<E, I>(engine: &mut E, prefix: &str, entries: I, progress: &mut Progress) -> Result<()>
+where
+    E: Engine + ?Sized,
+    I: IntoIterator<Item = Result<Entry>>,
+{
+    for entry in entries {
+        let Entry { key, value } = entry?;
+        if key.starts_with(prefix) {
+            engine.set(key, value)?;
+            progress.inc();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,259 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of recent changes kept so that watchers can resume.
+pub const FEED_CAPACITY: usize = 10_000;
+/// Number of changes a subscriber may fall behind before it is dropped.
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl Change {
+    pub fn key(&self) -> &str {
+        match self {
+            Change::Set { key, .. } | Change::Remove { key } => key,
+        }
+    }
+}
+
+/// A change together with its position in the feed, starting at 1.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub seq: u64,
+    pub change: Change,
+}
+
+/// A point in a particular feed. Sequence numbers restart with every feed, so
+/// a position is only meaningful together with the id of the feed it names.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub feed_id: u64,
+    pub seq: u64,
+}
+
+/// Written as `FEED:SEQ`.
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.feed_id, self.seq)
+    }
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid position {}, expected FEED:SEQ", s);
+        let (feed_id, seq) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Position {
+            feed_id: feed_id.parse().map_err(|_| invalid())?,
+            seq: seq.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+struct Subscriber {
+    prefix: String,
+    sender: SyncSender<ChangeEvent>,
+    lag: Arc<Mutex<Option<Error>>>,
+}
+
+/// The changes published after a subscription was made.
+pub struct Subscription {
+    receiver: Receiver<ChangeEvent>,
+    /// Why the feed dropped the subscriber, once it did.
+    lag: Arc<Mutex<Option<Error>>>,
+    position: Position,
+}
+
+impl Subscription {
+    /// The position of the feed when the subscription was made.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Waits up to `timeout` for the next change, returning `None` if none
+    /// arrived.
+    ///
+    /// Fails with `Error::SequenceUnavailable` once the subscriber fell
+    /// `SUBSCRIBER_BUFFER` changes behind and the feed dropped it. The error
+    /// names the last change delivered, after which a new subscription may
+    /// resume.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<ChangeEvent>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(self
+                .lag
+                .lock()
+                .expect("subscriber lock poisoned")
+                .take()
+                .unwrap_or_else(|| Error::WithMessage("the feed was closed".to_owned()))),
+        }
+    }
+
+    /// The changes that already arrived.
+    pub fn try_iter(&self) -> impl Iterator<Item = ChangeEvent> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+struct FeedState {
+    last_seq: u64,
+    backlog: VecDeque<ChangeEvent>,
+    subscribers: Vec<Subscriber>,
+}
+
+/// Sequenced log of recent mutations, fanned out to subscribers by key prefix.
+pub struct Feed {
+    id: u64,
+    capacity: usize,
+    state: Mutex<FeedState>,
+}
+
+impl Feed {
+    pub fn new(capacity: usize) -> Self {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        Feed {
+            id,
+            capacity,
+            state: Mutex::new(FeedState {
+                last_seq: 0,
+                backlog: VecDeque::with_capacity(capacity),
+                subscribers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Assigns the next sequence number to `change` and delivers it.
+    ///
+    /// Callers publish while still holding the engine, so sequence order is
+    /// the order in which changes were applied. Subscribers too far behind to
+    /// take the change are dropped instead of holding up the caller.
+    pub fn publish(&self, change: Change) -> u64 {
+        let mut state = self.state.lock().expect("feed lock poisoned");
+        state.last_seq += 1;
+        let event = ChangeEvent {
+            seq: state.last_seq,
+            change,
+        };
+
+        if state.backlog.len() == self.capacity {
+            state.backlog.pop_front();
+        }
+        state.backlog.push_back(event.clone());
+        let oldest = state.backlog.front().map_or(event.seq, |event| event.seq);
+
+        state.subscribers.retain(|subscriber| {
+            if !event.change.key().starts_with(&subscriber.prefix) {
+                return true;
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("dropping a subscriber that fell behind");
+                    *subscriber.lag.lock().expect("subscriber lock poisoned") =
+                        Some(Error::SequenceUnavailable {
+                            requested: event.seq - 1,
+                            oldest,
+                            last: event.seq,
+                        });
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+        state.last_seq
+    }
+
+    /// Identifies this feed among the feeds of earlier server runs.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.state.lock().expect("feed lock poisoned").last_seq
+    }
+
+    pub fn position(&self) -> Position {
+        Position {
+            feed_id: self.id,
+            seq: self.last_seq(),
+        }
+    }
+
+    /// Registers a subscriber for keys starting with `prefix`.
+    ///
+    /// Changes after position `after` that are still in the backlog are
+    /// returned first; everything published later arrives on the
+    /// subscription. Fails with `Error::SequenceUnavailable` if `after` names
+    /// the feed of an earlier server run, if changes after it were already
+    /// dropped from the backlog, or if it lies in the future.
+    pub fn subscribe(
+        &self,
+        prefix: String,
+        after: Option<Position>,
+    ) -> Result<(Vec<ChangeEvent>, Subscription)> {
+        let mut state = self.state.lock().expect("feed lock poisoned");
+
+        let backlog = match after {
+            Some(Position {
+                feed_id,
+                seq: after,
+            }) => {
+                let oldest = state
+                    .backlog
+                    .front()
+                    .map_or(state.last_seq + 1, |event| event.seq);
+                if feed_id != self.id || after + 1 < oldest || after > state.last_seq {
+                    return Err(Error::SequenceUnavailable {
+                        requested: after,
+                        oldest,
+                        last: state.last_seq,
+                    });
+                }
+                state
+                    .backlog
+                    .iter()
+                    .filter(|event| event.seq > after && event.change.key().starts_with(&prefix))
+                    .cloned()
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BUFFER);
+        let lag = Arc::new(Mutex::new(None));
+        state.subscribers.push(Subscriber {
+            prefix,
+            sender,
+            lag: Arc::clone(&lag),
+        });
+        let subscription = Subscription {
+            receiver,
+            lag,
+            position: Position {
+                feed_id: self.id,
+                seq: state.last_seq,
+            },
+        };
+        Ok((backlog, subscription))
+    }
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Feed::new(FEED_CAPACITY)
+    }
+}
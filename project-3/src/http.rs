@@ -0,0 +1,394 @@
+//! HTTP/JSON gateway for services that cannot speak the kvs protocol.
+//!
+//! - `GET /health`, which is the only route that needs no
+//!   `Authorization: Bearer <token>` header when the server requires
+//!   authentication.
+//! - `GET /keys?prefix=&start=&end=&limit=` lists pairs in key order; the
+//!   `next` field of the answer is the `start` of the following page.
+//! - `GET`, `PUT` and `DELETE /keys/{key}`, where `PUT` takes
+//!   `{"value": ...}`. Values carry an `ETag`, and writes honour `If-Match`
+//!   and `If-None-Match`, answering 409 when the condition fails. Cluster
+//!   members cannot check a condition and apply the write in one step, so
+//!   they answer conditional writes with 503.
+//!
+//! Requests the user has no access to are answered with 403. Requests
+//! beyond `Limits::max_connections` are answered with 503, and a `PUT` body
+//! that takes longer than `Limits::read_timeout` to arrive with 408.
+
+use crate::limits::{timed_out, DeadlineReader};
+use crate::server::{authentication_required, too_many_clients, Handler};
+use crate::{Access, Engine, Entry, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::thread;
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+const DEFAULT_PAGE: usize = 100;
+const MAX_PAGE: usize = 1000;
+
+#[derive(Serialize)]
+struct Page {
+    entries: Vec<Entry>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PutBody {
+    value: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leader: Option<String>,
+}
+
+/// A reply ready to be sent.
+struct Reply {
+    status: u16,
+    body: Option<String>,
+    etag: Option<String>,
+}
+
+impl Reply {
+    fn json<S: Serialize>(status: u16, body: &S) -> Result<Reply> {
+        Ok(Reply {
+            status,
+            body: Some(serde_json::to_string(body)?),
+            etag: None,
+        })
+    }
+
+    fn empty(status: u16) -> Reply {
+        Reply {
+            status,
+            body: None,
+            etag: None,
+        }
+    }
+
+    fn error(status: u16, message: String) -> Reply {
+        let body = ErrorBody {
+            error: message,
+            leader: None,
+        };
+        Reply::json(status, &body).unwrap_or_else(|_| Reply::empty(status))
+    }
+}
+
+/// Serves every request on its own thread until the server is unblocked.
+///
+/// Requests count against `Limits::max_connections` together with the
+/// server's connections; beyond it they are answered with 503 at once.
+pub(crate) fn serve<T: Engine + 'static>(server: &tiny_http::Server, handler: Handler<T>) {
+    for request in server.incoming_requests() {
+        let handler = handler.clone();
+        let open = match handler.open() {
+            Some(open) => open,
+            None => {
+                warn!("refusing HTTP request: too many clients");
+                if let Err(err) = send(request, error_reply(too_many_clients())) {
+                    error!("cannot refuse HTTP request: {}", err);
+                }
+                continue;
+            }
+        };
+        thread::spawn(move || {
+            let _open = open;
+            let method = request.method().clone();
+            let url = request.url().to_owned();
+            if let Err(err) = respond(&handler, request) {
+                error!("{} {} failed: {}", method, url, err);
+            }
+        });
+    }
+}
+
+fn respond<T: Engine + 'static>(handler: &Handler<T>, mut request: Request) -> Result<()> {
+    let reply = route(handler, &mut request).unwrap_or_else(error_reply);
+    send(request, reply)
+}
+
+fn send(request: Request, reply: Reply) -> Result<()> {
+    let mut response = Response::from_string(reply.body.unwrap_or_default())
+        .with_status_code(StatusCode(reply.status));
+    response.add_header(header("Content-Type", "application/json"));
+    if let Some(etag) = reply.etag {
+        response.add_header(header("ETag", &etag));
+    }
+    if reply.status == 401 {
+        response.add_header(header("WWW-Authenticate", "Bearer"));
+    }
+    Ok(request.respond(response)?)
+}
+
+fn route<T: Engine + 'static>(handler: &Handler<T>, request: &mut Request) -> Result<Reply> {
+    let url = request.url().to_owned();
+    let (path, query) = match url.find('?') {
+        Some(i) => (&url[..i], &url[i + 1..]),
+        None => (url.as_str(), ""),
+    };
+
+    let mut user = None;
+    if path != "/health" && handler.requires_auth() {
+        let peer = request
+            .remote_addr()
+            .map_or_else(|| "unknown peer".to_owned(), |addr| addr.to_string());
+        user = match bearer_token(request) {
+            Some(token) => handler.authenticate(token, &peer)?,
+            None => return Err(authentication_required()),
+        };
+    }
+    let user = user.as_deref();
+
+    match (request.method(), path) {
+        (Method::Get, "/health") => Reply::json(200, &serde_json::json!({ "status": "ok" })),
+        (Method::Get, "/keys") => list(handler, query, user),
+        (_, "/health") | (_, "/keys") => Ok(Reply::error(405, "method not allowed".to_owned())),
+        (method, path) if path.starts_with("/keys/") => {
+            let key = match percent_decode(&path["/keys/".len()..], false) {
+                Some(key) if !key.is_empty() => key,
+                _ => return Ok(Reply::error(400, "invalid key".to_owned())),
+            };
+            let access = match method {
+                Method::Get => Access::Read,
+                _ => Access::Write,
+            };
+            handler.authorize(user, &key, access)?;
+            match method {
+                Method::Get => get(handler, key),
+                Method::Put => put(handler, key, request),
+                Method::Delete => delete(handler, key, request),
+                _ => Ok(Reply::error(405, "method not allowed".to_owned())),
+            }
+        }
+        _ => Ok(Reply::error(404, "not found".to_owned())),
+    }
+}
+
+fn get<T: Engine + 'static>(handler: &Handler<T>, key: String) -> Result<Reply> {
+    let value = handler.get(key.clone())?.ok_or(Error::KeyNotFound)?;
+    let etag = etag(&value);
+    let mut reply = Reply::json(200, &Entry { key, value })?;
+    reply.etag = Some(etag);
+    Ok(reply)
+}
+
+fn put<T: Engine + 'static>(
+    handler: &Handler<T>,
+    key: String,
+    request: &mut Request,
+) -> Result<Reply> {
+    let max = handler.max_request_size();
+    let mut body = String::new();
+    let read = DeadlineReader::new(request.as_reader(), handler.read_timeout())
+        .take(max as u64 + 1)
+        .read_to_string(&mut body);
+    match read {
+        Ok(_) => {}
+        Err(err) if timed_out(&err) => {
+            return Ok(Reply::error(408, "body not received in time".to_owned()))
+        }
+        Err(err) => return Err(err.into()),
+    }
+    if body.len() > max {
+        return Ok(Reply::error(413, format!("body larger than {} bytes", max)));
+    }
+    let value = match serde_json::from_str::<PutBody>(&body) {
+        Ok(body) => body.value,
+        Err(err) => return Ok(Reply::error(400, format!("invalid body: {}", err))),
+    };
+    let etag = etag(&value);
+
+    match Precondition::of(request) {
+        Some(precondition) => {
+            if !handler.write_if(key, Some(value), |current| precondition.holds(current))? {
+                return Ok(Reply::error(409, "precondition failed".to_owned()));
+            }
+        }
+        None => handler.set(key, value)?,
+    }
+
+    let mut reply = Reply::empty(204);
+    reply.etag = Some(etag);
+    Ok(reply)
+}
+
+fn delete<T: Engine + 'static>(
+    handler: &Handler<T>,
+    key: String,
+    request: &Request,
+) -> Result<Reply> {
+    match Precondition::of(request) {
+        Some(precondition) => {
+            if !handler.write_if(key, None, |current| precondition.holds(current))? {
+                return Ok(Reply::error(409, "precondition failed".to_owned()));
+            }
+        }
+        None => handler.remove(key)?,
+    }
+    Ok(Reply::empty(204))
+}
+
+fn list<T: Engine + 'static>(
+    handler: &Handler<T>,
+    query: &str,
+    user: Option<&str>,
+) -> Result<Reply> {
+    let mut prefix = String::new();
+    let mut start = None;
+    let mut end = None;
+    let mut limit = DEFAULT_PAGE;
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = match pair.find('=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, ""),
+        };
+        let value = match percent_decode(value, true) {
+            Some(value) => value,
+            None => return Ok(Reply::error(400, format!("invalid {}", name))),
+        };
+        match name {
+            "prefix" => prefix = value,
+            "start" => start = Some(value),
+            "end" => end = Some(value),
+            "limit" => match value.parse() {
+                Ok(n) if n > 0 => limit = MAX_PAGE.min(n),
+                _ => return Ok(Reply::error(400, "invalid limit".to_owned())),
+            },
+            _ => return Ok(Reply::error(400, format!("unknown parameter {}", name))),
+        }
+    }
+
+    handler.authorize(user, &prefix, Access::Read)?;
+    // Keys with the prefix follow each other, beginning with the prefix.
+    let first = match start {
+        Some(start) if start > prefix => start,
+        _ => prefix.clone(),
+    };
+    let mut keys = handler.engine().keys_from(&first, limit + 1)?;
+    if let Some(i) = keys
+        .iter()
+        .position(|key| !key.starts_with(&prefix) || end.as_ref().is_some_and(|end| key >= end))
+    {
+        keys.truncate(i);
+    }
+
+    let next = keys.get(limit).cloned();
+    let mut entries = Vec::new();
+    for key in keys.into_iter().take(limit) {
+        // Keys removed since they were listed are skipped.
+        if let Some(value) = handler.get(key.clone())? {
+            entries.push(Entry { key, value });
+        }
+    }
+    Reply::json(200, &Page { entries, next })
+}
+
+/// The `If-Match` or `If-None-Match` condition of a write.
+enum Precondition {
+    Match(Vec<String>),
+    NoneMatch(Vec<String>),
+}
+
+impl Precondition {
+    fn of(request: &Request) -> Option<Precondition> {
+        entity_tags(request, "If-Match")
+            .map(Precondition::Match)
+            .or_else(|| entity_tags(request, "If-None-Match").map(Precondition::NoneMatch))
+    }
+
+    fn holds(&self, current: Option<&str>) -> bool {
+        let matches = |tags: &[String]| {
+            current.is_some_and(|value| {
+                let etag = etag(value);
+                tags.iter().any(|tag| tag == "*" || *tag == etag)
+            })
+        };
+        match self {
+            Precondition::Match(tags) => matches(tags),
+            Precondition::NoneMatch(tags) => !matches(tags),
+        }
+    }
+}
+
+/// The entity tags listed in header `name`, compared weakly.
+fn entity_tags(request: &Request, name: &'static str) -> Option<Vec<String>> {
+    let header = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))?;
+    let tags = header
+        .value
+        .as_str()
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/").to_owned())
+        .collect();
+    Some(tags)
+}
+
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))?
+        .value
+        .as_str()
+        .strip_prefix("Bearer ")
+}
+
+/// 128-bit FNV-1a of the value, which stays the same across Rust releases
+/// and is wide enough that two values of one key practically never share it.
+fn etag(value: &str) -> String {
+    let hash = value.bytes().fold(
+        0x6c62_272e_07bb_0142_62b8_2175_6295_c58d,
+        |hash: u128, byte| {
+            (hash ^ u128::from(byte)).wrapping_mul(0x0000_0000_0100_0000_0000_0000_0000_013b)
+        },
+    );
+    format!("\"{:032x}\"", hash)
+}
+
+fn error_reply(err: Error) -> Reply {
+    let status = match &err {
+        Error::KeyNotFound => 404,
+        Error::Auth(_) => 401,
+        Error::ReadOnly | Error::Replication(_) | Error::PermissionDenied(_) => 403,
+        Error::NotLeader(_) | Error::Raft(_) | Error::Limit(_) => 503,
+        _ => 500,
+    };
+    let mut reply = Reply::error(status, format!("{}", err));
+    if let Error::NotLeader(Some(leader)) = err {
+        let body = ErrorBody {
+            error: "not the cluster leader".to_owned(),
+            leader: Some(leader),
+        };
+        reply = Reply::json(status, &body).unwrap_or(reply);
+    }
+    reply
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+/// Decodes `%XX` escapes, and `+` as a space in query strings.
+fn percent_decode(s: &str, query: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b'+' if query => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
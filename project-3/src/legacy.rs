@@ -0,0 +1,130 @@
+//! The responses of the original JSON protocol, which gave `Get`, `Set` and
+//! `Remove` a response type each. JSON connections are still answered in
+//! those shapes, with errors carried as their message like older servers
+//! sent them; every other request is answered with a plain `Response`, as
+//! over the binary protocol.
+
+use crate::{Error, ErrorCode, ErrorResponse, Request, Response, Result};
+use serde::{Deserialize, Serialize};
+
+/// Which response type of the JSON protocol answers a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Get,
+    Set,
+    Remove,
+}
+
+impl Kind {
+    /// The kind of `request`, or `None` if it is answered with a `Response`.
+    pub fn of(request: &Request) -> Option<Kind> {
+        match request {
+            Request::Get { .. } => Some(Kind::Get),
+            Request::Set { .. } => Some(Kind::Set),
+            Request::Remove { .. } => Some(Kind::Remove),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) enum GetResponse {
+    Ok(Option<String>),
+    Err(String),
+}
+
+/// Answer to `Request::Set` and `Request::Remove`, which share its shape.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) enum WriteResponse {
+    Ok(()),
+    Err(String),
+}
+
+/// A response as the JSON protocol shapes it.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum Legacy {
+    Get(GetResponse),
+    Write(WriteResponse),
+    Response(Response),
+}
+
+/// Shapes `response` to a request of `kind` for the JSON protocol.
+pub(crate) fn encode(kind: Option<Kind>, response: Response) -> Result<Legacy> {
+    let legacy = match (kind, response) {
+        (None, response) => Legacy::Response(response),
+        (Some(Kind::Get), Response::Value(value)) => Legacy::Get(GetResponse::Ok(value)),
+        (Some(Kind::Get), Response::Err(err)) => {
+            Legacy::Get(GetResponse::Err(Error::from(err).to_string()))
+        }
+        (Some(Kind::Set), Response::Ok) | (Some(Kind::Remove), Response::Ok) => {
+            Legacy::Write(WriteResponse::Ok(()))
+        }
+        (Some(Kind::Set), Response::Err(err)) | (Some(Kind::Remove), Response::Err(err)) => {
+            Legacy::Write(WriteResponse::Err(Error::from(err).to_string()))
+        }
+        (Some(kind), other) => {
+            return Err(Error::Protocol(format!(
+                "{:?} cannot answer a {:?} request",
+                other, kind
+            )))
+        }
+    };
+    Ok(legacy)
+}
+
+/// Shapes the answer to a request that could not be read. Its error goes
+/// out as a bare message, which every response type of the JSON protocol
+/// decodes.
+pub(crate) fn encode_unread(response: Response) -> Legacy {
+    match response {
+        Response::Err(err) => Legacy::Write(WriteResponse::Err(Error::from(err).to_string())),
+        other => Legacy::Response(other),
+    }
+}
+
+/// Turns a JSON response to a request of `kind` into a `Response`.
+pub(crate) fn decode(kind: Option<Kind>, value: serde_json::Value) -> Result<Response> {
+    let response = match kind {
+        // Requests the server could not read are answered with a message.
+        None => match serde_json::from_value(value.clone()) {
+            Ok(response) => response,
+            Err(err) => match serde_json::from_value(value) {
+                Ok(WriteResponse::Err(message)) => Response::Err(error(message)),
+                _ => return Err(err.into()),
+            },
+        },
+        Some(Kind::Get) => match serde_json::from_value(value)? {
+            GetResponse::Ok(value) => Response::Value(value),
+            GetResponse::Err(message) => Response::Err(error(message)),
+        },
+        Some(Kind::Set) | Some(Kind::Remove) => match serde_json::from_value(value)? {
+            WriteResponse::Ok(()) => Response::Ok,
+            WriteResponse::Err(message) => Response::Err(error(message)),
+        },
+    };
+    Ok(response)
+}
+
+/// The error behind a JSON error message. Only a missing key, which callers
+/// handle on its own, and a refused limit are told apart; anything else
+/// keeps just its message.
+fn error(message: String) -> ErrorResponse {
+    let limit = Error::Limit(String::new()).to_string();
+    if message == Error::KeyNotFound.to_string() {
+        ErrorResponse {
+            code: ErrorCode::KeyNotFound,
+            message,
+        }
+    } else if let Some(message) = message.strip_prefix(&limit) {
+        ErrorResponse {
+            code: ErrorCode::Limit,
+            message: message.to_owned(),
+        }
+    } else {
+        ErrorResponse {
+            code: ErrorCode::WithMessage,
+            message,
+        }
+    }
+}
@@ -1,16 +1,59 @@
-pub use client::Client;
-pub use common::{GetResponse, RemoveResponse, Request, SetResponse};
+pub use acl::{Access, Acl};
+pub use async_client::AsyncClient;
+pub use async_server::AsyncServer;
+pub use auth::Auth;
+pub use backup::{backup, restore};
+pub use client::{Client, Connector, Pipeline, Scan, Watch};
+pub use common::{ErrorCode, ErrorResponse, Request, Response};
+pub use compaction::{CompactionPolicy, CompactionWindow, COMPACTION_THRESHOLD};
 pub use engine::Engine;
-pub use engines::{Sled, Store};
+pub use engines::{fsck, ReadOnly, Sled, Store};
 pub use error::{Error, Result};
-pub use server::Server;
+pub use export::{export, import, read_entries, Entry, EntryWriter, Format, Progress};
+pub use feed::{
+    Change, ChangeEvent, Feed, Position, Subscription, FEED_CAPACITY, SUBSCRIBER_BUFFER,
+};
+pub use limits::Limits;
+pub use manifest::{Manifest, FORMAT_VERSION};
+pub use migrate::{migrate, summarize, MigrationReport, Summary};
+pub use registry::{EngineConfig, Registry};
+pub use server::{Protocol, Server, DRAIN_TIMEOUT};
+pub use sharding::{rebalance, HashRing, RebalanceReport, ShardedClient, DEFAULT_VNODES};
+pub use shutdown::ShutdownHandle;
+pub use stats::{CompactionStats, GenerationStats, Stats};
+pub use stream::Addr;
+pub use tls::{ClientTls, ServerTls};
 
 #[macro_use]
 extern crate log;
 
+mod acl;
+mod async_client;
+mod async_server;
+mod auth;
+mod backup;
 mod client;
 mod common;
+mod compaction;
+mod connection;
 mod engine;
 mod engines;
 mod error;
+mod export;
+mod feed;
+mod http;
+mod legacy;
+mod limits;
+mod manifest;
+mod migrate;
+pub mod protocol;
+pub mod raft;
+mod registry;
+mod replication;
+mod resp;
 mod server;
+mod sharding;
+mod shutdown;
+mod stats;
+mod stream;
+mod tls;
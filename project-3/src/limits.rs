@@ -0,0 +1,151 @@
+use crate::protocol::MAX_FRAME_LEN;
+use crate::stream::Stream;
+use crate::Error;
+use std::{
+    cell::Cell,
+    io::{self, Read},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+/// Limits a server puts on its clients, where `None` means no limit.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Connections served at once; more are answered with `Error::Limit`
+    /// and closed, or just closed while many are being refused already.
+    pub max_connections: Option<usize>,
+    /// How long the rest of a request may take once it started arriving,
+    /// however steadily it trickles in.
+    pub read_timeout: Option<Duration>,
+    /// How long a client may take to accept a response.
+    pub write_timeout: Option<Duration>,
+    /// How long a connection may wait between requests before it is closed.
+    pub idle_timeout: Option<Duration>,
+    /// Largest request accepted, in bytes. Larger ones are refused before
+    /// they are buffered.
+    pub max_request_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: Some(1024),
+            read_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(300)),
+            max_request_size: MAX_FRAME_LEN as usize,
+        }
+    }
+}
+
+/// Reads from a client with the idle timeout while `idle` is raised, which
+/// the server does before waiting for a request, and once the request
+/// started arriving fails with `io::ErrorKind::TimedOut` when the read
+/// timeout has passed since.
+pub(crate) struct TimedReader<'a> {
+    stream: &'a Stream,
+    read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    idle: Rc<Cell<bool>>,
+    deadline: Option<Instant>,
+}
+
+impl<'a> TimedReader<'a> {
+    pub fn new(stream: &'a Stream, limits: &Limits, idle: Rc<Cell<bool>>) -> Self {
+        TimedReader {
+            stream,
+            read_timeout: limits.read_timeout,
+            idle_timeout: limits.idle_timeout,
+            idle,
+            deadline: None,
+        }
+    }
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.idle.get() {
+            if let Some(deadline) = self.deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+                }
+                self.stream.set_read_timeout(Some(left))?;
+            }
+            return (&mut &*self.stream).read(buf);
+        }
+        self.stream.set_read_timeout(self.idle_timeout)?;
+        let n = (&mut &*self.stream).read(buf)?;
+        self.stream.set_read_timeout(self.read_timeout)?;
+        self.deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        self.idle.set(false);
+        Ok(n)
+    }
+}
+
+/// Fails with `io::ErrorKind::TimedOut` once a read ends after `deadline`,
+/// for streams whose socket timeouts cannot be set. A read that never
+/// returns is not cut short.
+pub(crate) struct DeadlineReader<R: Read> {
+    inner: R,
+    deadline: Option<Instant>,
+}
+
+impl<R: Read> DeadlineReader<R> {
+    pub fn new(inner: R, timeout: Option<Duration>) -> Self {
+        DeadlineReader {
+            inner,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+        {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "read timed out"));
+        }
+        Ok(n)
+    }
+}
+
+/// Fails once more bytes are read than are left in `budget`, which the
+/// reader of the requests refills for each of them.
+pub(crate) struct LimitedReader<R: Read> {
+    inner: R,
+    budget: Rc<Cell<usize>>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, budget: Rc<Cell<usize>>) -> Self {
+        LimitedReader { inner, budget }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = self.budget.get().min(buf.len());
+        if max == 0 && !buf.is_empty() {
+            return Err(io::Error::other("request too large"));
+        }
+        let n = self.inner.read(&mut buf[..max])?;
+        self.budget.set(self.budget.get() - n);
+        Ok(n)
+    }
+}
+
+/// Whether `err` is a socket timeout.
+pub(crate) fn timed_out(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+pub(crate) fn too_large(max_request_size: usize) -> Error {
+    Error::Limit(format!("request larger than {} bytes", max_request_size))
+}
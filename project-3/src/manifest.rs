@@ -0,0 +1,146 @@
+use crate::sharding::Fnv1a;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the on-disk layout written by this build.
+pub const FORMAT_VERSION: u32 = 1;
+
+const MANIFEST: &str = "MANIFEST";
+const LEGACY_ENGINE_MARKER: &str = "engine";
+
+/// Metadata describing the contents of a data directory.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub engine: String,
+    pub format_version: u32,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub store_id: String,
+}
+
+impl Manifest {
+    pub fn new(engine: &str) -> Self {
+        Manifest {
+            engine: engine.to_owned(),
+            format_version: FORMAT_VERSION,
+            created_at: unix_now(),
+            store_id: new_store_id(),
+        }
+    }
+
+    /// Reads the manifest of `dir`, falling back to a bare `engine` marker
+    /// left by older releases. Nothing is written; saving the returned
+    /// manifest replaces the marker.
+    ///
+    /// The store id of a legacy directory is derived from its path, so every
+    /// load reports the id that the upgrade will keep.
+    ///
+    /// Returns `Error::IncompatibleFormat` when the data was written by a
+    /// newer format than this build understands.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let manifest_path = dir.join(MANIFEST);
+
+        let manifest = if manifest_path.exists() {
+            let content = std::fs::read(manifest_path)?;
+            serde_json::from_slice::<Manifest>(&content)?
+        } else {
+            match legacy_engine(dir)? {
+                Some(engine) => Manifest::legacy(dir, &engine)?,
+                None => return Ok(None),
+            }
+        };
+
+        manifest.check_compatible()?;
+        Ok(Some(manifest))
+    }
+
+    /// Writes the manifest into `dir`, replacing any legacy `engine` marker.
+    ///
+    /// The file is written under a temporary name and renamed into place, so
+    /// readers never observe a partially written manifest.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", MANIFEST));
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::File::open(&tmp_path)?.sync_all()?;
+        std::fs::rename(tmp_path, dir.join(MANIFEST))?;
+
+        let legacy_path = dir.join(LEGACY_ENGINE_MARKER);
+        if legacy_path.exists() {
+            std::fs::remove_file(legacy_path)?;
+        }
+        Ok(())
+    }
+
+    fn legacy(dir: &Path, engine: &str) -> Result<Self> {
+        let created_at = std::fs::metadata(dir.join(LEGACY_ENGINE_MARKER))?
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+
+        Ok(Manifest {
+            engine: engine.to_owned(),
+            format_version: FORMAT_VERSION,
+            created_at,
+            store_id: legacy_store_id(dir)?,
+        })
+    }
+
+    pub fn check_compatible(&self) -> Result<()> {
+        if self.format_version != FORMAT_VERSION {
+            return Err(Error::IncompatibleFormat {
+                found: self.format_version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn legacy_engine(dir: &Path) -> Result<Option<String>> {
+    let engine_path = dir.join(LEGACY_ENGINE_MARKER);
+
+    if !engine_path.exists() {
+        return Ok(None);
+    }
+
+    // Older releases wrote the engine as clap printed it, such as "Kvs".
+    let engine = std::fs::read_to_string(engine_path)?
+        .trim()
+        .to_ascii_lowercase();
+    if engine.is_empty() {
+        warn!("invalid engine file: empty");
+        return Ok(None);
+    }
+
+    Ok(Some(engine))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+fn new_store_id() -> String {
+    let random = || RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", random(), random())
+}
+
+fn legacy_store_id(dir: &Path) -> Result<String> {
+    let path = dir.canonicalize()?;
+    let path = path.to_string_lossy();
+    let hash = |salt: u8| {
+        let mut fnv = Fnv1a::default();
+        fnv.write(&[salt]);
+        fnv.write(path.as_bytes());
+        fnv.finish()
+    };
+    Ok(format!("{:016x}{:016x}", hash(0), hash(1)))
+}
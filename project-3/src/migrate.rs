@@ -0,0 +1,186 @@
+use crate::engine::KeyBatches;
+use crate::sharding::Fnv1a;
+use crate::{Engine, EngineConfig, Error, Manifest, Registry, Result};
+use std::hash::Hasher;
+use std::path::Path;
+
+/// Number of live keys and a digest of the keyspace, used to verify a migration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub keys: u64,
+    pub checksum: u64,
+}
+
+#[derive(Debug)]
+pub struct MigrationReport {
+    pub from_engine: String,
+    pub to_engine: String,
+    pub summary: Summary,
+}
+
+/// Pairs a migration writes to the target at once.
+const MIGRATE_BATCH: usize = 1024;
+
+/// Copies every live key of the engine recorded in `from` into a fresh `to`
+/// directory opened with `to_engine`.
+///
+/// The copy is built in a sibling of `to` and renamed into place once it
+/// was reopened and compared with the source by key count and checksum, so
+/// a failed migration leaves `to` as it was and can be retried.
+pub fn migrate(
+    registry: &Registry,
+    from: &Path,
+    to: &Path,
+    to_engine: &str,
+) -> Result<MigrationReport> {
+    let from_engine = Manifest::load(from)?
+        .ok_or_else(|| Error::Migration(format!("no manifest in {}", from.display())))?
+        .engine;
+
+    if !registry.has_engine(to_engine) {
+        return Err(Error::UnknownEngine(to_engine.to_owned()));
+    }
+
+    if to.exists() && std::fs::read_dir(to)?.next().is_some() {
+        return Err(Error::Migration(format!(
+            "target directory {} is not empty",
+            to.display()
+        )));
+    }
+    let name = to.file_name().ok_or_else(|| {
+        Error::Migration(format!("target directory {} has no name", to.display()))
+    })?;
+    let staging = to.with_file_name(format!(".{}.migrating", name.to_string_lossy()));
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+
+    let summary = match copy(registry, &from_engine, from, &staging, to_engine) {
+        Ok(summary) => summary,
+        Err(err) => {
+            if let Err(cleanup) = std::fs::remove_dir_all(&staging) {
+                warn!("cannot remove {}: {}", staging.display(), cleanup);
+            }
+            return Err(err);
+        }
+    };
+
+    if to.exists() {
+        std::fs::remove_dir(to)?;
+    }
+    std::fs::rename(&staging, to)?;
+
+    Ok(MigrationReport {
+        from_engine,
+        to_engine: to_engine.to_owned(),
+        summary,
+    })
+}
+
+/// Copies `from` into the empty `to` and verifies the copy, writing its
+/// manifest last.
+fn copy(
+    registry: &Registry,
+    from_engine: &str,
+    from: &Path,
+    to: &Path,
+    to_engine: &str,
+) -> Result<Summary> {
+    let mut source = registry.open(from_engine, &EngineConfig::new(from).read_only(true))?;
+    let mut target = registry.open(to_engine, &EngineConfig::new(to))?;
+
+    let mut copied = Checksum::new();
+    let mut batch = Vec::with_capacity(MIGRATE_BATCH);
+    for_each_entry(&mut source, |key, value| {
+        copied.update(&key, &value);
+        batch.push((key, value));
+        if batch.len() == MIGRATE_BATCH {
+            target.set_many(std::mem::take(&mut batch))?;
+        }
+        Ok(())
+    })?;
+    target.set_many(batch)?;
+    target.sync()?;
+    drop(target);
+
+    let expected = summarize(&mut source)?;
+    if copied.summary() != expected {
+        return Err(Error::Migration(
+            "source changed while it was being migrated".to_owned(),
+        ));
+    }
+
+    let mut target = registry.open(to_engine, &EngineConfig::new(to))?;
+    let actual = summarize(&mut target)?;
+    drop(target);
+
+    if actual != expected {
+        return Err(Error::Migration(format!(
+            "verification failed: expected {} keys (checksum {:016x}), found {} keys (checksum {:016x})",
+            expected.keys, expected.checksum, actual.keys, actual.checksum
+        )));
+    }
+
+    Manifest::new(to_engine).save(to)?;
+    Ok(actual)
+}
+
+/// Counts the live keys of `engine` and digests them in key order.
+pub fn summarize<E: Engine + ?Sized>(engine: &mut E) -> Result<Summary> {
+    let mut checksum = Checksum::new();
+    for_each_entry(engine, |key, value| {
+        checksum.update(&key, &value);
+        Ok(())
+    })?;
+    Ok(checksum.summary())
+}
+
+/// Calls `f` with every live pair of `engine` in key order, reading a
+/// batch of keys at a time.
+fn for_each_entry<E, F>(engine: &mut E, mut f: F) -> Result<()>
+where
+    E: Engine + ?Sized,
+    F: FnMut(String, String) -> Result<()>,
+{
+    let mut batches = KeyBatches::new("");
+    loop {
+        let keys = batches.next_keys(engine)?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        for key in keys {
+            let value = engine.get(key.clone())?.ok_or(Error::KeyNotFound)?;
+            f(key, value)?;
+        }
+    }
+}
+
+struct Checksum {
+    hasher: Fnv1a,
+    keys: u64,
+}
+
+impl Checksum {
+    fn new() -> Self {
+        Checksum {
+            hasher: Fnv1a::default(),
+            keys: 0,
+        }
+    }
+
+    fn update(&mut self, key: &str, value: &str) {
+        for field in &[key, value] {
+            self.hasher.write(&(field.len() as u64).to_le_bytes());
+            self.hasher.write(field.as_bytes());
+        }
+        self.keys += 1;
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            keys: self.keys,
+            checksum: self.hasher.finish(),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Framed binary protocol, negotiated by a handshake on a fresh connection.
+//!
+//! The client opens with `MAGIC` and the lowest and highest versions it
+//! speaks; the server answers with `MAGIC` and the version it picked, or 0
+//! if there is none in common, and then closes. Every later message is a
+//! frame:
+//!
+//! ```text
+//! length: u32 | version: u8 | opcode: u8 | request id: u32 | payload
+//! ```
+//!
+//! All integers are big-endian and `length` counts the bytes after itself.
+//! Payloads are bincode-encoded `Request`s and responses. Responses carry
+//! the id of the request they answer, so a client may send several requests
+//! before reading, and a server may answer them in any order. A frame that cannot
+//! be decoded is answered with an `OP_ERROR` frame and skipped, so the
+//! connection stays usable.
+//!
+//! Every request is answered with one or, for streams, several `Response`s.
+//!
+//! Connections that do not start with `MAGIC` speak the original protocol, a
+//! plain stream of JSON values; see the `legacy` module.
+
+use crate::{Error, Request, Response, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MAGIC: &[u8; 4] = b"KVSB";
+pub const PROTOCOL_VERSION: u8 = 1;
+
+pub const OP_REQUEST: u8 = 1;
+pub const OP_RESPONSE: u8 = 2;
+/// Payload is a UTF-8 message describing a frame that was rejected.
+pub const OP_ERROR: u8 = 3;
+
+const HEADER_LEN: u32 = 6;
+/// Frames above this size are refused instead of buffered.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub version: u8,
+    pub opcode: u8,
+    pub id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode<S: Serialize>(opcode: u8, id: u32, value: &S) -> Result<Frame> {
+        Ok(Frame {
+            version: PROTOCOL_VERSION,
+            opcode,
+            id,
+            payload: bincode::serialize(value)?,
+        })
+    }
+
+    pub fn decode<D: DeserializeOwned>(&self) -> Result<D> {
+        Ok(bincode::deserialize(&self.payload)?)
+    }
+
+    /// Reads the next frame, or `None` if the connection was closed between frames.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Option<Frame>> {
+        let length = match read_length(reader)? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+
+        let mut header = [0; HEADER_LEN as usize];
+        let mut payload = vec![0; payload_len(length)?];
+        reader.read_exact(&mut header)?;
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Frame::from_parts(header, payload)))
+    }
+
+    /// Reads the next frame like `read`, but skips a payload longer than
+    /// `max` bytes without buffering it and returns the frame's id and
+    /// payload length instead.
+    pub(crate) fn read_limited<R: Read>(
+        reader: &mut R,
+        max: usize,
+    ) -> Result<Option<std::result::Result<Frame, (u32, usize)>>> {
+        let length = match read_length(reader)? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header)?;
+        let len = payload_len(length)?;
+        if len > max {
+            io::copy(&mut reader.by_ref().take(len as u64), &mut io::sink())?;
+            let id = Frame::from_parts(header, Vec::new()).id;
+            return Ok(Some(Err((id, len))));
+        }
+
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Ok(Frame::from_parts(header, payload))))
+    }
+
+    /// Like `read`, for async streams.
+    pub async fn read_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Frame>> {
+        let mut length = [0; 4];
+        match reader.read_exact(&mut length).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut header = [0; HEADER_LEN as usize];
+        let mut payload = vec![0; payload_len(length)?];
+        reader.read_exact(&mut header).await?;
+        reader.read_exact(&mut payload).await?;
+        Ok(Some(Frame::from_parts(header, payload)))
+    }
+
+    /// Like `read_limited`, for async streams.
+    pub(crate) async fn read_limited_async<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        max: usize,
+    ) -> Result<Option<std::result::Result<Frame, (u32, usize)>>> {
+        let mut length = [0; 4];
+        match reader.read_exact(&mut length).await {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut header = [0; HEADER_LEN as usize];
+        reader.read_exact(&mut header).await?;
+        let len = payload_len(length)?;
+        if len > max {
+            let mut skipped = reader.take(len as u64);
+            tokio::io::copy(&mut skipped, &mut tokio::io::sink()).await?;
+            let id = Frame::from_parts(header, Vec::new()).id;
+            return Ok(Some(Err((id, len))));
+        }
+
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        Ok(Some(Ok(Frame::from_parts(header, payload))))
+    }
+
+    /// Writes the frame without flushing.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.header())?;
+        writer.write_all(&self.payload)?;
+        Ok(())
+    }
+
+    /// Like `write`, for async streams.
+    pub async fn write_async<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.header()).await?;
+        writer.write_all(&self.payload).await?;
+        Ok(())
+    }
+
+    /// The length prefix and header.
+    fn header(&self) -> [u8; 10] {
+        let length = (HEADER_LEN + self.payload.len() as u32).to_be_bytes();
+        let id = self.id.to_be_bytes();
+        [
+            length[0],
+            length[1],
+            length[2],
+            length[3],
+            self.version,
+            self.opcode,
+            id[0],
+            id[1],
+            id[2],
+            id[3],
+        ]
+    }
+
+    fn from_parts(header: [u8; HEADER_LEN as usize], payload: Vec<u8>) -> Frame {
+        Frame {
+            version: header[0],
+            opcode: header[1],
+            id: u32::from_be_bytes([header[2], header[3], header[4], header[5]]),
+            payload,
+        }
+    }
+}
+
+/// Writes `request` as frame `id` and flushes it.
+pub(crate) fn send_request<W: Write>(writer: &mut W, id: u32, request: &Request) -> Result<()> {
+    Frame::encode(OP_REQUEST, id, request)?.write(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next response, failing if the server closed the connection
+/// or rejected the request.
+pub(crate) fn read_response<R: Read>(reader: &mut R) -> Result<Response> {
+    let frame = Frame::read(reader)?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection")
+    })?;
+    match frame.opcode {
+        OP_RESPONSE => frame.decode(),
+        OP_ERROR => Err(Error::Protocol(
+            String::from_utf8_lossy(&frame.payload).into_owned(),
+        )),
+        opcode => Err(Error::Protocol(format!("unexpected opcode {}", opcode))),
+    }
+}
+
+/// Reads the length a frame starts with, or `None` at the end of the stream.
+fn read_length<R: Read>(reader: &mut R) -> Result<Option<[u8; 4]>> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => Ok(Some(length)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn payload_len(length: [u8; 4]) -> Result<usize> {
+    let length = u32::from_be_bytes(length);
+    if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&length) {
+        return Err(Error::Protocol(format!("invalid frame length {}", length)));
+    }
+    Ok((length - HEADER_LEN) as usize)
+}
+
+/// Offers every version this crate speaks and returns the one the server picked.
+pub fn connect<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u8> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[PROTOCOL_VERSION, PROTOCOL_VERSION])?;
+    writer.flush()?;
+
+    let mut answer = [0; 5];
+    reader.read_exact(&mut answer)?;
+    agreed_version(answer)
+}
+
+/// Like `connect`, for async streams.
+pub async fn connect_async<R, W>(reader: &mut R, writer: &mut W) -> Result<u8>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(MAGIC).await?;
+    writer
+        .write_all(&[PROTOCOL_VERSION, PROTOCOL_VERSION])
+        .await?;
+    writer.flush().await?;
+
+    let mut answer = [0; 5];
+    reader.read_exact(&mut answer).await?;
+    agreed_version(answer)
+}
+
+fn agreed_version(answer: [u8; 5]) -> Result<u8> {
+    if &answer[..4] != MAGIC {
+        return Err(Error::Protocol(
+            "server did not answer the handshake".to_owned(),
+        ));
+    }
+    match answer[4] {
+        0 => Err(Error::Protocol(
+            "server speaks no common protocol version".to_owned(),
+        )),
+        version => Ok(version),
+    }
+}
+
+/// Answers a client handshake, returning the agreed version.
+///
+/// The caller has already seen that the connection starts with `MAGIC`.
+pub fn accept<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<Option<u8>> {
+    let mut offer = [0; 6];
+    reader.read_exact(&mut offer)?;
+    let version = choose_version(offer)?;
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[version.unwrap_or(0)])?;
+    writer.flush()?;
+    Ok(version)
+}
+
+/// Like `accept`, for async streams.
+pub async fn accept_async<R, W>(reader: &mut R, writer: &mut W) -> Result<Option<u8>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut offer = [0; 6];
+    reader.read_exact(&mut offer).await?;
+    let version = choose_version(offer)?;
+
+    writer.write_all(MAGIC).await?;
+    writer.write_all(&[version.unwrap_or(0)]).await?;
+    writer.flush().await?;
+    Ok(version)
+}
+
+fn choose_version(offer: [u8; 6]) -> Result<Option<u8>> {
+    if &offer[..4] != MAGIC {
+        return Err(Error::Protocol("invalid handshake".to_owned()));
+    }
+
+    let (min, max) = (offer[4], offer[5]);
+    if (min..=max).contains(&PROTOCOL_VERSION) {
+        Ok(Some(PROTOCOL_VERSION))
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// An operation replicated through the Raft log.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Appended by every new leader to commit entries from earlier terms.
+    Noop,
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Replaces the cluster membership; takes effect as soon as it is appended.
+    Members(BTreeSet<String>),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub command: Command,
+}
+
+/// Describes the last entry covered by a snapshot.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMeta {
+    pub index: u64,
+    pub term: u64,
+    pub members: BTreeSet<String>,
+}
+
+/// Messages exchanged between cluster members.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum RaftMessage {
+    RequestVote {
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    AppendEntries {
+        term: u64,
+        leader: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    /// One chunk of the snapshot `meta` describes: the pairs following the
+    /// first `offset` ones. The follower installs it after the chunk marked
+    /// `done`.
+    InstallSnapshot {
+        term: u64,
+        leader: String,
+        meta: SnapshotMeta,
+        offset: u64,
+        pairs: Vec<(String, String)>,
+        done: bool,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub enum RaftResponse {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// On success `match_index` is the last index known to match the leader;
+    /// on failure it is a hint where the leader should retry.
+    Append {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    Snapshot {
+        term: u64,
+        success: bool,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A member's view of the cluster, as reported by `kvs-client cluster status`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ClusterStatus {
+    pub id: String,
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub members: Vec<String>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub snapshot_index: u64,
+}
@@ -0,0 +1,1099 @@
+//! Raft consensus in front of an `Engine`.
+//!
+//! Every write is appended to a replicated log and applied to the engine once
+//! a majority of the members stored it. Reads are served by the leader after
+//! it confirmed its leadership with a majority. Snapshots are engine backups,
+//! which replace the log prefix they cover and bring lagging members up to date.
+
+use crate::engine::KeyBatches;
+use crate::{Change, Engine, Error, Feed, Result};
+use peer::Peer;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use storage::Storage;
+
+pub use message::{ClusterStatus, Command, Entry, RaftMessage, RaftResponse, Role, SnapshotMeta};
+
+mod message;
+mod peer;
+mod storage;
+
+/// Opens a snapshot directory written by `Engine::backup` for reading.
+pub type SnapshotOpener = Arc<dyn Fn(&Path) -> Result<Box<dyn Engine>> + Send + Sync>;
+
+/// Applied entries kept in the log before a snapshot replaces them.
+pub const SNAPSHOT_THRESHOLD: u64 = 10_000;
+/// Bytes of keys and values sent to a lagging member per snapshot message.
+pub const SNAPSHOT_CHUNK: usize = 1024 * 1024;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// Election timeouts are drawn from this range of milliseconds.
+const ELECTION_TIMEOUT: (u64, u64) = (300, 600);
+const TICK: Duration = Duration::from_millis(10);
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+const SNAPSHOT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_APPEND_ENTRIES: usize = 256;
+/// How long a client request waits for the cluster.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct RaftConfig {
+    /// The address other members and clients reach this server at.
+    pub id: String,
+    pub dir: PathBuf,
+    /// Members to start with when `dir` holds no Raft state yet; empty for a
+    /// server that waits to be added to an existing cluster.
+    pub members: Vec<String>,
+    pub snapshot_threshold: u64,
+    pub snapshot_chunk: usize,
+    opener: SnapshotOpener,
+    /// Presented to the other members when they require authentication.
+    pub(crate) token: Option<String>,
+}
+
+impl RaftConfig {
+    pub fn new<F>(id: impl Into<String>, dir: impl Into<PathBuf>, opener: F) -> Self
+    where
+        F: Fn(&Path) -> Result<Box<dyn Engine>> + Send + Sync + 'static,
+    {
+        RaftConfig {
+            id: id.into(),
+            dir: dir.into(),
+            members: Vec::new(),
+            snapshot_threshold: SNAPSHOT_THRESHOLD,
+            snapshot_chunk: SNAPSHOT_CHUNK,
+            opener: Arc::new(opener),
+            token: None,
+        }
+    }
+
+    pub fn members(mut self, members: Vec<String>) -> Self {
+        self.members = members;
+        self
+    }
+
+    pub fn snapshot_threshold(mut self, entries: u64) -> Self {
+        self.snapshot_threshold = entries;
+        self
+    }
+
+    pub fn snapshot_chunk(mut self, bytes: usize) -> Self {
+        self.snapshot_chunk = bytes;
+        self
+    }
+}
+
+/// A snapshot being sent to a lagging member, one chunk at a time.
+struct Transfer {
+    meta: SnapshotMeta,
+    engine: Box<dyn Engine>,
+    batches: KeyBatches,
+    /// What is left of the batch read last.
+    keys: std::vec::IntoIter<String>,
+    sent: u64,
+}
+
+impl Transfer {
+    /// The next key of the snapshot, `None` once all were sent.
+    fn next_key(&mut self) -> Result<Option<String>> {
+        self.fill()?;
+        Ok(self.keys.next())
+    }
+
+    /// Whether every key of the snapshot was sent.
+    fn done(&mut self) -> Result<bool> {
+        self.fill()?;
+        Ok(self.keys.as_slice().is_empty())
+    }
+
+    /// Reads the next batch once the last one is used up.
+    fn fill(&mut self) -> Result<()> {
+        if self.keys.as_slice().is_empty() {
+            self.keys = self.batches.next_keys(&mut self.engine)?.into_iter();
+        }
+        Ok(())
+    }
+}
+
+/// What the leader knows about another member.
+struct Progress {
+    next_index: u64,
+    match_index: u64,
+    last_ack: Option<Instant>,
+}
+
+struct State {
+    storage: Storage,
+    role: Role,
+    leader: Option<String>,
+    commit_index: u64,
+    last_applied: u64,
+    election_deadline: Instant,
+    leader_contact: Option<Instant>,
+    votes: BTreeSet<String>,
+    progress: HashMap<String, Progress>,
+    /// Index of the entry the current leader appended when elected.
+    term_start: u64,
+    /// Entries proposed here, with whether they took effect once applied.
+    outcomes: HashMap<u64, Option<bool>>,
+    rng: u64,
+}
+
+impl State {
+    fn reset_election_deadline(&mut self) {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        let (min, max) = ELECTION_TIMEOUT;
+        let timeout = min + self.rng % (max - min);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+}
+
+/// One member of a Raft cluster, sharing its engine and change feed with the server.
+///
+/// Locks are always taken in the order engine, snapshot, state.
+pub struct Raft<T: Engine> {
+    id: String,
+    engine: Arc<Mutex<T>>,
+    feed: Arc<Feed>,
+    opener: SnapshotOpener,
+    snapshot_threshold: u64,
+    snapshot_chunk: usize,
+    token: Option<String>,
+    state: Mutex<State>,
+    changed: Condvar,
+    peers: Mutex<HashMap<String, Arc<Peer>>>,
+    snapshots: Mutex<()>,
+}
+
+impl<T: Engine + 'static> Raft<T> {
+    /// Loads the Raft state in `config.dir` and starts the election timer and
+    /// the thread applying committed entries.
+    pub(crate) fn start(
+        config: RaftConfig,
+        engine: Arc<Mutex<T>>,
+        feed: Arc<Feed>,
+    ) -> Result<Arc<Self>> {
+        let storage = Storage::open(&config.dir, config.members.into_iter().collect())?;
+
+        // The engine holds at least everything up to the snapshot. Entries
+        // after it are applied again, which is harmless because each one
+        // overwrites or removes a whole key.
+        let applied = storage.snapshot().index;
+
+        let mut hasher = DefaultHasher::new();
+        config.id.hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+
+        let mut state = State {
+            storage,
+            role: Role::Follower,
+            leader: None,
+            commit_index: applied,
+            last_applied: applied,
+            election_deadline: Instant::now(),
+            leader_contact: None,
+            votes: BTreeSet::new(),
+            progress: HashMap::new(),
+            term_start: 0,
+            outcomes: HashMap::new(),
+            rng: hasher.finish() | 1,
+        };
+        state.reset_election_deadline();
+
+        let raft = Arc::new(Raft {
+            id: config.id,
+            engine,
+            feed,
+            opener: config.opener,
+            snapshot_threshold: config.snapshot_threshold,
+            snapshot_chunk: config.snapshot_chunk,
+            token: config.token,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            peers: Mutex::new(HashMap::new()),
+            snapshots: Mutex::new(()),
+        });
+
+        let ticker = Arc::clone(&raft);
+        thread::spawn(move || ticker.tick());
+        let applier = Arc::clone(&raft);
+        thread::spawn(move || applier.apply());
+
+        Ok(raft)
+    }
+
+    /// Answers a message from another member.
+    pub(crate) fn handle(self: &Arc<Self>, message: RaftMessage) -> RaftResponse {
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => self.request_vote(term, candidate, last_log_index, last_log_term),
+            RaftMessage::AppendEntries {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.append_entries(
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            ),
+            RaftMessage::InstallSnapshot {
+                term,
+                leader,
+                meta,
+                offset,
+                pairs,
+                done,
+            } => self.install_snapshot(term, leader, meta, offset, pairs, done),
+        }
+    }
+
+    /// Replicates `command` and waits until it is applied here. Removing a
+    /// key that is missing by then fails with `KeyNotFound`.
+    pub(crate) fn propose(self: &Arc<Self>, command: Command) -> Result<()> {
+        let (index, term) = {
+            let mut state = self.lock();
+            let (index, term) = self.append_command(&mut state, command)?;
+            state.outcomes.insert(index, None);
+            (index, term)
+        };
+        let result = self.wait_applied(index, term);
+        let outcome = self.lock().outcomes.remove(&index).flatten();
+        match result {
+            Ok(()) if outcome == Some(false) => Err(Error::KeyNotFound),
+            result => result,
+        }
+    }
+
+    /// Reads `key` on the leader once every write committed so far is applied.
+    pub(crate) fn get(&self, key: String) -> Result<Option<String>> {
+        self.confirm_leadership()?;
+        self.engine.lock().expect("engine lock poisoned").get(key)
+    }
+
+    pub(crate) fn add_member(self: &Arc<Self>, addr: String) -> Result<()> {
+        self.change_members(|members| {
+            if members.insert(addr.clone()) {
+                Ok(())
+            } else {
+                Err(Error::Raft(format!("{} is already a member", addr)))
+            }
+        })
+    }
+
+    pub(crate) fn remove_member(self: &Arc<Self>, addr: String) -> Result<()> {
+        self.change_members(|members| {
+            if !members.remove(&addr) {
+                return Err(Error::Raft(format!("{} is not a member", addr)));
+            }
+            if members.is_empty() {
+                return Err(Error::Raft("cannot remove the last member".to_owned()));
+            }
+            Ok(())
+        })
+    }
+
+    pub(crate) fn status(&self) -> ClusterStatus {
+        let state = self.lock();
+        ClusterStatus {
+            id: self.id.clone(),
+            role: state.role,
+            term: state.storage.term(),
+            leader: state.leader.clone(),
+            members: state.storage.members().into_iter().collect(),
+            commit_index: state.commit_index,
+            last_applied: state.last_applied,
+            snapshot_index: state.storage.snapshot().index,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("raft lock poisoned")
+    }
+
+    fn peer(&self, addr: &str) -> Arc<Peer> {
+        let mut peers = self.peers.lock().expect("peers lock poisoned");
+        Arc::clone(
+            peers
+                .entry(addr.to_owned())
+                .or_insert_with(|| Arc::new(Peer::new(addr, self.token.clone()))),
+        )
+    }
+
+    fn tick(self: Arc<Self>) {
+        loop {
+            thread::sleep(TICK);
+            let mut state = self.lock();
+            if state.role == Role::Leader {
+                self.spawn_replicators(&mut state);
+            } else if Instant::now() >= state.election_deadline {
+                self.start_election(&mut state);
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>, state: &mut State) {
+        state.reset_election_deadline();
+        let members = state.storage.members();
+        if !members.contains(&self.id) {
+            return;
+        }
+
+        let term = state.storage.term() + 1;
+        if let Err(err) = state.storage.set_hard_state(term, Some(self.id.clone())) {
+            error!("cannot start election: {}", err);
+            return;
+        }
+        debug!("starting election for term {}", term);
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.leader_contact = None;
+        state.progress.clear();
+        state.votes = BTreeSet::new();
+        state.votes.insert(self.id.clone());
+
+        if has_quorum(&members, |member| state.votes.contains(member)) {
+            self.become_leader(state);
+            return;
+        }
+
+        let last_log_index = state.storage.last_index();
+        let last_log_term = state.storage.last_term();
+        for member in members.into_iter().filter(|member| *member != self.id) {
+            let raft = Arc::clone(self);
+            let peer = self.peer(&member);
+            let message = RaftMessage::RequestVote {
+                term,
+                candidate: self.id.clone(),
+                last_log_index,
+                last_log_term,
+            };
+
+            thread::spawn(move || {
+                if let Ok(RaftResponse::Vote {
+                    term: peer_term,
+                    granted,
+                }) = peer.call(message, RPC_TIMEOUT)
+                {
+                    let mut state = raft.lock();
+                    raft.observe_term(&mut state, peer_term);
+                    if granted && state.role == Role::Candidate && state.storage.term() == term {
+                        state.votes.insert(member);
+                        let members = state.storage.members();
+                        if has_quorum(&members, |member| state.votes.contains(member)) {
+                            raft.become_leader(&mut state);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    fn become_leader(self: &Arc<Self>, state: &mut State) {
+        let term = state.storage.term();
+        let index = state.storage.last_index() + 1;
+        let noop = Entry {
+            index,
+            term,
+            command: Command::Noop,
+        };
+        if let Err(err) = state.storage.append(vec![noop]) {
+            error!("cannot take leadership: {}", err);
+            state.role = Role::Follower;
+            return;
+        }
+
+        info!("elected leader for term {}", term);
+        state.role = Role::Leader;
+        state.leader = Some(self.id.clone());
+        state.term_start = index;
+        state.progress.clear();
+        self.spawn_replicators(state);
+        self.advance_commit(state);
+        self.changed.notify_all();
+    }
+
+    /// Steps down if `term` is newer than ours; returns whether it was.
+    fn observe_term(&self, state: &mut State, term: u64) -> bool {
+        if term <= state.storage.term() {
+            return false;
+        }
+        if let Err(err) = state.storage.set_hard_state(term, None) {
+            error!("cannot save term {}: {}", term, err);
+        }
+        if state.role != Role::Follower {
+            info!("stepping down in term {}", term);
+        }
+        state.role = Role::Follower;
+        state.leader = None;
+        state.progress.clear();
+        self.changed.notify_all();
+        true
+    }
+
+    fn follow(&self, state: &mut State, leader: String) {
+        if state.leader.as_ref() != Some(&leader) {
+            info!("following {} in term {}", leader, state.storage.term());
+        }
+        state.role = Role::Follower;
+        state.leader = Some(leader);
+        state.leader_contact = Some(Instant::now());
+        state.progress.clear();
+        state.reset_election_deadline();
+    }
+
+    /// Starts a replication thread for every member that lacks one.
+    fn spawn_replicators(self: &Arc<Self>, state: &mut State) {
+        let term = state.storage.term();
+        let next_index = state.storage.last_index() + 1;
+
+        for member in state.storage.members() {
+            if member == self.id || state.progress.contains_key(&member) {
+                continue;
+            }
+            state.progress.insert(
+                member.clone(),
+                Progress {
+                    next_index,
+                    match_index: 0,
+                    last_ack: None,
+                },
+            );
+            let raft = Arc::clone(self);
+            thread::spawn(move || raft.replicate(member, term));
+        }
+    }
+
+    /// Sends entries, snapshots and heartbeats to `member` while this server
+    /// leads in `term`.
+    fn replicate(self: Arc<Self>, member: String, term: u64) {
+        let peer = self.peer(&member);
+        let mut transfer = None;
+
+        loop {
+            let message = {
+                let mut state = self.lock();
+                if state.role != Role::Leader || state.storage.term() != term {
+                    return;
+                }
+                let (next_index, match_index) = match state.progress.get(&member) {
+                    Some(progress) => (progress.next_index, progress.match_index),
+                    None => return,
+                };
+                if !state.storage.members().contains(&member)
+                    && match_index >= state.storage.members_index()
+                {
+                    // The member has learned that it was removed.
+                    state.progress.remove(&member);
+                    return;
+                }
+
+                if next_index <= state.storage.snapshot().index {
+                    None
+                } else {
+                    let prev_log_index = next_index - 1;
+                    Some(RaftMessage::AppendEntries {
+                        term,
+                        leader: self.id.clone(),
+                        prev_log_index,
+                        prev_log_term: state.storage.term_at(prev_log_index).unwrap_or(0),
+                        entries: state.storage.entries_from(
+                            next_index,
+                            u64::MAX,
+                            MAX_APPEND_ENTRIES,
+                        ),
+                        leader_commit: state.commit_index,
+                    })
+                }
+            };
+
+            let (message, timeout) = match message {
+                Some(message) => (message, RPC_TIMEOUT),
+                None => match self.snapshot_chunk(term, &mut transfer) {
+                    Ok(message) => (message, SNAPSHOT_RPC_TIMEOUT),
+                    Err(err) => {
+                        error!("cannot read snapshot for {}: {}", member, err);
+                        transfer = None;
+                        thread::sleep(HEARTBEAT_INTERVAL);
+                        continue;
+                    }
+                },
+            };
+            let installed = match &message {
+                RaftMessage::InstallSnapshot {
+                    meta, done: true, ..
+                } => Some(meta.index),
+                _ => None,
+            };
+
+            let response = peer.call(message, timeout);
+            if installed.is_some()
+                || !matches!(response, Ok(RaftResponse::Snapshot { success: true, .. }))
+            {
+                // Any failure starts the transfer over from the first chunk.
+                transfer = None;
+            }
+
+            let mut state = self.lock();
+            let more = match response {
+                Ok(RaftResponse::Append {
+                    term: peer_term,
+                    success,
+                    match_index,
+                }) => {
+                    if self.observe_term(&mut state, peer_term) {
+                        return;
+                    }
+                    let last_index = state.storage.last_index();
+                    let more = match state.progress.get_mut(&member) {
+                        Some(progress) => {
+                            progress.last_ack = Some(Instant::now());
+                            if success {
+                                progress.match_index = progress.match_index.max(match_index);
+                                progress.next_index = progress.match_index + 1;
+                            } else {
+                                progress.next_index =
+                                    (progress.next_index - 1).min(match_index + 1).max(1);
+                            }
+                            progress.next_index <= last_index
+                        }
+                        None => return,
+                    };
+                    if success {
+                        self.advance_commit(&mut state);
+                    }
+                    more
+                }
+                Ok(RaftResponse::Snapshot {
+                    term: peer_term,
+                    success,
+                }) => {
+                    if self.observe_term(&mut state, peer_term) {
+                        return;
+                    }
+                    if let (Some(index), Some(progress), true) =
+                        (installed, state.progress.get_mut(&member), success)
+                    {
+                        progress.last_ack = Some(Instant::now());
+                        progress.match_index = progress.match_index.max(index);
+                        progress.next_index = progress.match_index + 1;
+                    }
+                    success
+                }
+                Ok(RaftResponse::Vote { .. }) => false,
+                Err(err) => {
+                    debug!("cannot reach {}: {}", member, err);
+                    false
+                }
+            };
+
+            if !more {
+                let _ = self
+                    .changed
+                    .wait_timeout(state, HEARTBEAT_INTERVAL)
+                    .expect("raft lock poisoned");
+            }
+        }
+    }
+
+    /// The next chunk of the latest snapshot, which `transfer` opens unless
+    /// it is already sending it.
+    fn snapshot_chunk(&self, term: u64, transfer: &mut Option<Transfer>) -> Result<RaftMessage> {
+        let latest = self.lock().storage.snapshot().index;
+        if transfer
+            .as_ref()
+            .is_none_or(|transfer| transfer.meta.index != latest)
+        {
+            let _snapshots = self.snapshots.lock().expect("snapshot lock poisoned");
+            let dir = self.lock().storage.snapshot_dir();
+            let meta = storage::snapshot_meta(&dir)?;
+            let engine = (self.opener)(&dir)?;
+            *transfer = Some(Transfer {
+                meta,
+                engine,
+                batches: KeyBatches::new(""),
+                keys: Vec::new().into_iter(),
+                sent: 0,
+            });
+        }
+        let transfer = transfer.as_mut().expect("snapshot transfer opened");
+
+        let mut pairs = Vec::new();
+        let mut bytes = 0;
+        while bytes < self.snapshot_chunk {
+            let key = match transfer.next_key()? {
+                Some(key) => key,
+                None => break,
+            };
+            if let Some(value) = transfer.engine.get(key.clone())? {
+                bytes += key.len() + value.len();
+                pairs.push((key, value));
+            }
+        }
+
+        let offset = transfer.sent;
+        transfer.sent += pairs.len() as u64;
+        Ok(RaftMessage::InstallSnapshot {
+            term,
+            leader: self.id.clone(),
+            meta: transfer.meta.clone(),
+            offset,
+            pairs,
+            done: transfer.done()?,
+        })
+    }
+
+    /// Commits the newest entry of the current term stored by a majority.
+    fn advance_commit(&self, state: &mut State) {
+        if state.role != Role::Leader {
+            return;
+        }
+        let term = state.storage.term();
+        let members = state.storage.members();
+
+        let mut index = state.storage.last_index();
+        while index > state.commit_index && state.storage.term_at(index) == Some(term) {
+            let stored = has_quorum(&members, |member| {
+                *member == self.id
+                    || state
+                        .progress
+                        .get(member)
+                        .is_some_and(|progress| progress.match_index >= index)
+            });
+            if stored {
+                state.commit_index = index;
+                self.changed.notify_all();
+                break;
+            }
+            index -= 1;
+        }
+
+        if !members.contains(&self.id) && state.commit_index >= state.storage.members_index() {
+            info!("removed from the cluster, stepping down");
+            state.role = Role::Follower;
+            state.leader = None;
+            state.progress.clear();
+            self.changed.notify_all();
+        }
+    }
+
+    fn append_command(self: &Arc<Self>, state: &mut State, command: Command) -> Result<(u64, u64)> {
+        if state.role != Role::Leader {
+            return Err(Error::NotLeader(state.leader.clone()));
+        }
+
+        let term = state.storage.term();
+        let index = state.storage.last_index() + 1;
+        state.storage.append(vec![Entry {
+            index,
+            term,
+            command,
+        }])?;
+
+        self.spawn_replicators(state);
+        self.advance_commit(state);
+        self.changed.notify_all();
+        Ok((index, term))
+    }
+
+    fn wait_applied(&self, index: u64, term: u64) -> Result<()> {
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut state = self.lock();
+
+        loop {
+            if state.last_applied >= index {
+                return match state.storage.term_at(index) {
+                    Some(entry_term) if entry_term != term => Err(Error::Raft(
+                        "the request was overwritten by another leader".to_owned(),
+                    )),
+                    _ => Ok(()),
+                };
+            }
+            if state.commit_index < index
+                && (state.role != Role::Leader || state.storage.term() != term)
+            {
+                return Err(Error::NotLeader(state.leader.clone()));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Raft("timed out waiting for a majority".to_owned()));
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .expect("raft lock poisoned")
+                .0;
+        }
+    }
+
+    /// Waits until a majority acknowledged this leader recently and every
+    /// entry committed at that point is applied.
+    fn confirm_leadership(&self) -> Result<()> {
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let lease = Duration::from_millis(ELECTION_TIMEOUT.0);
+        let mut read_index = None;
+        let mut state = self.lock();
+
+        loop {
+            if state.role != Role::Leader {
+                return Err(Error::NotLeader(state.leader.clone()));
+            }
+
+            if read_index.is_none() && state.commit_index >= state.term_start {
+                let members = state.storage.members();
+                let confirmed = has_quorum(&members, |member| {
+                    *member == self.id
+                        || state
+                            .progress
+                            .get(member)
+                            .and_then(|progress| progress.last_ack)
+                            .is_some_and(|ack| ack.elapsed() < lease)
+                });
+                if confirmed {
+                    read_index = Some(state.commit_index);
+                }
+            }
+            if let Some(index) = read_index {
+                if state.last_applied >= index {
+                    return Ok(());
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Raft("leadership could not be confirmed".to_owned()));
+            }
+            state = self
+                .changed
+                .wait_timeout(state, (deadline - now).min(HEARTBEAT_INTERVAL))
+                .expect("raft lock poisoned")
+                .0;
+        }
+    }
+
+    /// Appends a new membership once the previous change is committed.
+    fn change_members<F>(self: &Arc<Self>, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut BTreeSet<String>) -> Result<()>,
+    {
+        let (index, term) = {
+            let mut state = self.lock();
+            if state.role != Role::Leader {
+                return Err(Error::NotLeader(state.leader.clone()));
+            }
+            if state.commit_index < state.term_start
+                || state.storage.members_index() > state.commit_index
+            {
+                return Err(Error::Raft(
+                    "another membership change is in progress".to_owned(),
+                ));
+            }
+
+            let mut members = state.storage.members();
+            change(&mut members)?;
+            info!("changing members to {:?}", members);
+            self.append_command(&mut state, Command::Members(members))?
+        };
+        self.wait_applied(index, term)
+    }
+
+    fn request_vote(
+        &self,
+        term: u64,
+        candidate: String,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) -> RaftResponse {
+        let mut state = self.lock();
+
+        let leader_alive = state
+            .leader_contact
+            .is_some_and(|contact| contact.elapsed() < Duration::from_millis(ELECTION_TIMEOUT.0));
+        if leader_alive && term > state.storage.term() {
+            // Most likely a removed member that has not heard of its removal.
+            return RaftResponse::Vote {
+                term: state.storage.term(),
+                granted: false,
+            };
+        }
+
+        self.observe_term(&mut state, term);
+        let current = state.storage.term();
+        let up_to_date = (last_log_term, last_log_index)
+            >= (state.storage.last_term(), state.storage.last_index());
+        let free = state
+            .storage
+            .voted_for()
+            .is_none_or(|voted_for| voted_for == candidate);
+
+        let mut granted = term == current && up_to_date && free;
+        if granted {
+            match state.storage.set_hard_state(current, Some(candidate)) {
+                Ok(()) => state.reset_election_deadline(),
+                Err(err) => {
+                    error!("cannot save vote: {}", err);
+                    granted = false;
+                }
+            }
+        }
+
+        RaftResponse::Vote {
+            term: current,
+            granted,
+        }
+    }
+
+    fn append_entries(
+        &self,
+        term: u64,
+        leader: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    ) -> RaftResponse {
+        let mut state = self.lock();
+        self.observe_term(&mut state, term);
+        let current = state.storage.term();
+        let reject = |match_index| RaftResponse::Append {
+            term: current,
+            success: false,
+            match_index,
+        };
+
+        if term < current {
+            return reject(0);
+        }
+        self.follow(&mut state, leader);
+
+        if prev_log_index > state.storage.last_index() {
+            return reject(state.storage.last_index());
+        }
+        // Entries up to the snapshot are committed, so they always match.
+        if prev_log_index > state.storage.snapshot().index
+            && state.storage.term_at(prev_log_index) != Some(prev_log_term)
+        {
+            return reject(prev_log_index - 1);
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        if let Err(err) = state.storage.append(entries) {
+            error!("cannot append entries: {}", err);
+            return reject(prev_log_index);
+        }
+
+        if leader_commit > state.commit_index {
+            state.commit_index = leader_commit.min(last_new).max(state.commit_index);
+            self.changed.notify_all();
+        }
+
+        RaftResponse::Append {
+            term: current,
+            success: true,
+            match_index: last_new,
+        }
+    }
+
+    fn install_snapshot(
+        &self,
+        term: u64,
+        leader: String,
+        meta: SnapshotMeta,
+        offset: u64,
+        pairs: Vec<(String, String)>,
+        done: bool,
+    ) -> RaftResponse {
+        {
+            let mut state = self.lock();
+            self.observe_term(&mut state, term);
+            let current = state.storage.term();
+            if term < current {
+                return RaftResponse::Snapshot {
+                    term: current,
+                    success: false,
+                };
+            }
+            self.follow(&mut state, leader);
+            if meta.index <= state.last_applied {
+                return RaftResponse::Snapshot {
+                    term,
+                    success: true,
+                };
+            }
+
+            let success = match state.storage.receive_snapshot(&meta, offset, pairs) {
+                Ok(success) => success,
+                Err(err) => {
+                    error!("cannot receive snapshot: {}", err);
+                    false
+                }
+            };
+            if !success || !done {
+                return RaftResponse::Snapshot { term, success };
+            }
+        }
+
+        let mut engine = self.engine.lock().expect("engine lock poisoned");
+        let success = match self.install(&mut *engine) {
+            Ok(()) => true,
+            Err(err) => {
+                error!("cannot install snapshot: {}", err);
+                false
+            }
+        };
+        RaftResponse::Snapshot { term, success }
+    }
+
+    /// Makes the engine hold exactly the pairs of the snapshot received and
+    /// keeps them as our own snapshot.
+    fn install(&self, engine: &mut T) -> Result<()> {
+        let (meta, pairs) = self.lock().storage.take_snapshot()?;
+        let mut stale: HashSet<String> = engine.keys()?.into_iter().collect();
+        for pair in pairs {
+            let (key, value) = pair?;
+            stale.remove(&key);
+            if engine.get(key.clone())?.as_ref() != Some(&value) {
+                engine.set(key.clone(), value.clone())?;
+                self.feed.publish(Change::Set { key, value });
+            }
+        }
+        for key in stale {
+            engine.remove(key.clone())?;
+            self.feed.publish(Change::Remove { key });
+        }
+
+        let index = meta.index;
+        self.save_snapshot(engine, meta)?;
+
+        let mut state = self.lock();
+        state.commit_index = state.commit_index.max(index);
+        state.last_applied = index;
+        self.changed.notify_all();
+        info!("installed snapshot at index {}", index);
+        Ok(())
+    }
+
+    /// Applies committed entries as they come.
+    fn apply(self: Arc<Self>) {
+        loop {
+            {
+                let mut state = self.lock();
+                while state.last_applied >= state.commit_index {
+                    state = self.changed.wait(state).expect("raft lock poisoned");
+                }
+            }
+
+            let mut engine = self.engine.lock().expect("engine lock poisoned");
+            let entries = {
+                let state = self.lock();
+                state.storage.entries_from(
+                    state.last_applied + 1,
+                    state.commit_index,
+                    MAX_APPEND_ENTRIES,
+                )
+            };
+
+            let mut applied = None;
+            let mut ineffective = Vec::new();
+            let mut failed = false;
+            for entry in entries {
+                match self.apply_entry(&mut *engine, entry.command) {
+                    Ok(true) => {}
+                    Ok(false) => ineffective.push(entry.index),
+                    Err(err) => {
+                        error!("cannot apply entry {}: {}", entry.index, err);
+                        failed = true;
+                        break;
+                    }
+                }
+                applied = Some(entry.index);
+            }
+
+            let snapshot = {
+                let mut state = self.lock();
+                if let Some(index) = applied {
+                    let first = state.last_applied + 1;
+                    for (entry, outcome) in state.outcomes.iter_mut() {
+                        if (first..=index).contains(entry) {
+                            *outcome = Some(!ineffective.contains(entry));
+                        }
+                    }
+                    state.last_applied = index;
+                    self.changed.notify_all();
+                }
+                let snapshot_index = state.storage.snapshot().index;
+                (state.last_applied >= snapshot_index + self.snapshot_threshold).then(|| {
+                    let index = state.last_applied;
+                    SnapshotMeta {
+                        index,
+                        term: state.storage.term_at(index).unwrap_or(0),
+                        members: state.storage.members_at(index),
+                    }
+                })
+            };
+
+            if let Some(meta) = snapshot {
+                let index = meta.index;
+                match self.save_snapshot(&mut *engine, meta) {
+                    Ok(()) => info!("took snapshot at index {}", index),
+                    Err(err) => error!("cannot take snapshot: {}", err),
+                }
+            }
+
+            if failed {
+                drop(engine);
+                thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+
+    /// Applies `command`, returning false if it had no effect because the
+    /// key to remove was missing.
+    fn apply_entry(&self, engine: &mut T, command: Command) -> Result<bool> {
+        match command {
+            Command::Set { key, value } => {
+                engine.set(key.clone(), value.clone())?;
+                self.feed.publish(Change::Set { key, value });
+            }
+            Command::Remove { key } => match engine.remove(key.clone()) {
+                Ok(()) => {
+                    self.feed.publish(Change::Remove { key });
+                }
+                Err(Error::KeyNotFound) => return Ok(false),
+                Err(err) => return Err(err),
+            },
+            Command::Noop | Command::Members(_) => {}
+        }
+        Ok(true)
+    }
+
+    /// Backs the engine up as the snapshot described by `meta`.
+    fn save_snapshot(&self, engine: &mut T, meta: SnapshotMeta) -> Result<()> {
+        let _snapshots = self.snapshots.lock().expect("snapshot lock poisoned");
+        let dir = self.lock().storage.prepare_snapshot()?;
+        engine.backup(&dir)?;
+        self.lock().storage.commit_snapshot(meta)
+    }
+}
+
+fn has_quorum<F: Fn(&String) -> bool>(members: &BTreeSet<String>, acked: F) -> bool {
+    members.iter().filter(|member| acked(member)).count() * 2 > members.len()
+}
@@ -0,0 +1,100 @@
+use super::{RaftMessage, RaftResponse};
+use crate::protocol;
+use crate::{Error, Request, Response, Result};
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// Id of the last request sent.
+    id: u32,
+}
+
+/// Another cluster member, reached over a plain TCP connection that is
+/// reopened after any failure. The token and the entries are not encrypted.
+pub(super) struct Peer {
+    addr: String,
+    token: Option<String>,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl Peer {
+    pub fn new(addr: &str, token: Option<String>) -> Self {
+        Peer {
+            addr: addr.to_owned(),
+            token,
+            connection: Mutex::new(None),
+        }
+    }
+
+    pub fn call(&self, message: RaftMessage, timeout: Duration) -> Result<RaftResponse> {
+        let mut connection = self.connection.lock().expect("peer lock poisoned");
+        let mut conn = match connection.take() {
+            Some(conn) => conn,
+            None => self.connect()?,
+        };
+
+        let result = conn.call(message, timeout);
+        if result.is_ok() {
+            *connection = Some(conn);
+        }
+        result
+    }
+
+    fn connect(&self) -> Result<Connection> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::Raft(format!("cannot resolve {}", self.addr)))?;
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream.try_clone()?),
+            stream,
+            id: 0,
+        };
+        protocol::connect(&mut conn.reader, &mut conn.writer)?;
+        if let Some(token) = &self.token {
+            conn.auth(token, CONNECT_TIMEOUT)?;
+        }
+        Ok(conn)
+    }
+}
+
+impl Connection {
+    fn auth(&mut self, token: &str, timeout: Duration) -> Result<()> {
+        let token = token.to_owned();
+        match self.request(&Request::Auth { token }, timeout)? {
+            Response::Ok => Ok(()),
+            other => Err(other.unexpected("an authentication result")),
+        }
+    }
+
+    fn call(&mut self, message: RaftMessage, timeout: Duration) -> Result<RaftResponse> {
+        match self.request(&Request::Raft(message), timeout)? {
+            Response::Raft(response) => Ok(response),
+            other => Err(other.unexpected("a Raft response")),
+        }
+    }
+
+    fn request(&mut self, request: &Request, timeout: Duration) -> Result<Response> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.set_write_timeout(Some(timeout))?;
+
+        self.id = self.id.wrapping_add(1);
+        protocol::send_request(&mut self.writer, self.id, request)?;
+        protocol::read_response(&mut self.reader)
+    }
+}
@@ -0,0 +1,374 @@
+use super::{Command, Entry, SnapshotMeta};
+use crate::{Error, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const STATE: &str = "state.json";
+const LOG: &str = "log.jsonl";
+const SNAPSHOT: &str = "snapshot";
+const SNAPSHOT_META: &str = "RAFT_SNAPSHOT";
+const INCOMING: &str = "snapshot.incoming";
+
+#[derive(Deserialize, Serialize, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+/// A snapshot being received from the leader, its pairs staged in a file.
+struct Incoming {
+    meta: SnapshotMeta,
+    received: u64,
+    file: BufWriter<File>,
+}
+
+/// Durable Raft state under one directory: the current term and vote, the
+/// latest snapshot and the log entries that follow it.
+///
+/// The snapshot directory holds an engine backup together with the
+/// `RAFT_SNAPSHOT` metadata; a new snapshot is built next to it and swapped
+/// in by renaming.
+pub(super) struct Storage {
+    dir: PathBuf,
+    hard_state: HardState,
+    snapshot: SnapshotMeta,
+    entries: Vec<Entry>,
+    log: BufWriter<File>,
+    incoming: Option<Incoming>,
+}
+
+impl Storage {
+    /// Opens the state in `dir`, starting with `members` if there is none yet.
+    pub fn open(dir: &Path, members: BTreeSet<String>) -> Result<Storage> {
+        fs::create_dir_all(dir)?;
+        let old_snapshot = dir.join(format!("{}.old", SNAPSHOT));
+        if !dir.join(SNAPSHOT).exists() && old_snapshot.exists() {
+            // A crash interrupted swapping in a new snapshot.
+            fs::rename(&old_snapshot, dir.join(SNAPSHOT))?;
+        }
+
+        let hard_state = read_json(&dir.join(STATE))?.unwrap_or_default();
+        let snapshot = match read_json(&dir.join(SNAPSHOT).join(SNAPSHOT_META))? {
+            Some(meta) => meta,
+            None => {
+                let meta = SnapshotMeta {
+                    index: 0,
+                    term: 0,
+                    members,
+                };
+                fs::create_dir_all(dir.join(SNAPSHOT))?;
+                write_json(&dir.join(SNAPSHOT).join(SNAPSHOT_META), &meta)?;
+                meta
+            }
+        };
+
+        let mut storage = Storage {
+            dir: dir.to_owned(),
+            hard_state,
+            snapshot,
+            entries: Vec::new(),
+            log: BufWriter::new(open_log(&dir.join(LOG))?),
+            incoming: None,
+        };
+
+        let mut reader = BufReader::new(File::open(dir.join(LOG))?);
+        let mut line = String::new();
+        let mut valid = 0;
+        let mut clean = true;
+        while reader.read_line(&mut line)? > 0 {
+            // A torn last line is an append that never completed.
+            let entry = match serde_json::from_str::<Entry>(&line) {
+                Ok(entry) if line.ends_with('\n') => entry,
+                _ => break,
+            };
+            // Entries the snapshot covers or later ones replace are left
+            // behind by a crash before the log was rewritten.
+            clean &= entry.index == storage.last_index() + 1;
+            storage.push(entry)?;
+            valid += line.len() as u64;
+            line.clear();
+        }
+
+        if !clean {
+            storage.rewrite()?;
+        } else if !line.is_empty() {
+            let log = storage.log.get_ref();
+            log.set_len(valid)?;
+            log.sync_data()?;
+        }
+        Ok(storage)
+    }
+
+    pub fn term(&self) -> u64 {
+        self.hard_state.term
+    }
+
+    pub fn voted_for(&self) -> Option<&str> {
+        self.hard_state.voted_for.as_deref()
+    }
+
+    pub fn set_hard_state(&mut self, term: u64, voted_for: Option<String>) -> Result<()> {
+        self.hard_state = HardState { term, voted_for };
+        write_json(&self.dir.join(STATE), &self.hard_state)
+    }
+
+    pub fn snapshot(&self) -> &SnapshotMeta {
+        &self.snapshot
+    }
+
+    pub fn snapshot_dir(&self) -> PathBuf {
+        self.dir.join(SNAPSHOT)
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot.index, |entry| entry.index)
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries
+            .last()
+            .map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, unless it is compacted away or missing.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot.index - 1) as usize)
+    }
+
+    /// Up to `max` entries starting at `start`, which must follow the snapshot.
+    pub fn entries_from(&self, start: u64, end: u64, max: usize) -> Vec<Entry> {
+        let skip = start.saturating_sub(self.snapshot.index + 1) as usize;
+        self.entries
+            .iter()
+            .skip(skip)
+            .take_while(|entry| entry.index <= end)
+            .take(max)
+            .cloned()
+            .collect()
+    }
+
+    /// The membership in effect: the latest one in the log, committed or not.
+    pub fn members(&self) -> BTreeSet<String> {
+        self.members_at(self.last_index())
+    }
+
+    pub fn members_at(&self, index: u64) -> BTreeSet<String> {
+        self.entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.command {
+                Command::Members(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.members.clone())
+    }
+
+    /// Index of the entry that set the membership in effect.
+    pub fn members_index(&self) -> u64 {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| matches!(entry.command, Command::Members(_)))
+            .map_or(self.snapshot.index, |entry| entry.index)
+    }
+
+    /// Appends `entries`, first dropping any existing entry that conflicts
+    /// with them and everything after it.
+    pub fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        let mut truncated = false;
+        let mut appended = Vec::new();
+
+        for entry in entries {
+            if entry.index <= self.snapshot.index {
+                continue;
+            }
+            if entry.index <= self.last_index() {
+                if self.term_at(entry.index) == Some(entry.term) {
+                    continue;
+                }
+                self.truncate(entry.index);
+                truncated = true;
+            }
+            self.push(entry.clone())?;
+            appended.push(entry);
+        }
+
+        if truncated {
+            self.rewrite()
+        } else if !appended.is_empty() {
+            for entry in &appended {
+                serde_json::to_writer(&mut self.log, entry)?;
+                self.log.write_all(b"\n")?;
+            }
+            self.log.flush()?;
+            self.log.get_ref().sync_data()?;
+            Ok(())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Empties and returns the directory a new snapshot is built in.
+    pub fn prepare_snapshot(&self) -> Result<PathBuf> {
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+        Ok(tmp)
+    }
+
+    /// Swaps in the snapshot built in the prepared directory and drops the
+    /// log entries it covers.
+    ///
+    /// If the log disagrees with `meta`, as when a leader sent the snapshot,
+    /// the whole log is discarded.
+    pub fn commit_snapshot(&mut self, meta: SnapshotMeta) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT));
+        let old = self.dir.join(format!("{}.old", SNAPSHOT));
+        write_json(&tmp.join(SNAPSHOT_META), &meta)?;
+        if old.exists() {
+            fs::remove_dir_all(&old)?;
+        }
+        fs::rename(self.snapshot_dir(), &old)?;
+        fs::rename(&tmp, self.snapshot_dir())?;
+        fs::remove_dir_all(&old)?;
+
+        if self.term_at(meta.index) == Some(meta.term) {
+            self.entries.retain(|entry| entry.index > meta.index);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = meta;
+        self.rewrite()
+    }
+
+    /// Stages a chunk of the snapshot `meta` holding the pairs after the
+    /// first `offset`. Returns false if it does not follow the chunks staged
+    /// so far, so the leader has to start over.
+    pub fn receive_snapshot(
+        &mut self,
+        meta: &SnapshotMeta,
+        offset: u64,
+        pairs: Vec<(String, String)>,
+    ) -> Result<bool> {
+        if offset == 0 {
+            self.incoming = Some(Incoming {
+                meta: meta.clone(),
+                received: 0,
+                file: BufWriter::new(File::create(self.dir.join(INCOMING))?),
+            });
+        }
+        let incoming = match &mut self.incoming {
+            Some(incoming) if incoming.meta == *meta && incoming.received == offset => incoming,
+            _ => return Ok(false),
+        };
+
+        let received = pairs.len() as u64;
+        for pair in pairs {
+            serde_json::to_writer(&mut incoming.file, &pair)?;
+            incoming.file.write_all(b"\n")?;
+        }
+        incoming.received += received;
+        Ok(true)
+    }
+
+    /// Takes the snapshot received in full, along with a reader of its pairs.
+    pub fn take_snapshot(
+        &mut self,
+    ) -> Result<(SnapshotMeta, impl Iterator<Item = Result<(String, String)>>)> {
+        let mut incoming = self
+            .incoming
+            .take()
+            .ok_or_else(|| Error::Raft("no snapshot was received".to_owned()))?;
+        incoming.file.flush()?;
+        let path = self.dir.join(INCOMING);
+        let file = File::open(&path)?;
+        fs::remove_file(&path)?;
+
+        let pairs = serde_json::Deserializer::from_reader(BufReader::new(file))
+            .into_iter::<(String, String)>()
+            .map(|pair| Ok(pair?));
+        Ok((incoming.meta, pairs))
+    }
+
+    fn push(&mut self, entry: Entry) -> Result<()> {
+        if entry.index <= self.snapshot.index {
+            return Ok(());
+        }
+        if entry.index <= self.last_index() {
+            self.truncate(entry.index);
+        }
+        if entry.index != self.last_index() + 1 {
+            return Err(Error::Raft(format!(
+                "log entry {} does not follow entry {}",
+                entry.index,
+                self.last_index()
+            )));
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn truncate(&mut self, from: u64) {
+        let keep = from.saturating_sub(self.snapshot.index + 1) as usize;
+        self.entries.truncate(keep);
+    }
+
+    /// Replaces the log file with the entries held in memory.
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", LOG));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in &self.entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, self.dir.join(LOG))?;
+        self.log = BufWriter::new(open_log(&self.dir.join(LOG))?);
+        Ok(())
+    }
+}
+
+/// Reads the metadata of the snapshot stored in `dir`.
+pub(super) fn snapshot_meta(dir: &Path) -> Result<SnapshotMeta> {
+    read_json(&dir.join(SNAPSHOT_META))?
+        .ok_or_else(|| Error::Raft(format!("no snapshot in {}", dir.display())))
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+}
+
+/// Writes under a temporary name and renames into place, like the manifest.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(value)?)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
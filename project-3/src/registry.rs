@@ -0,0 +1,141 @@
+use crate::{CompactionPolicy, Engine, Error, ReadOnly, Result, Sled, Store};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+type EngineFactory = Box<dyn Fn(&EngineConfig) -> Result<Box<dyn Engine>> + Send + Sync>;
+type WrapperFactory = Box<dyn Fn(Box<dyn Engine>) -> Result<Box<dyn Engine>> + Send + Sync>;
+
+/// Settings handed to an engine factory when the engine is opened.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub path: PathBuf,
+    /// Open without taking ownership of the directory; writes are rejected.
+    /// sled still locks its directory, so only `kvs` can be opened this way
+    /// while another process writes to it.
+    pub read_only: bool,
+    /// Policy for engines that compact on their own.
+    pub compaction: CompactionPolicy,
+}
+
+impl EngineConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        EngineConfig {
+            path: path.into(),
+            read_only: false,
+            compaction: CompactionPolicy::default(),
+        }
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn compaction(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction = policy;
+        self
+    }
+}
+
+/// Named engine factories and wrappers, so the engine stack can be chosen at runtime.
+///
+/// `Registry::default()` knows the `kvs` and `sled` engines and the `read-only` wrapper.
+pub struct Registry {
+    engines: BTreeMap<String, EngineFactory>,
+    wrappers: BTreeMap<String, WrapperFactory>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            engines: BTreeMap::new(),
+            wrappers: BTreeMap::new(),
+        }
+    }
+
+    pub fn register_engine<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&EngineConfig) -> Result<Box<dyn Engine>> + Send + Sync + 'static,
+    {
+        self.engines.insert(name.to_owned(), Box::new(factory));
+    }
+
+    pub fn register_wrapper<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(Box<dyn Engine>) -> Result<Box<dyn Engine>> + Send + Sync + 'static,
+    {
+        self.wrappers.insert(name.to_owned(), Box::new(factory));
+    }
+
+    pub fn has_engine(&self, name: &str) -> bool {
+        self.engines.contains_key(name)
+    }
+
+    pub fn engine_names(&self) -> Vec<&str> {
+        self.engines.keys().map(String::as_str).collect()
+    }
+
+    pub fn wrapper_names(&self) -> Vec<&str> {
+        self.wrappers.keys().map(String::as_str).collect()
+    }
+
+    pub fn open(&self, name: &str, config: &EngineConfig) -> Result<Box<dyn Engine>> {
+        let factory = self
+            .engines
+            .get(name)
+            .ok_or_else(|| Error::UnknownEngine(name.to_owned()))?;
+        factory(config)
+    }
+
+    pub fn wrap(&self, name: &str, engine: Box<dyn Engine>) -> Result<Box<dyn Engine>> {
+        let factory = self
+            .wrappers
+            .get(name)
+            .ok_or_else(|| Error::UnknownWrapper(name.to_owned()))?;
+        factory(engine)
+    }
+
+    /// Opens engine `name` and applies `wrappers` in order, innermost first.
+    pub fn build<S: AsRef<str>>(
+        &self,
+        name: &str,
+        wrappers: &[S],
+        config: &EngineConfig,
+    ) -> Result<Box<dyn Engine>> {
+        wrappers
+            .iter()
+            .try_fold(self.open(name, config)?, |engine, wrapper| {
+                self.wrap(wrapper.as_ref(), engine)
+            })
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Registry::new();
+
+        registry.register_engine("kvs", |config| {
+            let mut store = if config.read_only {
+                Store::open_read_only(&config.path)?
+            } else {
+                Store::open(&config.path)?
+            };
+            store.set_compaction_policy(config.compaction.clone());
+            Ok(Box::new(store))
+        });
+        registry.register_engine("sled", |config| {
+            let mut engine = Sled::new(sled::open(&config.path)?);
+            if config.compaction.paused {
+                engine.pause_compaction(true)?;
+            }
+            if config.read_only {
+                Ok(Box::new(ReadOnly::new(engine)))
+            } else {
+                Ok(Box::new(engine))
+            }
+        });
+        registry.register_wrapper("read-only", |engine| Ok(Box::new(ReadOnly::new(engine))));
+
+        registry
+    }
+}
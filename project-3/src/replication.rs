@@ -0,0 +1,191 @@
+use crate::engine::KeyBatches;
+use crate::protocol;
+use crate::{Change, Engine, Error, Feed, Position, Request, Response, Result};
+use std::{
+    collections::VecDeque,
+    io::{BufReader, BufWriter},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Pause before reconnecting to a primary that went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Ids of the frames a replica sends.
+const AUTH_ID: u32 = 1;
+const REPLICATE_ID: u32 = 2;
+
+/// Follows a primary by applying its snapshot and change stream to the local engine.
+///
+/// The primary is reached over plain TCP, so the token and the data are
+/// not encrypted.
+pub(crate) struct Replica<T: Engine> {
+    pub primary: String,
+    pub engine: Arc<Mutex<T>>,
+    pub feed: Arc<Feed>,
+    /// Cleared on promotion, which stops replication.
+    pub replica: Arc<AtomicBool>,
+    pub position: Option<Position>,
+    /// Presented to the primary when it requires authentication.
+    pub token: Option<String>,
+}
+
+impl<T: Engine> Replica<T> {
+    /// Replicates until the server is promoted, reconnecting whenever the
+    /// primary goes away.
+    pub fn run(mut self) {
+        while self.replica.load(Ordering::SeqCst) {
+            match self.follow() {
+                Ok(()) => {}
+                Err(err) => warn!("replication from {} interrupted: {}", self.primary, err),
+            }
+            if self.replica.load(Ordering::SeqCst) {
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+        info!("stopped replicating from {}", self.primary);
+    }
+
+    fn follow(&mut self) -> Result<()> {
+        let stream = TcpStream::connect(&self.primary)?;
+        let mut writer = BufWriter::new(&stream);
+        let mut reader = BufReader::new(&stream);
+        protocol::connect(&mut reader, &mut writer)?;
+
+        if let Some(token) = self.token.clone() {
+            protocol::send_request(&mut writer, AUTH_ID, &Request::Auth { token })?;
+            match protocol::read_response(&mut reader)? {
+                Response::Ok => {}
+                other => return Err(other.unexpected("an authentication result")),
+            }
+        }
+
+        let resume = self.position;
+        protocol::send_request(&mut writer, REPLICATE_ID, &Request::Replicate { resume })?;
+        info!("replicating from {}", self.primary);
+
+        let mut snapshot: Option<LocalKeys> = None;
+
+        loop {
+            let applied = match protocol::read_response(&mut reader)? {
+                Response::SnapshotStart => {
+                    snapshot = Some(LocalKeys::default());
+                    true
+                }
+                Response::Entry { key, value } => {
+                    let local = snapshot.as_mut().ok_or_else(|| {
+                        Error::Replication("snapshot entry outside a snapshot".to_owned())
+                    })?;
+                    local.remove_before(&self.engine, Some(&key), |key| {
+                        self.apply(Change::Remove { key })
+                    })? && self.apply(Change::Set { key, value })?
+                }
+                Response::SnapshotEnd(position) => {
+                    let mut local = snapshot.take().ok_or_else(|| {
+                        Error::Replication("snapshot end outside a snapshot".to_owned())
+                    })?;
+                    let applied = local.remove_before(&self.engine, None, |key| {
+                        self.apply(Change::Remove { key })
+                    })?;
+                    self.position = Some(position);
+                    applied
+                }
+                Response::Event(event) => {
+                    let applied = self.apply(event.change)?;
+                    if let Some(position) = self.position.as_mut() {
+                        position.seq = event.seq;
+                    }
+                    applied
+                }
+                Response::Heartbeat { .. } => self.replica.load(Ordering::SeqCst),
+                other => return Err(other.unexpected("a replication stream")),
+            };
+
+            if !applied {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Applies `change` unless the server was promoted in the meantime.
+    ///
+    /// Promotion is checked under the engine lock, so no replicated change
+    /// lands after the first write accepted as primary.
+    fn apply(&self, change: Change) -> Result<bool> {
+        let mut engine = self.engine.lock().expect("engine lock poisoned");
+        if !self.replica.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+
+        match &change {
+            Change::Set { key, value } => engine.set(key.clone(), value.clone())?,
+            Change::Remove { key } => match engine.remove(key.clone()) {
+                Ok(()) | Err(Error::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            },
+        }
+        self.feed.publish(change);
+        Ok(true)
+    }
+}
+
+/// The replica's own keys, walked in order next to the sorted snapshot so
+/// that keys the primary no longer has are found a batch at a time.
+struct LocalKeys {
+    batches: KeyBatches,
+    pending: VecDeque<String>,
+}
+
+impl Default for LocalKeys {
+    fn default() -> Self {
+        LocalKeys {
+            batches: KeyBatches::new(""),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl LocalKeys {
+    /// Passes every local key before `key`, or every remaining one if
+    /// `None`, to `remove` and skips `key` itself. Stops early once `remove`
+    /// returns false, which is returned.
+    fn remove_before<T, F>(
+        &mut self,
+        engine: &Mutex<T>,
+        key: Option<&str>,
+        mut remove: F,
+    ) -> Result<bool>
+    where
+        T: Engine,
+        F: FnMut(String) -> Result<bool>,
+    {
+        loop {
+            while let Some(local) = self.pending.pop_front() {
+                match key {
+                    Some(key) if local == key => return Ok(true),
+                    Some(key) if local.as_str() > key => {
+                        self.pending.push_front(local);
+                        return Ok(true);
+                    }
+                    _ => {
+                        if !remove(local)? {
+                            return Ok(false);
+                        }
+                    }
+                }
+            }
+
+            let keys = self
+                .batches
+                .next_keys(&mut *engine.lock().expect("engine lock poisoned"))?;
+            if keys.is_empty() {
+                return Ok(true);
+            }
+            self.pending.extend(keys);
+        }
+    }
+}
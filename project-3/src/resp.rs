@@ -0,0 +1,225 @@
+//! Enough of the Redis serialization protocol (RESP2) to serve redis clients.
+
+use crate::limits::too_large;
+use crate::{Error, Result};
+use std::io::{BufRead, Read, Write};
+
+/// Largest bulk string a client may send, as in Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+/// Deepest `*` nesting a glob pattern may reach before it stops matching.
+const MAX_GLOB_NESTING: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// `None` is the nil bulk string.
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn ok() -> Value {
+        Value::Simple("OK".to_owned())
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            Value::Error(message) => write!(writer, "-{}\r\n", message)?,
+            Value::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Value::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Value::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s)?,
+            Value::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the arguments of the next command, either a RESP array of bulk
+/// strings or an inline command as typed into telnet.
+///
+/// Returns `None` once the client closed the connection, and fails with
+/// `Error::Limit` before buffering more than `max_size` bytes of the command,
+/// counting its headers.
+pub(crate) fn read_command<R: BufRead>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_len(&line[1..], MAX_ARGS, "multibulk length")?;
+    // The count is untrusted, so the arguments grow as they arrive.
+    let mut args = Vec::new();
+    let mut left = max_size.saturating_sub(line.len() + 2);
+    for _ in 0..count.unwrap_or(0) {
+        let header = read_line(reader, max_size)?.ok_or_else(truncated)?;
+        left = charge(left, header.len() + 2, max_size)?;
+        if header.first() != Some(&b'$') {
+            return Err(Error::Protocol(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&header[..header.len().min(1)])
+            )));
+        }
+        let len = parse_len(&header[1..], MAX_BULK_LEN, "bulk length")?
+            .ok_or_else(|| Error::Protocol("invalid bulk length".to_owned()))?;
+        left = charge(left, len + 2, max_size)?;
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(Error::Protocol("bulk string not terminated".to_owned()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Takes `used` bytes out of what is `left` of a command's budget.
+fn charge(left: usize, used: usize, max_size: usize) -> Result<usize> {
+    left.checked_sub(used).ok_or_else(|| too_large(max_size))
+}
+
+/// Reads a line without its terminator, accepting a bare `\n` like Redis does.
+fn read_line<R: BufRead>(reader: &mut R, max_size: usize) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let limit = max_size as u64 + 2;
+    if Read::take(&mut *reader, limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        if line.len() as u64 == limit {
+            return Err(too_large(max_size));
+        }
+        return Err(truncated());
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Parses a length, where a negative one stands for a nil value.
+fn parse_len(digits: &[u8], max: usize, what: &str) -> Result<Option<usize>> {
+    let invalid = || Error::Protocol(format!("invalid {}", what));
+    let n: i64 = std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    if n < 0 {
+        Ok(None)
+    } else if n as u64 > max as u64 {
+        Err(invalid())
+    } else {
+        Ok(Some(n as usize))
+    }
+}
+
+fn truncated() -> Error {
+    Error::Protocol("connection closed in the middle of a command".to_owned())
+}
+
+/// Matches `text` against a Redis glob pattern: `*`, `?`, `[...]` classes
+/// with ranges and `^` negation, and `\` escapes.
+///
+/// Like Redis, patterns nesting `*` deeper than `MAX_GLOB_NESTING` match
+/// nothing, and a `*` that already tried every suffix of the text stops
+/// the stars before it from retrying shorter ones, which keeps patterns like
+/// `*a*a*a*b` polynomial.
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    glob_match_at(pattern, text, 0, &mut false)
+}
+
+fn glob_match_at(pattern: &[u8], text: &[u8], nesting: usize, exhausted: &mut bool) -> bool {
+    if nesting > MAX_GLOB_NESTING {
+        return false;
+    }
+    let next =
+        |pattern, text, exhausted: &mut bool| glob_match_at(pattern, text, nesting, exhausted);
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', mut rest)) => {
+            while let Some((b'*', tail)) = rest.split_first() {
+                rest = tail;
+            }
+            if rest.is_empty() {
+                return true;
+            }
+            for skip in 0..=text.len() {
+                if glob_match_at(rest, &text[skip..], nesting + 1, exhausted) {
+                    return true;
+                }
+                if *exhausted {
+                    return false;
+                }
+            }
+            *exhausted = true;
+            false
+        }
+        Some((b'?', rest)) => !text.is_empty() && next(rest, &text[1..], exhausted),
+        Some((b'[', rest)) => {
+            let (c, text) = match text.split_first() {
+                Some(split) => split,
+                None => return false,
+            };
+            let (negate, mut class) = match rest.split_first() {
+                Some((b'^', class)) => (true, class),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match class {
+                    // An unterminated class runs to the end of the pattern.
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        class = tail;
+                        break;
+                    }
+                    [b'\\', escaped, tail @ ..] => {
+                        matched |= escaped == c;
+                        class = tail;
+                    }
+                    [low, b'-', high, tail @ ..] if *high != b']' => {
+                        let (low, high) = if low <= high {
+                            (low, high)
+                        } else {
+                            (high, low)
+                        };
+                        matched |= (low..=high).contains(&c);
+                        class = tail;
+                    }
+                    [other, tail @ ..] => {
+                        matched |= other == c;
+                        class = tail;
+                    }
+                }
+            }
+            matched != negate && next(class, text, exhausted)
+        }
+        Some((b'\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && next(rest, &text[1..], exhausted)
+        }
+        Some((literal, rest)) => text.first() == Some(literal) && next(rest, &text[1..], exhausted),
+    }
+}
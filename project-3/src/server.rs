@@ -1,29 +1,351 @@
-use crate::{Engine, GetResponse, RemoveResponse, Request, Result, SetResponse};
-use serde::Serialize;
-use serde_json::Deserializer;
+use crate::acl::{self, Access, Acl};
+use crate::connection::{BinaryConnection, Connection, JsonConnection};
+use crate::engine::KeyBatches;
+use crate::http;
+use crate::limits::{timed_out, Limits, TimedReader};
+use crate::protocol;
+use crate::raft::{Command, Raft, RaftConfig};
+use crate::replication::Replica;
+use crate::resp::{self, Value};
+use crate::shutdown::{Connections, Open, ShutdownHandle};
+use crate::stream::{Listener, Stream};
+use crate::{
+    backup, Auth, Change, Engine, Error, Feed, Manifest, Position, Request, Response, Result,
+    ServerTls, Subscription,
+};
 use std::{
-    io::{BufReader, BufWriter},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    cell::Cell,
+    fmt,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    rc::Rc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+/// Interval at which an idle watch or replication connection is probed.
+const WATCH_HEARTBEAT: Duration = Duration::from_secs(5);
+/// How often the engine is given the chance to start its own upkeep.
+pub(crate) const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the accept loop and event streams look for a shutdown.
+pub(crate) const SHUTDOWN_POLL: Duration = Duration::from_millis(50);
+/// How long a shutdown waits for requests in flight by default.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a connection refused for being one too many may take to send
+/// the request its refusal answers.
+pub(crate) const REFUSAL_WAIT: Duration = Duration::from_secs(1);
+/// Refused connections answered at once; more are closed without an answer.
+pub(crate) const MAX_REFUSALS: usize = 16;
+
+/// The wire protocol a server speaks to its clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// JSON, or the binary protocol for clients that negotiate it.
+    Kvs,
+    /// RESP2, for redis clients.
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(format!("unknown protocol {}, expected kvs or resp", s)),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Kvs => write!(f, "kvs"),
+            Protocol::Resp => write!(f, "resp"),
+        }
+    }
+}
+
+/// A server that serves every connection on a thread of its own.
+///
+/// Watch and replication streams hold their connection for as long as the
+/// client stays, so connections cannot take turns on one thread. The engine
+/// sits behind a mutex that each request takes for as long as it touches
+/// the engine, and changes are published while it is held, so the feed
+/// sees them in the order they were applied.
 pub struct Server<T: Engine> {
-    engine: T,
+    engine: Arc<Mutex<T>>,
+    protocol: Protocol,
+    manifest: Option<Manifest>,
+    feed: Arc<Feed>,
+    primary: Option<String>,
+    replica: Arc<AtomicBool>,
+    cluster: Option<RaftConfig>,
+    raft: Option<Arc<Raft<T>>>,
+    http: Option<String>,
+    limits: Limits,
+    auth: Option<Auth>,
+    acl: Option<Acl>,
+    token: Option<String>,
+    tls: Option<ServerTls>,
+    backup_dir: Option<PathBuf>,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+    connections: Arc<Connections>,
+    refusals: Arc<Connections>,
 }
 
-impl<T: Engine> Server<T> {
+impl<T: Engine + 'static> Server<T> {
     pub fn new(engine: T) -> Self {
-        Server { engine }
+        Server {
+            engine: Arc::new(Mutex::new(engine)),
+            protocol: Protocol::Kvs,
+            manifest: None,
+            feed: Arc::new(Feed::default()),
+            primary: None,
+            replica: Arc::new(AtomicBool::new(false)),
+            cluster: None,
+            raft: None,
+            http: None,
+            limits: Limits::default(),
+            auth: None,
+            acl: None,
+            token: None,
+            tls: None,
+            backup_dir: None,
+            shutdown: ShutdownHandle::default(),
+            drain_timeout: DRAIN_TIMEOUT,
+            connections: Arc::new(Connections::default()),
+            refusals: Arc::new(Connections::default()),
+        }
+    }
+
+    /// Speaks `protocol` to clients instead of the kvs protocol.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the manifest describing the served data, which is required for backups.
+    pub fn manifest(mut self, manifest: Manifest) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    /// Runs the server as a read-only replica of the primary at `addr` until
+    /// it is promoted.
+    pub fn replica_of(mut self, addr: String) -> Self {
+        self.primary = Some(addr);
+        self.replica.store(true, Ordering::SeqCst);
+        self
+    }
+
+    /// Runs the server as a member of a Raft cluster, so that writes are
+    /// committed by a majority of the members before they are acknowledged.
+    ///
+    /// The members authenticate each other with `token`, which `auth` must take.
+    pub fn cluster(mut self, config: RaftConfig) -> Self {
+        self.cluster = Some(config);
+        self
+    }
+
+    /// Also serves the HTTP gateway on `addr`.
+    pub fn http(mut self, addr: String) -> Self {
+        self.http = Some(addr);
+        self
+    }
+
+    /// Sets the limits put on clients.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Requires clients to authenticate with one of the tokens of `auth`
+    /// before anything else.
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Only lets authenticated users do what `acl` grants them.
+    ///
+    /// Replicas authenticate like clients, so their user needs admin access
+    /// to every key.
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Presents `token` to the primary and the other cluster members when
+    /// they require authentication.
+    ///
+    /// Replicas and cluster members never use TLS, so the token crosses the
+    /// network in clear text along with the data; keep their traffic on a
+    /// trusted network.
+    pub fn token(mut self, token: String) -> Self {
+        self.token = Some(token);
+        self
     }
 
-    pub fn run<A: ToSocketAddrs>(mut self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+    /// Serves clients over TLS.
+    ///
+    /// Replicas and cluster members still connect to each other without
+    /// it. The HTTP gateway only speaks plain HTTP, so `run` refuses to
+    /// serve it next to TLS.
+    pub fn tls(mut self, tls: ServerTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Lets clients back up into new directories under `dir`, which is
+    /// required for backups over the network.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// Gives at most `timeout` to the requests in flight when shutting down.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Returns a handle that makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves every connection on its own thread until shut down.
+    ///
+    /// Replication and Raft keep running on their own threads afterwards,
+    /// so the engine is only closed with the process in those modes.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = Listener::tcp(addr)?;
+        self.serve_on(listener)
+    }
+
+    /// Serves like `run`, on a Unix domain socket at `path`, which is
+    /// removed again on shutdown.
+    pub fn run_unix<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        if self.tls.is_some() {
+            return Err(Error::Tls(
+                "TLS is only served over TCP, not on a Unix socket".to_owned(),
+            ));
+        }
+        let listener = Listener::unix(path.as_ref())?;
+        self.serve_on(listener)
+    }
+
+    fn serve_on(mut self, listener: Listener) -> Result<()> {
+        if self.tls.is_some() && self.http.is_some() {
+            return Err(Error::Tls(
+                "the HTTP gateway cannot be served next to TLS".to_owned(),
+            ));
+        }
+        listener.set_nonblocking(true)?;
+
+        if let Some(mut config) = self.cluster.take() {
+            if self.peer().is_none() {
+                return Err(Error::Auth(
+                    "cluster members authenticate each other with the server's token, \
+                     which the server's auth must take"
+                        .to_owned(),
+                ));
+            }
+            config.token = self.token.clone();
+            let engine = Arc::clone(&self.engine);
+            self.raft = Some(Raft::start(config, engine, Arc::clone(&self.feed))?);
+        }
+
+        if let Some(primary) = self.primary.clone() {
+            let replica = Replica {
+                primary,
+                engine: Arc::clone(&self.engine),
+                feed: Arc::clone(&self.feed),
+                replica: Arc::clone(&self.replica),
+                position: None,
+                token: self.token.clone(),
+            };
+            thread::spawn(move || replica.run());
+        }
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(tcp_stream) => {
-                    if let Err(err) = self.serve(tcp_stream) {
-                        error!("serving failed: {}", err)
+        let maintenance = {
+            let engine = Arc::clone(&self.engine);
+            let shutdown = self.shutdown.clone();
+            thread::spawn(move || maintain(&engine, &shutdown))
+        };
+
+        let mut gateway = None;
+        if let Some(addr) = &self.http {
+            let server = tiny_http::Server::http(addr.as_str())
+                .map_err(|err| Error::Http(format!("cannot listen on {}: {}", addr, err)))?;
+            let server = Arc::new(server);
+            let handler = self.handler();
+            let serving = Arc::clone(&server);
+            gateway = Some((
+                server,
+                thread::spawn(move || http::serve(&serving, handler)),
+            ));
+        }
+
+        while !self.shutdown.is_shutdown() {
+            match listener.accept() {
+                Ok(stream) => {
+                    let prepared = stream
+                        .set_nonblocking(false)
+                        .and_then(|_| stream.set_write_timeout(self.limits.write_timeout));
+                    if let Err(err) = prepared {
+                        error!("connection failed: {}", err);
+                        continue;
                     }
+                    // Decided before anything is spent on the connection, so
+                    // that a flood of clients only costs a few threads.
+                    let refused = self
+                        .limits
+                        .max_connections
+                        .is_some_and(|max| self.connections.streams() >= max);
+                    let connections = if !refused {
+                        &self.connections
+                    } else if self.refusals.streams() < MAX_REFUSALS {
+                        warn!("refusing connection: too many clients");
+                        &self.refusals
+                    } else {
+                        warn!("dropping connection: too many clients");
+                        continue;
+                    };
+                    let open = connections.open(stream.try_clone().ok());
+                    let handler = self.handler();
+                    let protocol = self.protocol;
+                    let tls = self.tls.clone();
+                    thread::spawn(move || {
+                        let _open = open;
+                        let stream = match (&tls, stream) {
+                            (Some(tls), Stream::Tcp(tcp_stream)) => tls.accept(tcp_stream),
+                            (_, stream) => Ok(stream),
+                        };
+                        let served = stream.and_then(|stream| match protocol {
+                            Protocol::Kvs => handler.serve(stream, refused),
+                            Protocol::Resp => handler.serve_resp(stream, refused),
+                        });
+                        match served {
+                            Ok(()) => {}
+                            Err(Error::IO(err)) if timed_out(&err) => {
+                                debug!("closed connection that timed out")
+                            }
+                            Err(err) => error!("serving failed: {}", err),
+                        }
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(SHUTDOWN_POLL);
                 }
                 Err(err) => {
                     error!("connection failed: {}", err);
@@ -31,61 +353,863 @@ impl<T: Engine> Server<T> {
             }
         }
 
+        info!("shutting down");
+        drop(listener);
+        if let Some((server, serving)) = gateway {
+            server.unblock();
+            let _ = serving.join();
+        }
+        let _ = maintenance.join();
+        self.refusals.drain(Duration::ZERO);
+        let cut = self.connections.drain(self.drain_timeout);
+        if cut > 0 {
+            warn!("closed {} connections with requests still in flight", cut);
+        }
+        self.handler().engine().sync()?;
+        info!("engine synced");
         Ok(())
     }
 
-    fn serve(&mut self, stream: TcpStream) -> Result<()> {
-        let reader = BufReader::new(&stream);
+    /// The user the server's own token authenticates.
+    fn peer(&self) -> Option<String> {
+        let auth = self.auth.as_ref()?;
+        auth.authenticate(self.token.as_ref()?).map(str::to_owned)
+    }
+
+    fn handler(&self) -> Handler<T> {
+        Handler {
+            engine: Arc::clone(&self.engine),
+            manifest: self.manifest.clone(),
+            feed: Arc::clone(&self.feed),
+            primary: self.primary.clone(),
+            replica: Arc::clone(&self.replica),
+            raft: self.raft.clone(),
+            limits: self.limits.clone(),
+            auth: self.auth.clone(),
+            acl: self.acl.clone(),
+            peer: self.peer(),
+            backup_dir: self.backup_dir.clone(),
+            shutdown: self.shutdown.clone(),
+            connections: Arc::clone(&self.connections),
+        }
+    }
+}
+
+/// Serves requests on behalf of a server, from any protocol.
+pub(crate) struct Handler<T: Engine> {
+    engine: Arc<Mutex<T>>,
+    manifest: Option<Manifest>,
+    feed: Arc<Feed>,
+    primary: Option<String>,
+    replica: Arc<AtomicBool>,
+    raft: Option<Arc<Raft<T>>>,
+    limits: Limits,
+    auth: Option<Auth>,
+    acl: Option<Acl>,
+    /// The user the server's own token authenticates, as its replicas and
+    /// fellow cluster members do.
+    peer: Option<String>,
+    backup_dir: Option<PathBuf>,
+    shutdown: ShutdownHandle,
+    connections: Arc<Connections>,
+}
+
+impl<T: Engine> Clone for Handler<T> {
+    fn clone(&self) -> Self {
+        Handler {
+            engine: Arc::clone(&self.engine),
+            manifest: self.manifest.clone(),
+            feed: Arc::clone(&self.feed),
+            primary: self.primary.clone(),
+            replica: Arc::clone(&self.replica),
+            raft: self.raft.clone(),
+            limits: self.limits.clone(),
+            auth: self.auth.clone(),
+            acl: self.acl.clone(),
+            peer: self.peer.clone(),
+            backup_dir: self.backup_dir.clone(),
+            shutdown: self.shutdown.clone(),
+            connections: Arc::clone(&self.connections),
+        }
+    }
+}
+
+impl<T: Engine + 'static> Handler<T> {
+    /// Serves the binary protocol if the client opens with its handshake,
+    /// and JSON otherwise.
+    ///
+    /// A `refused` connection only gets its first request answered, with
+    /// `Error::Limit`.
+    fn serve(&self, stream: Stream, refused: bool) -> Result<()> {
+        let mut limits = self.limits.clone();
+        if refused {
+            limits.idle_timeout = Some(REFUSAL_WAIT);
+        }
+        let idle = Rc::new(Cell::new(true));
+        let mut reader = BufReader::new(TimedReader::new(&stream, &limits, Rc::clone(&idle)));
         let mut writer = BufWriter::new(&stream);
-        let request_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+        let max = limits.max_request_size;
+        let peer = stream.peer_name();
 
-        for request in request_reader {
-            let request = request?;
+        if reader.fill_buf()?.starts_with(&protocol::MAGIC[..1]) {
+            match protocol::accept(&mut reader, &mut writer)? {
+                Some(version) => {
+                    let conn = BinaryConnection::new(reader, writer, version, max);
+                    self.answer(conn, &idle, refused, &peer)
+                }
+                None => Ok(()),
+            }
+        } else {
+            let conn = JsonConnection::new(reader, writer, max);
+            self.answer(conn, &idle, refused, &peer)
+        }
+    }
 
-            match request {
-                Request::Get { key } => {
-                    let resp: GetResponse = {
-                        match self.engine.get(key) {
-                            Ok(value) => GetResponse::Ok(value),
-                            Err(err) => GetResponse::Err(format!("{}", err)),
-                        }
-                    };
+    fn answer<C: Connection>(
+        &self,
+        mut conn: C,
+        idle: &Cell<bool>,
+        refused: bool,
+        peer: &str,
+    ) -> Result<()> {
+        if !refused {
+            return self.serve_requests(conn, idle, peer);
+        }
+        if conn.read_request()?.is_some() {
+            conn.send(&Response::Err(too_many_clients().into()))?;
+        }
+        Ok(())
+    }
 
-                    send_response(&mut writer, resp)?;
+    /// Answers requests until the client leaves. `idle` is raised while
+    /// waiting for the next one.
+    ///
+    /// When the server requires authentication, requests before a
+    /// successful `Auth` are refused, and a failed one closes the connection.
+    /// Requests the user has no access to are refused too.
+    fn serve_requests<C: Connection>(
+        &self,
+        mut conn: C,
+        idle: &Cell<bool>,
+        peer: &str,
+    ) -> Result<()> {
+        let mut authenticated = self.auth.is_none();
+        let mut user = None;
+        loop {
+            idle.set(true);
+            let request = match conn.read_request() {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(Error::Limit(message)) => {
+                    // The rest of the request is still unread.
+                    return conn.send(&Response::Err(Error::Limit(message).into()));
+                }
+                Err(err) => return Err(err),
+            };
+            let request = match request {
+                Request::Auth { token } => match self.authenticate(&token, peer) {
+                    Ok(found) => {
+                        authenticated = true;
+                        user = found;
+                        conn.send(&Response::Ok)?;
+                        continue;
+                    }
+                    Err(err) => return conn.send(&Response::Err(err.into())),
+                },
+                _ if !authenticated => {
+                    conn.send(&Response::Err(authentication_required().into()))?;
+                    continue;
                 }
+                request => request,
+            };
+            if let Err(err) = self.permit(user.as_deref(), &request) {
+                conn.send(&Response::Err(err.into()))?;
+                continue;
+            }
+            let resp = match request {
+                Request::Get { key } => Response::from_result(self.get(key), Response::Value),
                 Request::Set { key, value } => {
-                    let resp: SetResponse = {
-                        match self.engine.set(key, value) {
-                            Ok(_) => SetResponse::Ok(()),
-                            Err(err) => SetResponse::Err(format!("{}", err)),
-                        }
-                    };
-
-                    send_response(&mut writer, resp)?;
+                    Response::from_result(self.set(key, value), |_| Response::Ok)
                 }
                 Request::Remove { key } => {
-                    let resp: RemoveResponse = {
-                        match self.engine.remove(key) {
-                            Ok(_) => RemoveResponse::Ok(()),
-                            Err(err) => RemoveResponse::Err(format!("{}", err)),
+                    Response::from_result(self.remove(key), |_| Response::Ok)
+                }
+                Request::Backup { path } => {
+                    Response::from_result(self.backup(&path), |_| Response::Ok)
+                }
+                Request::Stats => Response::from_result(self.engine().stats(), Response::Stats),
+                Request::Compact => {
+                    info!("compacting on request");
+                    Response::from_result(self.engine().compact(), |_| Response::Ok)
+                }
+                Request::PauseCompaction { paused } => {
+                    info!("compaction {}", if paused { "paused" } else { "resumed" });
+                    Response::from_result(self.engine().pause_compaction(paused), |_| Response::Ok)
+                }
+                Request::Scan { prefix } => match self.scan(&prefix, &mut conn) {
+                    Ok(()) => continue,
+                    Err(err) => Response::Err(err.into()),
+                },
+                Request::Watch { prefix, after } => {
+                    return self.watch(prefix, after, &mut conn);
+                }
+                Request::Replicate { resume } => {
+                    return self.replicate(resume, &mut conn);
+                }
+                Request::Promote => Response::from_result(self.promote(), |_| Response::Ok),
+                Request::Raft(message) => Response::from_result(
+                    self.raft().map(|raft| raft.handle(message)),
+                    Response::Raft,
+                ),
+                Request::ClusterStatus => self.cluster(|_| Ok(())),
+                Request::AddMember { addr } => self.cluster(|raft| raft.add_member(addr)),
+                Request::RemoveMember { addr } => self.cluster(|raft| raft.remove_member(addr)),
+                Request::Auth { .. } => unreachable!("authentication is handled above"),
+            };
+
+            conn.send(&resp)?;
+        }
+    }
+
+    fn serve_resp(&self, stream: Stream, refused: bool) -> Result<()> {
+        let idle = Rc::new(Cell::new(true));
+        let mut reader = BufReader::new(TimedReader::new(&stream, &self.limits, Rc::clone(&idle)));
+        let mut writer = BufWriter::new(&stream);
+
+        if refused {
+            Value::Error("ERR max number of clients reached".to_owned()).write(&mut writer)?;
+            return Ok(writer.flush()?);
+        }
+
+        let peer = stream.peer_name();
+        let mut authenticated = self.auth.is_none();
+        let mut user = None;
+        loop {
+            idle.set(true);
+            let args = match resp::read_command(&mut reader, self.limits.max_request_size) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(Error::Protocol(message)) => {
+                    Value::Error(format!("ERR Protocol error: {}", message)).write(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                Err(Error::Limit(message)) => {
+                    Value::Error(format!("ERR {}", message)).write(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            if args.is_empty() {
+                continue;
+            }
+
+            let mut quit = args[0].eq_ignore_ascii_case(b"quit");
+            let reply = match args
+                .into_iter()
+                .map(String::from_utf8)
+                .collect::<std::result::Result<Vec<_>, _>>()
+            {
+                Ok(args) if args[0].eq_ignore_ascii_case("auth") && matches!(args.len(), 2 | 3) => {
+                    match self.resp_auth(&args[1..], &peer) {
+                        Ok(found) => {
+                            authenticated = true;
+                            user = found;
+                            Value::ok()
                         }
-                    };
+                        Err(_) => {
+                            quit = true;
+                            Value::Error(
+                                "WRONGPASS invalid username-password pair or user is disabled."
+                                    .to_owned(),
+                            )
+                        }
+                    }
+                }
+                Ok(_) if !authenticated && !quit => {
+                    Value::Error("NOAUTH Authentication required.".to_owned())
+                }
+                Ok(args) => self.resp_command(&args, user.as_deref()),
+                Err(_) => Value::Error("ERR arguments must be valid UTF-8".to_owned()),
+            };
+            reply.write(&mut writer)?;
+
+            // Answer pipelined commands in one write.
+            if quit || reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn resp_command(&self, args: &[String], user: Option<&str>) -> Value {
+        let (command, args) = (&args[0], &args[1..]);
+        let name = command.to_ascii_lowercase();
+        let allowed = |keys: &[String], access| {
+            keys.iter()
+                .try_for_each(|key| self.authorize(user, key, access))
+        };
+        let wrong_arity = || {
+            Ok(Value::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            )))
+        };
+
+        let reply = match (name.as_str(), args) {
+            ("ping", []) => Ok(Value::Simple("PONG".to_owned())),
+            ("ping", [message]) => Ok(Value::Bulk(Some(message.clone()))),
+            ("quit", _) => Ok(Value::ok()),
+            // redis-cli asks for command docs when it starts.
+            ("command", _) => Ok(Value::Array(Vec::new())),
+            ("get", [key]) => allowed(&args[..1], Access::Read)
+                .and_then(|_| self.get(key.clone()))
+                .map(Value::Bulk),
+            ("set", [key, value]) => allowed(&args[..1], Access::Write)
+                .and_then(|_| self.set(key.clone(), value.clone()))
+                .map(|_| Value::ok()),
+            ("set", [_, _, ..]) => Ok(Value::Error("ERR syntax error".to_owned())),
+            ("del", keys) if !keys.is_empty() => {
+                allowed(keys, Access::Write).and_then(|_| self.resp_del(keys))
+            }
+            ("exists", keys) if !keys.is_empty() => {
+                allowed(keys, Access::Read).and_then(|_| self.resp_exists(keys))
+            }
+            ("mget", keys) if !keys.is_empty() => allowed(keys, Access::Read).and_then(|_| {
+                keys.iter()
+                    .map(|key| self.get(key.clone()).map(Value::Bulk))
+                    .collect::<Result<_>>()
+                    .map(Value::Array)
+            }),
+            ("mset", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let keys: Vec<String> = pairs.iter().step_by(2).cloned().collect();
+                allowed(&keys, Access::Write).and_then(|_| {
+                    pairs
+                        .chunks(2)
+                        .try_for_each(|pair| self.set(pair[0].clone(), pair[1].clone()))
+                        .map(|_| Value::ok())
+                })
+            }
+            ("keys", [pattern]) => self.resp_keys(pattern, user),
+            ("scan", [cursor, options @ ..]) => self.resp_scan(cursor, options, user),
+            ("info", []) => self
+                .authorize(user, "", Access::Admin)
+                .and_then(|_| self.resp_info(None)),
+            ("info", [section]) => self
+                .authorize(user, "", Access::Admin)
+                .and_then(|_| self.resp_info(Some(section))),
+            (
+                "ping" | "auth" | "get" | "set" | "del" | "exists" | "mget" | "mset" | "keys"
+                | "scan" | "info",
+                _,
+            ) => wrong_arity(),
+            _ => Ok(Value::Error(format!("ERR unknown command '{}'", command))),
+        };
 
-                    send_response(&mut writer, resp)?;
+        reply.unwrap_or_else(|err| match err {
+            Error::Replication(message) => Value::Error(format!("READONLY {}", message)),
+            Error::PermissionDenied(message) => Value::Error(format!("NOPERM {}", message)),
+            Error::NotLeader(Some(leader)) => {
+                Value::Error(format!("ERR not the cluster leader, try {}", leader))
+            }
+            err => Value::Error(format!("ERR {}", err)),
+        })
+    }
+
+    /// Answers `AUTH token` and `AUTH user token`, given their arguments,
+    /// with the user authenticated. The token must belong to the user.
+    fn resp_auth(&self, args: &[String], peer: &str) -> Result<Option<String>> {
+        let (user, token) = match args {
+            [user, token] => (Some(user), token),
+            _ => (None, &args[0]),
+        };
+        let found = self.authenticate(token, peer)?;
+        match (user, &found) {
+            (Some(user), Some(found)) if user != found => {
+                warn!("{} presented the token of {} as {}", peer, found, user);
+                Err(Error::Auth("invalid token".to_owned()))
+            }
+            _ => Ok(found),
+        }
+    }
+
+    fn resp_del(&self, keys: &[String]) -> Result<Value> {
+        let mut removed = 0;
+        for key in keys {
+            match self.remove(key.clone()) {
+                Ok(()) => removed += 1,
+                Err(Error::KeyNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Value::Integer(removed))
+    }
+
+    fn resp_exists(&self, keys: &[String]) -> Result<Value> {
+        let mut found = 0;
+        for key in keys {
+            if self.get(key.clone())?.is_some() {
+                found += 1;
+            }
+        }
+        Ok(Value::Integer(found))
+    }
+
+    /// Lists the keys matching `pattern` that `user` may read.
+    fn resp_keys(&self, pattern: &str, user: Option<&str>) -> Result<Value> {
+        let mut keys = Vec::new();
+        self.walk_readable_keys(user, |key| {
+            if resp::glob_match(pattern.as_bytes(), key.as_bytes()) {
+                keys.push(Value::Bulk(Some(key)));
+            }
+            true
+        })?;
+        Ok(Value::Array(keys))
+    }
+
+    /// Pages through the sorted keys, with the cursor being the position of
+    /// the next key. Keys added or removed between calls may shift the pages.
+    fn resp_scan(&self, cursor: &str, options: &[String], user: Option<&str>) -> Result<Value> {
+        let syntax_error = || Ok(Value::Error("ERR syntax error".to_owned()));
+        let cursor: usize = match cursor.parse() {
+            Ok(cursor) => cursor,
+            Err(_) => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+        };
+
+        let mut pattern = None;
+        let mut count = 10;
+        for option in options.chunks(2) {
+            match (option[0].to_ascii_lowercase().as_str(), option.get(1)) {
+                ("match", Some(value)) => pattern = Some(value.as_bytes()),
+                ("count", Some(value)) => match value.parse() {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return syntax_error(),
+                },
+                _ => return syntax_error(),
+            }
+        }
+
+        let end = cursor.saturating_add(count);
+        let mut position = 0;
+        let mut next = 0;
+        let mut page = Vec::new();
+        self.walk_readable_keys(user, |key| {
+            if position == end {
+                next = end;
+                return false;
+            }
+            if position >= cursor
+                && pattern.is_none_or(|pattern| resp::glob_match(pattern, key.as_bytes()))
+            {
+                page.push(Value::Bulk(Some(key)));
+            }
+            position += 1;
+            true
+        })?;
+
+        Ok(Value::Array(vec![
+            Value::Bulk(Some(next.to_string())),
+            Value::Array(page),
+        ]))
+    }
+
+    fn resp_info(&self, section: Option<&String>) -> Result<Value> {
+        let stats = self.engine().stats()?;
+        let role = if self.replica.load(Ordering::SeqCst) {
+            "slave"
+        } else {
+            "master"
+        };
+        let mode = if self.raft.is_some() {
+            "cluster"
+        } else {
+            "standalone"
+        };
+        let sections = [
+            (
+                "server",
+                format!(
+                    "kvs_version:{}\r\nredis_mode:{}\r\n",
+                    env!("CARGO_PKG_VERSION"),
+                    mode
+                ),
+            ),
+            ("replication", format!("role:{}\r\n", role)),
+            (
+                "storage",
+                format!(
+                    "engine:{}\r\ndisk_bytes:{}\r\nlive_bytes:{}\r\ndead_bytes:{}\r\n",
+                    stats.engine,
+                    stats.disk_bytes,
+                    stats.live_bytes.unwrap_or(0),
+                    stats.dead_bytes.unwrap_or(0)
+                ),
+            ),
+            ("keyspace", format!("db0:keys={}\r\n", stats.keys)),
+        ];
+
+        let wanted = section.map(|s| s.to_ascii_lowercase());
+        let all = wanted
+            .as_deref()
+            .is_none_or(|s| s == "all" || s == "default" || s == "everything");
+        let info: Vec<String> = sections
+            .iter()
+            .filter(|(name, _)| all || wanted.as_deref() == Some(*name))
+            .map(|(name, body)| {
+                format!("# {}{}\r\n{}", &name[..1].to_uppercase(), &name[1..], body)
+            })
+            .collect();
+        Ok(Value::Bulk(Some(info.join("\r\n"))))
+    }
+
+    /// Fails with `Error::PermissionDenied` unless `user` may access `key`,
+    /// or every key starting with it.
+    pub(crate) fn authorize(&self, user: Option<&str>, key: &str, access: Access) -> Result<()> {
+        match &self.acl {
+            Some(acl) => acl.check(user, key, access),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails unless `user` may make `request`.
+    ///
+    /// Backups need admin access: from the ACL when there is one, and
+    /// otherwise from the server's own token when clients authenticate.
+    /// Raft messages are only taken from the other cluster members, which
+    /// authenticate with that token too.
+    fn permit(&self, user: Option<&str>, request: &Request) -> Result<()> {
+        if let Request::Raft(_) = request {
+            if self.peer.is_none() || self.peer.as_deref() != user {
+                return Err(Error::PermissionDenied(format!(
+                    "{} is not a cluster member",
+                    user.unwrap_or("anonymous")
+                )));
+            }
+            return Ok(());
+        }
+        if let Request::Backup { .. } = request {
+            if self.acl.is_none() && self.requires_auth() && self.peer.as_deref() != user {
+                return Err(Error::PermissionDenied(format!(
+                    "{} has no admin access",
+                    user.unwrap_or("anonymous")
+                )));
+            }
+        }
+        let (key, access) = acl::required(request);
+        self.authorize(user, key, access)
+    }
+
+    /// Calls `f` with the keys `user` may read, in order, until it returns
+    /// false.
+    fn walk_readable_keys<F>(&self, user: Option<&str>, mut f: F) -> Result<()>
+    where
+        F: FnMut(String) -> bool,
+    {
+        let mut batches = KeyBatches::new("");
+        loop {
+            let keys = batches.next_keys(&mut *self.engine())?;
+            if keys.is_empty() {
+                return Ok(());
+            }
+            for key in keys {
+                let readable = self.acl.as_ref().is_none_or(|acl| {
+                    user.is_some_and(|user| acl.allows(user, &key, Access::Read))
+                });
+                if readable && !f(key) {
+                    return Ok(());
                 }
+            }
+        }
+    }
+
+    /// Whether clients must authenticate before anything else.
+    pub(crate) fn requires_auth(&self) -> bool {
+        self.auth.is_some()
+    }
+
+    /// Returns the user `token` authenticates, or `None` when the server
+    /// takes anyone. Failures are logged along with the `peer` they came from.
+    pub(crate) fn authenticate(&self, token: &str, peer: &str) -> Result<Option<String>> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => return Ok(None),
+        };
+        match auth.authenticate(token) {
+            Some(user) => {
+                debug!("{} authenticated as {}", peer, user);
+                Ok(Some(user.to_owned()))
+            }
+            None => {
+                warn!("failed authentication from {}", peer);
+                Err(Error::Auth("invalid token".to_owned()))
+            }
+        }
+    }
+
+    pub(crate) fn max_request_size(&self) -> usize {
+        self.limits.max_request_size
+    }
+
+    /// Counts a request without a connection of its own as in flight, unless
+    /// `Limits::max_connections` connections and requests are open already.
+    pub(crate) fn open(&self) -> Option<Open> {
+        self.connections
+            .open_within(None, self.limits.max_connections)
+    }
+
+    pub(crate) fn read_timeout(&self) -> Option<Duration> {
+        self.limits.read_timeout
+    }
+
+    pub(crate) fn engine(&self) -> MutexGuard<'_, T> {
+        self.engine.lock().expect("engine lock poisoned")
+    }
+
+    /// Fails if writes must go to the primary instead.
+    fn check_primary(&self) -> Result<()> {
+        if self.replica.load(Ordering::SeqCst) {
+            return Err(Error::Replication(format!(
+                "read-only replica of {}",
+                self.primary.as_deref().unwrap_or("unknown primary")
+            )));
+        }
+        Ok(())
+    }
+
+    fn promote(&self) -> Result<()> {
+        if !self.replica.swap(false, Ordering::SeqCst) {
+            return Err(Error::Replication("server is not a replica".to_owned()));
+        }
+        info!("promoted to primary");
+        Ok(())
+    }
+
+    fn raft(&self) -> Result<&Arc<Raft<T>>> {
+        self.raft
+            .as_ref()
+            .ok_or_else(|| Error::Raft("server is not a cluster member".to_owned()))
+    }
+
+    /// Runs `change` on the cluster and reports the resulting status.
+    fn cluster<F>(&self, change: F) -> Response
+    where
+        F: FnOnce(&Arc<Raft<T>>) -> Result<()>,
+    {
+        let status = self
+            .raft()
+            .and_then(|raft| change(raft).map(|_| raft.status()));
+        Response::from_result(status, Response::Cluster)
+    }
+
+    pub(crate) fn get(&self, key: String) -> Result<Option<String>> {
+        match &self.raft {
+            Some(raft) => raft.get(key),
+            None => self.engine().get(key),
+        }
+    }
+
+    pub(crate) fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_primary()?;
+        if let Some(raft) = &self.raft {
+            return raft.propose(Command::Set { key, value });
+        }
+
+        let mut engine = self.engine();
+        engine.set(key.clone(), value.clone())?;
+        self.feed.publish(Change::Set { key, value });
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, key: String) -> Result<()> {
+        self.check_primary()?;
+        if let Some(raft) = &self.raft {
+            return raft.propose(Command::Remove { key });
+        }
+
+        let mut engine = self.engine();
+        engine.remove(key.clone())?;
+        self.feed.publish(Change::Remove { key });
+        Ok(())
+    }
+
+    /// Sets `key`, or removes it if `value` is `None`, provided `condition`
+    /// holds for its current value. Returns whether the write happened.
+    pub(crate) fn write_if<F>(
+        &self,
+        key: String,
+        value: Option<String>,
+        condition: F,
+    ) -> Result<bool>
+    where
+        F: FnOnce(Option<&str>) -> bool,
+    {
+        self.check_primary()?;
+        if self.raft.is_some() {
+            return Err(Error::Raft(
+                "conditional writes are not supported in cluster mode".to_owned(),
+            ));
+        }
+
+        let mut engine = self.engine();
+        let current = engine.get(key.clone())?;
+        if value.is_none() && current.is_none() {
+            return Err(Error::KeyNotFound);
+        }
+        if !condition(current.as_deref()) {
+            return Ok(false);
+        }
+
+        match value {
+            Some(value) => {
+                engine.set(key.clone(), value.clone())?;
+                self.feed.publish(Change::Set { key, value });
+            }
+            None => {
+                engine.remove(key.clone())?;
+                self.feed.publish(Change::Remove { key });
+            }
+        }
+        Ok(true)
+    }
+
+    fn scan<C: Connection>(&self, prefix: &str, conn: &mut C) -> Result<()> {
+        let mut batches = KeyBatches::new(prefix);
+        loop {
+            // The engine stays unlocked while a batch goes out.
+            let entries = match batches.next_entries(&mut *self.engine())? {
+                Some(entries) => entries,
+                None => break,
             };
+            for (key, value) in entries {
+                conn.write(&Response::Entry { key, value })?;
+            }
         }
 
+        conn.send(&Response::End)?;
         Ok(())
     }
+
+    fn backup(&self, name: &str) -> Result<()> {
+        let path = backup::target(self.backup_dir.as_deref(), name)?;
+        let manifest = self
+            .manifest
+            .as_ref()
+            .ok_or_else(|| Error::Backup("server has no manifest".to_owned()))?;
+
+        info!("backing up to {}", path.display());
+        backup(&mut *self.engine(), manifest, &path)
+    }
+
+    /// Announces the feed's position, replays the backlog after `after`, then
+    /// streams new changes.
+    fn watch<C: Connection>(
+        &self,
+        prefix: String,
+        after: Option<Position>,
+        conn: &mut C,
+    ) -> Result<()> {
+        let (backlog, events) = match self.feed.subscribe(prefix, after) {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                conn.send(&Response::Err(err.into()))?;
+                return Ok(());
+            }
+        };
+
+        conn.send(&Response::Watching(events.position()))?;
+        for event in backlog {
+            conn.send(&Response::Event(event))?;
+        }
+        self.forward(events, conn)
+    }
+
+    fn replicate<C: Connection>(&self, resume: Option<Position>, conn: &mut C) -> Result<()> {
+        let resumed =
+            resume.and_then(|position| self.feed.subscribe(String::new(), Some(position)).ok());
+
+        let events = match resumed {
+            Some((backlog, events)) => {
+                info!("replica resumed, replaying {} changes", backlog.len());
+                for event in backlog {
+                    conn.send(&Response::Event(event))?;
+                }
+                events
+            }
+            None => {
+                // Subscribe before reading any key, so that every change the
+                // snapshot misses arrives on the subscription.
+                let (_, events) = self.feed.subscribe(String::new(), None)?;
+                let position = events.position();
+
+                info!("sending snapshot to replica");
+                conn.send(&Response::SnapshotStart)?;
+                // Entries go out in key order, which the replica relies on to
+                // find its stale keys.
+                let mut batches = KeyBatches::new("");
+                let mut keys = 0;
+                loop {
+                    // The engine stays unlocked while a batch goes out.
+                    let entries = match batches.next_entries(&mut *self.engine())? {
+                        Some(entries) => entries,
+                        None => break,
+                    };
+                    keys += entries.len();
+                    for (key, value) in entries {
+                        conn.write(&Response::Entry { key, value })?;
+                    }
+                }
+                conn.send(&Response::SnapshotEnd(position))?;
+                info!("sent snapshot of {} keys to replica", keys);
+                events
+            }
+        };
+
+        self.forward(events, conn)
+    }
+
+    /// Sends every event from `events` until the client goes away, with a
+    /// heartbeat whenever the feed stays quiet. A client that falls too far
+    /// behind is sent the error and dropped.
+    fn forward<C: Connection>(&self, events: Subscription, conn: &mut C) -> Result<()> {
+        let mut last_sent = Instant::now();
+        while !self.shutdown.is_shutdown() {
+            match events.recv_timeout(SHUTDOWN_POLL) {
+                Ok(Some(event)) => conn.send(&Response::Event(event))?,
+                Ok(None) if last_sent.elapsed() >= WATCH_HEARTBEAT => {
+                    let last_seq = self.feed.last_seq();
+                    conn.send(&Response::Heartbeat { last_seq })?
+                }
+                Ok(None) => continue,
+                Err(err) => return conn.send(&Response::Err(err.into())),
+            }
+            last_sent = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// Calls `Engine::maintain` every `MAINTENANCE_INTERVAL` until shut down.
+fn maintain<T: Engine>(engine: &Mutex<T>, shutdown: &ShutdownHandle) {
+    let mut last = Instant::now();
+    while !shutdown.is_shutdown() {
+        thread::sleep(SHUTDOWN_POLL);
+        if last.elapsed() < MAINTENANCE_INTERVAL {
+            continue;
+        }
+        if let Err(err) = engine.lock().expect("engine lock poisoned").maintain() {
+            error!("engine maintenance failed: {}", err);
+        }
+        last = Instant::now();
+    }
+}
+
+pub(crate) fn too_many_clients() -> Error {
+    Error::Limit("too many clients".to_owned())
 }
 
-fn send_response<W, S>(writer: &mut W, value: S) -> std::io::Result<()>
-where
-    W: std::io::Write,
-    S: Serialize,
-{
-    let mut writer = writer;
-    serde_json::to_writer(&mut writer, &value)?;
-    writer.flush()
+pub(crate) fn authentication_required() -> Error {
+    Error::Auth("authentication required".to_owned())
 }
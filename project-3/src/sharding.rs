@@ -0,0 +1,253 @@
+use crate::{Addr, Client, Connector, Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+
+/// Virtual nodes placed on the ring for every server by default.
+pub const DEFAULT_VNODES: usize = 160;
+/// Keys `rebalance` holds before copying them to their new owners.
+const REBALANCE_BATCH: usize = 1024;
+
+/// Consistent-hash ring mapping keys to server addresses.
+///
+/// Each server is hashed onto the ring `vnodes` times and owns the keys
+/// hashing up to each of its points, so adding or removing a server only
+/// moves the keys next to its points.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    vnodes: usize,
+    points: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    pub fn new(vnodes: usize) -> Self {
+        HashRing {
+            vnodes,
+            points: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_nodes<S: AsRef<str>>(vnodes: usize, nodes: &[S]) -> Self {
+        let mut ring = HashRing::new(vnodes);
+        for node in nodes {
+            ring.add(node.as_ref());
+        }
+        ring
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for vnode in 0..self.vnodes {
+            let point = ring_hash(format!("{}#{}", node, vnode).as_bytes());
+            self.points.insert(point, node.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.points.retain(|_, owner| owner != node);
+    }
+
+    /// The server owning `key`, or `None` if the ring is empty.
+    pub fn node_for(&self, key: &str) -> Option<&str> {
+        let hash = ring_hash(key.as_bytes());
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+    }
+
+    pub fn nodes(&self) -> Vec<&str> {
+        let mut nodes: Vec<&str> = self.points.values().map(String::as_str).collect();
+        nodes.sort_unstable();
+        nodes.dedup();
+        nodes
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust
+/// releases, for hashes that are kept or compared between runs.
+pub(crate) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// FNV-1a, so every client places keys identically.
+///
+/// FNV-1a barely mixes the last bytes, which are all that differ between the
+/// virtual nodes of one server, so the result goes through MurmurHash3's
+/// finalizer.
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut fnv = Fnv1a::default();
+    fnv.write(bytes);
+    let mut hash = fnv.finish();
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Spreads keys over several servers with the same API as `Client`.
+pub struct ShardedClient {
+    ring: HashRing,
+    clients: HashMap<String, Client>,
+    connector: Connector,
+}
+
+impl ShardedClient {
+    pub fn connect<S: AsRef<str>>(nodes: &[S]) -> Result<Self> {
+        ShardedClient::with_vnodes(nodes, DEFAULT_VNODES)
+    }
+
+    pub fn with_vnodes<S: AsRef<str>>(nodes: &[S], vnodes: usize) -> Result<Self> {
+        ShardedClient::with_connector(nodes, vnodes, Connector::new())
+    }
+
+    /// Connects to every server, and to those added later, through `connector`.
+    pub fn with_connector<S: AsRef<str>>(
+        nodes: &[S],
+        vnodes: usize,
+        connector: Connector,
+    ) -> Result<Self> {
+        let mut client = ShardedClient {
+            ring: HashRing::new(vnodes),
+            clients: HashMap::new(),
+            connector,
+        };
+        for node in nodes {
+            client.add_node(node.as_ref())?;
+        }
+        Ok(client)
+    }
+
+    /// Starts routing keys to `node`. Keys it now owns stay where they were
+    /// until `rebalance` moves them.
+    pub fn add_node(&mut self, node: &str) -> Result<()> {
+        let client = self.connector.connect(&Addr::Tcp(node.to_owned()))?;
+        self.clients.insert(node.to_owned(), client);
+        self.ring.add(node);
+        Ok(())
+    }
+
+    /// Stops routing keys to `node`, without moving the keys it holds.
+    pub fn remove_node(&mut self, node: &str) {
+        self.ring.remove(node);
+        self.clients.remove(node);
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.client_for(&key)?.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.client_for(&key)?.set(key, value)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.client_for(&key)?.remove(key)
+    }
+
+    fn client_for(&mut self, key: &str) -> Result<&mut Client> {
+        let node = self
+            .ring
+            .node_for(key)
+            .ok_or_else(|| Error::Sharding("no servers to route to".to_owned()))?;
+        self.clients
+            .get_mut(node)
+            .ok_or_else(|| Error::Sharding(format!("no connection to {}", node)))
+    }
+}
+
+/// Keys examined and moved by `rebalance`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RebalanceReport {
+    pub scanned: u64,
+    pub moved: u64,
+}
+
+/// Moves every key whose owner differs between the ring over `from` and the
+/// ring over `to`, copying it to the new owner before removing the old copy.
+/// Keys move in batches during the scan, so an error keeps the batches
+/// moved before it.
+///
+/// Servers only in `from` are emptied; run this with both rings' servers up
+/// and with writers already using the `to` ring, so no write lands on an old
+/// owner after its keys were scanned. Every server is reached through
+/// `connector`.
+pub fn rebalance<S: AsRef<str>>(
+    from: &[S],
+    to: &[S],
+    vnodes: usize,
+    connector: &Connector,
+) -> Result<RebalanceReport> {
+    let old_ring = HashRing::with_nodes(vnodes, from);
+    let mut new_client = ShardedClient::with_connector(to, vnodes, connector.clone())?;
+    if new_client.ring.nodes().is_empty() {
+        return Err(Error::Sharding(
+            "cannot rebalance onto no servers".to_owned(),
+        ));
+    }
+
+    let mut report = RebalanceReport::default();
+    for node in old_ring.nodes() {
+        // The scan keeps its connection busy, so removals go over another.
+        let mut scanner = connector.connect(&Addr::Tcp(node.to_owned()))?;
+        let mut source = connector.connect(&Addr::Tcp(node.to_owned()))?;
+        let mut moving = Vec::with_capacity(REBALANCE_BATCH);
+        for entry in scanner.scan(String::new())? {
+            let (key, value) = entry?;
+            report.scanned += 1;
+            if new_client.ring.node_for(&key) != Some(node) {
+                moving.push((key, value));
+            }
+            if moving.len() == REBALANCE_BATCH {
+                move_keys(&mut moving, &mut new_client, &mut source, &mut report)?;
+            }
+        }
+        move_keys(&mut moving, &mut new_client, &mut source, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Copies the pairs in `moving` to their new owners, then removes them from
+/// `source`, leaving `moving` empty.
+fn move_keys(
+    moving: &mut Vec<(String, String)>,
+    new_client: &mut ShardedClient,
+    source: &mut Client,
+    report: &mut RebalanceReport,
+) -> Result<()> {
+    if moving.is_empty() {
+        return Ok(());
+    }
+    for (key, value) in moving.iter() {
+        new_client.set(key.clone(), value.clone())?;
+    }
+    for (key, _) in moving.drain(..) {
+        source.remove(key)?;
+        report.moved += 1;
+    }
+    debug!(
+        "rebalance: {} keys scanned, {} moved",
+        report.scanned, report.moved
+    );
+    Ok(())
+}
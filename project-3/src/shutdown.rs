@@ -0,0 +1,127 @@
+use crate::stream::Stream;
+use crate::Result;
+use std::{
+    collections::HashMap,
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Asks a running `Server` or `AsyncServer` to stop.
+///
+/// The server stops accepting connections, finishes the requests it is
+/// answering, syncs the engine and returns from `run`.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Shuts down on SIGINT and SIGTERM instead of letting them kill the process.
+    pub fn on_signals(&self) -> Result<()> {
+        for &signal in &[signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            signal_hook::flag::register(signal, Arc::clone(&self.requested))?;
+        }
+        Ok(())
+    }
+}
+
+/// The connections and HTTP requests a server is serving, so that shutdown
+/// can wait for them.
+#[derive(Default)]
+pub(crate) struct Connections {
+    open: Mutex<HashMap<u64, Option<Stream>>>,
+    next_id: AtomicU64,
+    closed: Condvar,
+}
+
+impl Connections {
+    /// Tracks a connection, or a request without one, until the guard drops.
+    pub fn open(self: &Arc<Self>, stream: Option<Stream>) -> Open {
+        self.open_within(stream, None)
+            .expect("connections without a limit are always opened")
+    }
+
+    /// Tracks a connection, or a request without one, unless `max` of
+    /// either are open already.
+    pub fn open_within(
+        self: &Arc<Self>,
+        stream: Option<Stream>,
+        max: Option<usize>,
+    ) -> Option<Open> {
+        let mut open = self.lock();
+        if max.is_some_and(|max| open.len() >= max) {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        open.insert(id, stream);
+        Some(Open {
+            connections: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Counts the open connections, leaving out requests without one.
+    pub fn streams(&self) -> usize {
+        self.lock()
+            .values()
+            .filter(|stream| stream.is_some())
+            .count()
+    }
+
+    /// Waits up to `timeout` for the connections to finish their current
+    /// request and close, then cuts off the rest. Returns how many were cut.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut open = self.lock();
+
+        // Blocked reads see the end of the stream, while responses still go out.
+        for stream in open.values().flatten() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !open.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            open = self
+                .closed
+                .wait_timeout(open, deadline - now)
+                .expect("connections lock poisoned")
+                .0;
+        }
+
+        for stream in open.values().flatten() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        open.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Option<Stream>>> {
+        self.open.lock().expect("connections lock poisoned")
+    }
+}
+
+/// Keeps a connection counted as open.
+pub(crate) struct Open {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        self.connections.lock().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
@@ -0,0 +1,33 @@
+use serde::{Deserialize, Serialize};
+
+/// Size and bookkeeping figures reported by `Engine::stats`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub engine: String,
+    pub keys: u64,
+    /// Total size of the engine files.
+    pub disk_bytes: u64,
+    /// Bytes of records that are still reachable, if the engine tracks them.
+    pub live_bytes: Option<u64>,
+    /// Bytes of overwritten or removed records awaiting compaction.
+    pub dead_bytes: Option<u64>,
+    pub generations: Vec<GenerationStats>,
+    pub compaction: CompactionStats,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct GenerationStats {
+    pub gen: u64,
+    pub bytes: u64,
+}
+
+/// Compactions since the engine was opened; the counts start over when it
+/// is opened again.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct CompactionStats {
+    pub count: u64,
+    pub total_duration_ms: u64,
+    pub last_duration_ms: Option<u64>,
+    /// Seconds since the Unix epoch when the last compaction finished.
+    pub last_finished_at: Option<u64>,
+}
@@ -0,0 +1,264 @@
+use crate::tls::TlsStream;
+use std::{
+    convert::Infallible,
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+};
+
+/// Where a server listens or a client connects: `HOST:PORT`, or
+/// `unix:PATH` for a Unix domain socket. The latter parse everywhere, but
+/// only connect and listen on Unix platforms.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Addr {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Addr {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("unix:") {
+            Some(path) => Addr::Unix(PathBuf::from(path)),
+            None => Addr::Tcp(s.to_owned()),
+        })
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Addr::Tcp(addr) => write!(f, "{}", addr),
+            Addr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection between a client and a server, like `TcpStream` but
+/// possibly encrypted or local. Both halves can be used at once through
+/// shared references.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    #[cfg(unix)]
+    pub fn connect_unix(path: &Path) -> io::Result<Stream> {
+        UnixStream::connect(path).map(Stream::Unix)
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect_unix(_path: &Path) -> io::Result<Stream> {
+        Err(unix_unsupported())
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Tls(stream) => stream.try_clone().map(Stream::Tls),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self.socket() {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// The address of the other end, for logs.
+    pub fn peer_name(&self) -> String {
+        match self.socket() {
+            Socket::Tcp(stream) => stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "unknown peer".to_owned()),
+            #[cfg(unix)]
+            // Clients rarely bind their end of a Unix socket to a path.
+            Socket::Unix(_) => "local client".to_owned(),
+        }
+    }
+
+    fn socket(&self) -> Socket<'_> {
+        match self {
+            Stream::Tcp(stream) => Socket::Tcp(stream),
+            Stream::Tls(stream) => Socket::Tcp(stream.tcp()),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Socket::Unix(stream),
+        }
+    }
+}
+
+/// The socket under a stream.
+enum Socket<'a> {
+    Tcp(&'a TcpStream),
+    #[cfg(unix)]
+    Unix(&'a UnixStream),
+}
+
+/// A bound socket accepting connections.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    /// Removes its socket file when dropped.
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn tcp<A: ToSocketAddrs>(addr: A) -> io::Result<Listener> {
+        TcpListener::bind(addr).map(Listener::Tcp)
+    }
+
+    /// Listens on a socket file at `path`, replacing one left behind by a
+    /// server that is no longer running. Anything else at `path` is left
+    /// alone and fails with `AddrInUse`.
+    #[cfg(unix)]
+    pub fn unix(path: &Path) -> io::Result<Listener> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata)
+                if metadata.file_type().is_socket() && UnixStream::connect(path).is_err() =>
+            {
+                fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} already exists", path.display()),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok(Listener::Unix(listener, path.to_owned()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn unix(_path: &Path) -> io::Result<Listener> {
+        Err(unix_unsupported())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => {
+                listener.accept().map(|(stream, _)| Stream::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&mut &*stream).read(buf),
+            Stream::Tls(stream) => (&mut &*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&mut &*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&mut &*stream).write(buf),
+            Stream::Tls(stream) => (&mut &*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&mut &*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&mut &*stream).flush(),
+            Stream::Tls(stream) => (&mut &*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&mut &*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not supported on this platform",
+    )
+}
@@ -0,0 +1,221 @@
+use crate::stream::Stream;
+use crate::{Error, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Bytes read from the socket at once, which decrypt to no more than
+/// rustls buffers.
+const TLS_READ: usize = 16 * 1024;
+
+/// The certificate a server presents to its clients.
+#[derive(Clone)]
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    /// Presents the PEM certificate chain in `cert` with the private key in
+    /// `key`. With `client_ca`, clients must present a certificate signed by
+    /// one of its certificates too (mutual TLS).
+    pub fn load(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<ServerTls> {
+        let builder = match client_ca {
+            Some(path) => {
+                let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(path)?))
+                    .build()
+                    .map_err(|err| Error::Tls(err.to_string()))?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        Ok(ServerTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Starts serving TLS on an accepted connection. The handshake happens
+    /// on the first read.
+    pub(crate) fn accept(&self, tcp: TcpStream) -> Result<Stream> {
+        let session = ServerConnection::new(Arc::clone(&self.config))
+            .map_err(|err| Error::Tls(err.to_string()))?;
+        Ok(Stream::Tls(TlsStream::new(tcp, session.into())))
+    }
+}
+
+/// How a client checks the servers it connects to over TLS, and the
+/// certificate it presents to them.
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+}
+
+impl ClientTls {
+    /// Only trusts servers whose certificate is signed by one of the PEM
+    /// certificates in `ca`. With `identity`, a certificate chain and its
+    /// private key, the client presents that certificate (mutual TLS).
+    pub fn load(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<ClientTls> {
+        let builder = ClientConfig::builder().with_root_certificates(load_roots(ca)?);
+        let config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|err| Error::Tls(err.to_string()))?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(ClientTls {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Starts TLS on a connection to `addr`, whose host the server
+    /// certificate must be issued for, and completes the handshake.
+    pub(crate) fn connect(&self, tcp: TcpStream, addr: &str) -> Result<Stream> {
+        let name = ServerName::try_from(host(addr).to_owned())
+            .map_err(|_| Error::Tls(format!("{} has no valid host name", addr)))?;
+        let mut session: Connection = ClientConnection::new(Arc::clone(&self.config), name)
+            .map_err(|err| Error::Tls(err.to_string()))?
+            .into();
+        while session.is_handshaking() {
+            match session.complete_io(&mut &tcp) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                    return Err(Error::Tls(err.to_string()))
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(Stream::Tls(TlsStream::new(tcp, session)))
+    }
+}
+
+/// A TLS session over a socket, which its clones share so that one thread
+/// can read while another writes. The session is only locked while it
+/// processes bytes, never while waiting on the socket.
+pub(crate) struct TlsStream {
+    tcp: TcpStream,
+    session: Arc<Mutex<Connection>>,
+}
+
+impl TlsStream {
+    fn new(tcp: TcpStream, session: Connection) -> Self {
+        TlsStream {
+            tcp,
+            session: Arc::new(Mutex::new(session)),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            tcp: self.tcp.try_clone()?,
+            session: Arc::clone(&self.session),
+        })
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.session.lock().expect("TLS session lock poisoned")
+    }
+
+    /// Sends whatever the session has to send, such as handshake messages,
+    /// alerts and encrypted writes.
+    fn send_tls(&self, session: &mut Connection) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(&mut &self.tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut session = self.lock();
+            match session.reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                // Both protocols delimit their messages, so a peer closing
+                // without a close_notify cannot truncate one unnoticed.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                result => return result,
+            }
+            self.send_tls(&mut session)?;
+            drop(session);
+
+            let mut incoming = [0; TLS_READ];
+            let n = (&self.tcp).read(&mut incoming)?;
+            let mut session = self.lock();
+            let mut rest = &incoming[..n];
+            loop {
+                session.read_tls(&mut rest)?;
+                if let Err(err) = session.process_new_packets() {
+                    // Tell the peer why before giving up.
+                    let _ = self.send_tls(&mut session);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+                if rest.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.lock();
+        let n = session.writer().write(buf)?;
+        self.send_tls(&mut session)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.lock();
+        session.writer().flush()?;
+        self.send_tls(&mut session)
+    }
+}
+
+/// The host part of a `HOST:PORT` address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| Error::Tls(format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|err| Error::Tls(format!("{}: {}", path.display(), err)))?;
+    }
+    Ok(roots)
+}
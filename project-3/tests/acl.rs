@@ -0,0 +1,129 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use project_3::{Access, Acl, Auth, Client, Error, Protocol, Result};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+fn start_server(protocol: Protocol) -> Result<TestServer> {
+    let auth = Auth::default()
+        .user("alice", "alice-secret")
+        .user("bob", "bob-secret")
+        .user("ops", "ops-secret");
+    let acl = Acl::default()
+        .allow("alice", "a/", Access::Write)
+        .allow("bob", "b/", Access::Write)
+        .allow("bob", "a/", Access::Read)
+        .allow("ops", "", Access::Admin);
+    common::start_server(move |server| server.protocol(protocol).auth(auth).acl(acl))
+}
+
+fn denied<T: std::fmt::Debug>(result: Result<T>) {
+    match result {
+        Err(Error::PermissionDenied(_)) => {}
+        other => panic!("expected permission denied, got {:?}", other),
+    }
+}
+
+// Users only reach the prefixes granted to them
+#[test]
+fn prefix_access() -> Result<()> {
+    let server = start_server(Protocol::Kvs)?;
+    let addr = server.addr.as_str();
+
+    let mut alice = Client::connect_with_auth(addr, "alice-secret".to_owned())?;
+    let mut bob = Client::connect_with_auth(addr, "bob-secret".to_owned())?;
+    let mut ops = Client::connect_with_auth(addr, "ops-secret".to_owned())?;
+
+    alice.set("a/1".to_owned(), "alice".to_owned())?;
+    bob.set("b/1".to_owned(), "bob".to_owned())?;
+    assert_eq!(bob.get("a/1".to_owned())?, Some("alice".to_owned()));
+    denied(bob.remove("a/1".to_owned()));
+    denied(alice.get("b/1".to_owned()));
+    denied(alice.remove("b/1".to_owned()));
+    denied(alice.scan(String::new())?.next().expect("an answer"));
+    assert_eq!(alice.scan("a/".to_owned())?.count(), 1);
+
+    denied(alice.stats());
+    denied(alice.compact());
+    ops.stats()?;
+    ops.remove("b/1".to_owned())?;
+    Ok(())
+}
+
+// redis clients get NOPERM, and only see the keys they may read
+#[test]
+fn resp_acl() -> Result<()> {
+    let server = start_server(Protocol::Resp)?;
+    let addr = server.addr.as_str();
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut command = |command: &str| -> Result<String> {
+        stream.write_all(command.as_bytes())?;
+        let mut reply = String::new();
+        reader.read_line(&mut reply)?;
+        Ok(reply)
+    };
+    assert_eq!(
+        command("*3\r\n$4\r\nAUTH\r\n$5\r\nalice\r\n$12\r\nalice-secret\r\n")?,
+        "+OK\r\n"
+    );
+    assert_eq!(
+        command("*3\r\n$3\r\nSET\r\n$3\r\na/1\r\n$1\r\n1\r\n")?,
+        "+OK\r\n"
+    );
+    assert!(command("*3\r\n$3\r\nSET\r\n$3\r\nb/1\r\n$1\r\n1\r\n")?.starts_with("-NOPERM "));
+    assert!(command("*3\r\n$4\r\nMGET\r\n$3\r\na/1\r\n$3\r\nb/1\r\n")?.starts_with("-NOPERM "));
+    assert!(command("*1\r\n$4\r\nINFO\r\n")?.starts_with("-NOPERM "));
+    assert_eq!(command("*2\r\n$4\r\nKEYS\r\n$1\r\n*\r\n")?, "*1\r\n");
+    Ok(())
+}
+
+// Rules are read from a file, where a missing prefix means every key
+#[test]
+fn load_acl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("acl");
+    fs::write(
+        &path,
+        "# team rules\nalice write a/\nbob read\n\nops admin\n",
+    )?;
+    let acl = Acl::load(&path)?;
+    assert!(acl.allows("alice", "a/1", Access::Read));
+    assert!(acl.allows("alice", "a/1", Access::Write));
+    assert!(!acl.allows("alice", "b/1", Access::Read));
+    assert!(!acl.allows("alice", "a/1", Access::Admin));
+    assert!(acl.allows("bob", "b/1", Access::Read));
+    assert!(!acl.allows("bob", "b/1", Access::Write));
+    assert!(acl.allows("ops", "", Access::Admin));
+    assert!(!acl.allows("mallory", "a/1", Access::Read));
+
+    fs::write(&path, "alice everything a/\n")?;
+    assert!(Acl::load(&path).is_err());
+    fs::write(&path, "alice\n")?;
+    assert!(Acl::load(&path).is_err());
+    Ok(())
+}
+
+// Rules name users from a token file, so the server refuses them without one
+#[test]
+fn acl_requires_token_file() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("acl");
+    fs::write(&path, "alice write a/\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &common::free_addr(), "--acl"])
+        .arg(&path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--token-file"));
+}
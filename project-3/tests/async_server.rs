@@ -0,0 +1,70 @@
+use project_3::{AsyncClient, Client, Error, Result};
+
+mod common;
+
+// Many connections share the runtime's few threads
+#[tokio::test]
+async fn async_clients() -> Result<()> {
+    let server = common::start_async_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    let mut tasks = Vec::new();
+    for i in 0..200 {
+        let addr = server.addr.clone();
+        tasks.push(tokio::spawn(async move {
+            let mut client = AsyncClient::connect(addr).await?;
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+            client.get(format!("key{}", i)).await
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap()?, Some(format!("value{}", i)));
+    }
+
+    let mut client = AsyncClient::connect(addr).await?;
+    assert_eq!(client.scan("key1".to_owned()).await?.len(), 111);
+    client.remove("key0".to_owned()).await?;
+    assert_eq!(client.get("key0".to_owned()).await?, None);
+    assert!(client.remove("key0".to_owned()).await.is_err());
+    assert_eq!(client.stats().await?.keys, 199);
+    Ok(())
+}
+
+#[test]
+fn blocking_client_against_async_server() -> Result<()> {
+    let server = common::start_async_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    let mut client = Client::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    // Streams are left to the threaded server
+    match Client::connect(addr)?.watch(String::new(), None) {
+        Err(Error::Protocol(_)) => {}
+        other => panic!("unexpected watch result {:?}", other.is_ok()),
+    }
+    Ok(())
+}
+
+// Responses answered as they finish still reach the right callers
+#[test]
+fn pipelining_against_async_server() -> Result<()> {
+    let server = common::start_async_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    let mut client = Client::connect(addr)?;
+    let pairs: Vec<_> = (0..5000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs)?;
+
+    let keys: Vec<_> = (0..5000).map(|i| format!("key{}", i)).collect();
+    let values = client.get_many(keys)?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use assert_cmd::prelude::*;
+use predicates::str::contains;
+use project_3::{Auth, Client, Error, Protocol, Result};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+fn start_server(protocol: Protocol) -> Result<TestServer> {
+    let auth = Auth::default()
+        .user("alice", "alice-secret")
+        .user("bob", "bob-secret");
+    common::start_server(move |server| server.protocol(protocol).auth(auth))
+}
+
+// Requests are refused until the client authenticates
+#[test]
+fn requires_token() -> Result<()> {
+    let server = start_server(Protocol::Kvs)?;
+    let addr = server.addr.as_str();
+
+    let mut anonymous = Client::connect(addr)?;
+    match anonymous.set("key".to_owned(), "value".to_owned()) {
+        Err(Error::Auth(_)) => {}
+        other => panic!("expected an authentication error, got {:?}", other),
+    }
+    anonymous.auth("bob-secret".to_owned())?;
+    anonymous.set("key".to_owned(), "value".to_owned())?;
+
+    let mut json = Client::connect_json(addr)?;
+    json.auth("alice-secret".to_owned())?;
+    assert_eq!(json.get("key".to_owned())?, Some("value".to_owned()));
+
+    let mut client = Client::connect_with_auth(addr, "alice-secret".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A wrong token is refused and the connection closed
+#[test]
+fn wrong_token() -> Result<()> {
+    let server = start_server(Protocol::Kvs)?;
+    let addr = server.addr.as_str();
+
+    match Client::connect_with_auth(addr, "guess".to_owned()) {
+        Err(Error::Auth(_)) => {}
+        Err(err) => panic!("expected an authentication error, got {:?}", err),
+        Ok(_) => panic!("authenticated with a wrong token"),
+    }
+
+    let mut client = Client::connect(addr)?;
+    assert!(client.auth("guess".to_owned()).is_err());
+    assert!(client.get("key".to_owned()).is_err());
+    Ok(())
+}
+
+// redis clients authenticate with AUTH, with or without a user name
+#[test]
+fn resp_auth() -> Result<()> {
+    let server = start_server(Protocol::Resp)?;
+    let addr = server.addr.as_str();
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut command = |command: &str| -> Result<String> {
+        stream.write_all(command.as_bytes())?;
+        let mut reply = String::new();
+        reader.read_line(&mut reply)?;
+        Ok(reply)
+    };
+    assert_eq!(
+        command("*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?,
+        "-NOAUTH Authentication required.\r\n"
+    );
+    assert_eq!(
+        command("*2\r\n$4\r\nAUTH\r\n$10\r\nbob-secret\r\n")?,
+        "+OK\r\n"
+    );
+    assert_eq!(command("*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")?, "$-1\r\n");
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*3\r\n$4\r\nAUTH\r\n$5\r\nalice\r\n$10\r\nbob-secret\r\n")?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    assert_eq!(
+        reply,
+        "-WRONGPASS invalid username-password pair or user is disabled.\r\n"
+    );
+    Ok(())
+}
+
+// Tokens are read from a file, and kvs-client presents --token
+#[test]
+fn token_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("tokens");
+    fs::write(
+        &path,
+        "# team tokens\nalice alice-secret\n\nbob bob-secret\n",
+    )?;
+    let auth = Auth::load(&path)?;
+    assert_eq!(auth.authenticate("bob-secret"), Some("bob"));
+    assert_eq!(auth.authenticate("bob"), None);
+
+    let server = common::start_server(move |server| server.auth(auth))?;
+    let addr = server.addr.as_str();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "value", "--addr", addr])
+        .env_remove("KVS_TOKEN")
+        .assert()
+        .failure()
+        .stderr(contains("authentication"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "set",
+            "key",
+            "value",
+            "--addr",
+            addr,
+            "--token",
+            "alice-secret",
+        ])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr])
+        .env("KVS_TOKEN", "bob-secret")
+        .assert()
+        .success()
+        .stdout("value\n");
+
+    fs::write(&path, "alice\n")?;
+    match Auth::load(&path) {
+        Err(Error::Auth(_)) => {}
+        other => panic!("expected an authentication error, got {:?}", other),
+    }
+    Ok(())
+}
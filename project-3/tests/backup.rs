@@ -0,0 +1,157 @@
+use project_3::{
+    backup, restore, summarize, Auth, Client, Engine, EngineConfig, Error, Manifest, Registry,
+    Result, Store,
+};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+mod common;
+
+// Should produce a backup that restores to the state at backup time
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let registry = Registry::default();
+
+    for name in &["kvs", "sled"] {
+        let data_dir = TempDir::new().expect("unable to create temporary working directory");
+        let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+        let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+        let manifest = Manifest::new(name);
+
+        let mut engine = registry.open(name, &EngineConfig::new(data_dir.path()))?;
+        for key_id in 0..100 {
+            engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        engine.remove("key0".to_owned())?;
+        let expected = summarize(&mut engine)?;
+
+        backup(&mut engine, &manifest, backup_dir.path())?;
+
+        // Writes after the backup are not part of it
+        engine.set("key1".to_owned(), "changed".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("changed".to_owned()));
+        drop(engine);
+
+        let summary = restore(&registry, backup_dir.path(), restore_dir.path())?;
+        assert_eq!(summary, expected);
+        assert_eq!(Manifest::load(restore_dir.path())?, Some(manifest));
+
+        let mut engine = registry.open(name, &EngineConfig::new(restore_dir.path()))?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.get("key0".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn restore_into_non_empty_directory() -> Result<()> {
+    let registry = Registry::default();
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut engine = registry.open("kvs", &EngineConfig::new(data_dir.path()))?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    backup(&mut engine, &Manifest::new("kvs"), backup_dir.path())?;
+
+    std::fs::write(restore_dir.path().join("1.log"), "")?;
+    let res = restore(&registry, backup_dir.path(), restore_dir.path());
+    assert!(matches!(res, Err(Error::Backup(_))));
+
+    Ok(())
+}
+
+// A directory without a manifest is not a complete backup
+#[test]
+fn restore_incomplete_backup() {
+    let registry = Registry::default();
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let res = restore(&registry, backup_dir.path(), restore_dir.path());
+    assert!(matches!(res, Err(Error::Backup(_))));
+}
+
+// A backup of the kvs engine holds only live records, and leaves the logs
+// of the running store alone
+#[test]
+fn backup_is_compacted() -> Result<()> {
+    let data_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let logs = |dir: &Path| -> Result<Vec<_>> {
+        let mut logs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "log") {
+                logs.push((path.clone(), fs::metadata(path)?.len()));
+            }
+        }
+        Ok(logs)
+    };
+
+    let mut store = Store::open(data_dir.path())?;
+    for round in 0..100 {
+        store.set("key".to_owned(), format!("value{}", round))?;
+    }
+    let before = logs(data_dir.path())?;
+    backup(&mut store, &Manifest::new("kvs"), backup_dir.path())?;
+    assert_eq!(logs(data_dir.path())?, before);
+
+    let copied = logs(backup_dir.path())?;
+    assert_eq!(copied.len(), 1);
+    assert!(copied[0].1 < before.iter().map(|(_, len)| len).sum::<u64>() / 50);
+    assert_eq!(
+        Store::open(backup_dir.path())?.get("key".to_owned())?,
+        Some("value99".to_owned())
+    );
+    Ok(())
+}
+
+// Clients back up into the backup directory only, and need admin access
+#[test]
+fn backup_over_the_network() -> Result<()> {
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let auth = Auth::default()
+        .user("alice", "alice-secret")
+        .user("ops", "ops-secret");
+    let target = backup_dir.path().to_owned();
+    let server = common::start_server(move |server| {
+        server
+            .manifest(Manifest::new("kvs"))
+            .auth(auth)
+            .token("ops-secret".to_owned())
+            .backup_dir(target)
+    })?;
+    let addr = server.addr.as_str();
+
+    let mut alice = Client::connect_with_auth(addr, "alice-secret".to_owned())?;
+    assert!(matches!(
+        alice.backup("nightly".to_owned()),
+        Err(Error::PermissionDenied(_))
+    ));
+
+    let mut ops = Client::connect_with_auth(addr, "ops-secret".to_owned())?;
+    for outside in &["", "../nightly", "/tmp/nightly", "nightly/../.."] {
+        assert!(matches!(
+            ops.backup(outside.to_string()),
+            Err(Error::Backup(_))
+        ));
+    }
+    #[cfg(unix)]
+    {
+        let outside = TempDir::new().expect("unable to create temporary working directory");
+        std::os::unix::fs::symlink(outside.path(), backup_dir.path().join("link"))?;
+        for through_link in &["link", "link/nightly"] {
+            assert!(matches!(
+                ops.backup(through_link.to_string()),
+                Err(Error::Backup(_))
+            ));
+        }
+        assert_eq!(std::fs::read_dir(outside.path())?.count(), 0);
+    }
+    ops.backup("nightly".to_owned())?;
+    assert!(backup_dir.path().join("nightly").join("MANIFEST").exists());
+    Ok(())
+}
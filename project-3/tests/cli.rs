@@ -4,9 +4,11 @@ use std::fs::{self, File};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
+mod common;
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -212,6 +214,376 @@ fn cli_wrong_engine() {
     }
 }
 
+// sled locks its directory, so `--read-only` is refused for it
+#[test]
+fn cli_read_only_sled() {
+    let addr = common::free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--read-only", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("cannot be served --read-only"));
+}
+
+#[test]
+fn cli_compaction_paused_sled() {
+    let addr = common::free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--compaction-paused", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("cannot start --compaction-paused"));
+}
+
+#[test]
+fn cli_data_dir() {
+    let addr = common::free_addr();
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "sled", "--addr", &addr, "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(&addr);
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    let manifest = fs::read_to_string(data_dir.join("MANIFEST")).expect("manifest not written");
+    assert!(manifest.contains("sled"));
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+
+    // Data written by a newer format must be refused
+    fs::write(
+        data_dir.join("MANIFEST"),
+        manifest.replace("\"format_version\": 1", "\"format_version\": 99"),
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+fn cli_backup_restore(engine: &str) {
+    let addr = common::free_addr();
+    let addr = addr.as_str();
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let backup_dir = temp_dir.path().join("backups");
+    let restore_dir = temp_dir.path().join("restore");
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr, "--data-dir"])
+        .arg(&data_dir)
+        .arg("--backup-dir")
+        .arg(&backup_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "nightly", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // Backups stay inside the backup directory
+    for outside in &["../escape", "/tmp/escape"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", outside, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("inside the backup directory"));
+    }
+
+    // The server keeps serving after the backup
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("restore")
+        .arg(backup_dir.join("nightly"))
+        .arg("--data-dir")
+        .arg(&restore_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("restored 1 keys"));
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--data-dir"])
+        .arg(&restore_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_backup_restore_kvs_engine() {
+    cli_backup_restore("kvs");
+}
+
+#[test]
+fn cli_backup_restore_sled_engine() {
+    cli_backup_restore("sled");
+}
+
+#[test]
+fn cli_export_import() {
+    let addr = common::free_addr();
+    let addr = addr.as_str();
+    let temp_dir = TempDir::new().unwrap();
+    let fixture = temp_dir.path().join("fixture.csv");
+    fs::write(
+        &fixture,
+        "key,value\nuser:1,alice\nuser:2,\"bob, jr\"\nitem:1,box\n",
+    )
+    .unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(addr);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["import", "--format", "csv", "--addr", addr])
+        .arg(&fixture)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("import: 3 entries"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "user:2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("bob, jr\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["export", "--prefix", "user:", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            "{\"key\":\"user:1\",\"value\":\"alice\"}\n{\"key\":\"user:2\",\"value\":\"bob, jr\"}\n",
+        );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    // The same data can be read offline from the data directory
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args([
+            "export",
+            "--format",
+            "csv",
+            "--prefix",
+            "item:",
+            "--data-dir",
+        ])
+        .arg(temp_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key,value\nitem:1,box\n");
+}
+
+#[test]
+fn cli_stats() {
+    let addr = common::free_addr();
+    let addr = addr.as_str();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(addr);
+
+    for value in &["value1", "value2"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"engine\": \"kvs\""))
+        .stdout(contains("\"keys\": 1,"))
+        .stdout(contains("\"compaction\""));
+
+    for args in &[["compact", "--pause"], ["compact", "--resume"]] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"dead_bytes\": 0,"))
+        .stdout(contains("\"count\": 1,"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A replica should copy the primary's data, refuse writes and accept them once promoted.
+#[test]
+fn cli_replication() {
+    let primary_addr = common::free_addr();
+    let primary_addr = primary_addr.as_str();
+    let replica_addr = common::free_addr();
+    let replica_addr = replica_addr.as_str();
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let client = |args: &[&str], addr: &str| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&primary_dir)
+            .assert()
+    };
+
+    let mut primary = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", primary_addr])
+        .current_dir(&primary_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(primary_addr);
+    client(&["set", "key1", "value1"], primary_addr).success();
+
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", replica_addr])
+        .args(["--replica-of", primary_addr])
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(replica_addr);
+
+    client(&["set", "key2", "value2"], primary_addr).success();
+    client(&["rm", "key1"], primary_addr).success();
+    // The replica applies the removal some time after the primary answered
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while Instant::now() < deadline {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", replica_addr])
+            .output()
+            .unwrap();
+        if output.stdout == b"Key not found\n" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    client(&["get", "key2"], replica_addr)
+        .success()
+        .stdout("value2\n");
+    client(&["get", "key1"], replica_addr)
+        .success()
+        .stdout("Key not found\n");
+    client(&["set", "key3", "value3"], replica_addr)
+        .failure()
+        .stderr(contains("read-only replica"));
+    client(&["promote"], primary_addr)
+        .failure()
+        .stderr(contains("not a replica"));
+
+    primary.kill().expect("server exited before killed");
+    primary.wait().expect("failed to wait on server");
+
+    client(&["promote"], replica_addr).success();
+    client(&["set", "key3", "value3"], replica_addr).success();
+    client(&["get", "key3"], replica_addr)
+        .success()
+        .stdout("value3\n");
+
+    replica.kill().expect("server exited before killed");
+    replica.wait().expect("failed to wait on server");
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -0,0 +1,105 @@
+//! Servers for the integration tests, each on a port of its own.
+#![allow(dead_code)]
+
+use project_3::{AsyncServer, Result, Server, Store};
+use std::io::{self, Read};
+use std::net::{Shutdown, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// How long a server may take to start accepting connections.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+const SETTLE: Duration = Duration::from_millis(50);
+
+/// A running server and the directory of the store it serves.
+pub struct TestServer {
+    pub addr: String,
+    pub dir: TempDir,
+}
+
+/// Serves a new `Store` with the server `configure` returns, on a free port.
+pub fn start_server<F>(configure: F) -> Result<TestServer>
+where
+    F: FnOnce(Server<Store>) -> Server<Store> + Send + 'static,
+{
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(dir.path())?;
+    let addr = free_addr();
+    let listen = addr.clone();
+    thread::spawn(move || configure(Server::new(store)).run(listen));
+    wait_until_accepting(&addr);
+    Ok(TestServer { addr, dir })
+}
+
+/// Like `start_server`, with an `AsyncServer` on a runtime of its own.
+pub fn start_async_server<F>(configure: F) -> Result<TestServer>
+where
+    F: FnOnce(AsyncServer<Store>) -> AsyncServer<Store> + Send + 'static,
+{
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(dir.path())?;
+    let addr = free_addr();
+    let listen = addr.clone();
+    thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(configure(AsyncServer::new(store)).run(listen))
+    });
+    wait_until_accepting(&addr);
+    Ok(TestServer { addr, dir })
+}
+
+/// A local address nothing listens on, picked by binding port 0.
+pub fn free_addr() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("unable to bind a free port");
+    listener
+        .local_addr()
+        .expect("unable to read the bound address")
+        .to_string()
+}
+
+/// Waits until a server accepts at `addr`, and until it has let go of the
+/// connection used to find out, so it does not count against any limit.
+pub fn wait_until_accepting(addr: &str) {
+    wait_until(addr, || {
+        let mut stream = TcpStream::connect(addr)?;
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = stream.set_read_timeout(Some(START_TIMEOUT));
+        let _ = stream.read_to_end(&mut Vec::new());
+        Ok(())
+    })
+}
+
+/// Like `wait_until_accepting`, for a server on the Unix socket at `path`.
+#[cfg(unix)]
+pub fn wait_until_accepting_unix(path: &Path) {
+    wait_until(&path.display().to_string(), || {
+        let mut stream = UnixStream::connect(path)?;
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = stream.set_read_timeout(Some(START_TIMEOUT));
+        let _ = stream.read_to_end(&mut Vec::new());
+        Ok(())
+    })
+}
+
+/// Retries `probe` until it connects to the server at `name`.
+fn wait_until<F: Fn() -> io::Result<()>>(name: &str, probe: F) {
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        match probe() {
+            Ok(()) => {
+                // The server closes the socket just before it stops counting it.
+                thread::sleep(SETTLE);
+                return;
+            }
+            Err(err) if Instant::now() > deadline => {
+                panic!("server at {} never accepted: {}", name, err)
+            }
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    }
+}
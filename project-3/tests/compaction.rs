@@ -0,0 +1,191 @@
+use project_3::{
+    CompactionPolicy, CompactionWindow, Engine, EngineConfig, Registry, Result, Sled, Store,
+    COMPACTION_THRESHOLD,
+};
+use std::time::{Duration, UNIX_EPOCH};
+use tempfile::TempDir;
+
+fn overwrite(store: &mut Store, rounds: usize) -> Result<()> {
+    for round in 0..rounds {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", round))?;
+        }
+    }
+    Ok(())
+}
+
+// Should reclaim all dead bytes when asked, regardless of the policy
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    overwrite(&mut store, 10)?;
+
+    let before = store.stats()?;
+    assert!(before.dead_bytes.unwrap() > 0);
+    assert_eq!(before.compaction.count, 0);
+
+    store.compact()?;
+
+    let after = store.stats()?;
+    assert_eq!(after.dead_bytes, Some(0));
+    assert_eq!(after.live_bytes, before.live_bytes);
+    assert!(after.disk_bytes < before.disk_bytes);
+    assert_eq!(after.compaction.count, 1);
+    assert!(after.compaction.last_finished_at.is_some());
+
+    drop(store);
+    let mut store = Store::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn paused_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    store.set_compaction_policy(CompactionPolicy {
+        min_dead_bytes: 1024,
+        ..CompactionPolicy::default()
+    });
+    store.pause_compaction(true)?;
+    overwrite(&mut store, 10)?;
+    assert_eq!(store.stats()?.compaction.count, 0);
+
+    store.pause_compaction(false)?;
+    overwrite(&mut store, 1)?;
+    assert!(store.stats()?.compaction.count > 0);
+
+    Ok(())
+}
+
+// Removals count towards the policy too, and a compaction that became due
+// without a write starts when the engine is maintained
+#[test]
+fn compaction_without_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    store.set_compaction_policy(CompactionPolicy {
+        min_dead_bytes: 1024,
+        ..CompactionPolicy::default()
+    });
+    store.pause_compaction(true)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert_eq!(store.stats()?.compaction.count, 0);
+
+    store.pause_compaction(false)?;
+    store.maintain()?;
+    assert_eq!(store.stats()?.compaction.count, 1);
+
+    for key_id in 50..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(store.stats()?.compaction.count > 1);
+    Ok(())
+}
+
+// Dead bytes come out the same when counted while writing and when loading
+#[test]
+fn dead_bytes_survive_restart() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    overwrite(&mut store, 2)?;
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let before = store.stats()?;
+    drop(store);
+
+    let mut store = Store::open(temp_dir.path())?;
+    let after = store.stats()?;
+    assert_eq!(after.dead_bytes, before.dead_bytes);
+    assert_eq!(after.live_bytes, before.live_bytes);
+    Ok(())
+}
+
+// sled can neither start nor hold back its compaction, and says so
+#[test]
+fn sled_refuses_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = Sled::new(sled::open(temp_dir.path())?);
+    assert!(engine.compact().is_err());
+    assert!(engine.pause_compaction(true).is_err());
+
+    let config = EngineConfig::new(temp_dir.path().join("paused")).compaction(CompactionPolicy {
+        paused: true,
+        ..CompactionPolicy::default()
+    });
+    assert!(Registry::default().open("sled", &config).is_err());
+    Ok(())
+}
+
+// The compaction policy is handed to the engine through its config
+#[test]
+fn policy_from_config() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = EngineConfig::new(temp_dir.path()).compaction(CompactionPolicy {
+        min_dead_bytes: 1024,
+        min_dead_ratio: Some(1.0),
+        ..CompactionPolicy::default()
+    });
+    let mut engine = Registry::default().open("kvs", &config)?;
+
+    for key_id in 0..100 {
+        engine.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    engine.set("key0".to_owned(), "other".to_owned())?;
+    assert_eq!(engine.stats()?.compaction.count, 0);
+
+    for _ in 0..2 {
+        for key_id in 0..100 {
+            engine.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+    }
+    assert!(engine.stats()?.compaction.count > 0);
+
+    Ok(())
+}
+
+#[test]
+fn policy_thresholds() {
+    let policy = CompactionPolicy::default();
+    assert!(!policy.should_compact(COMPACTION_THRESHOLD, 0));
+    assert!(policy.should_compact(COMPACTION_THRESHOLD + 1, 0));
+
+    let policy = CompactionPolicy {
+        min_dead_bytes: 0,
+        min_dead_ratio: Some(0.5),
+        ..CompactionPolicy::default()
+    };
+    assert!(!policy.should_compact(49, 100));
+    assert!(policy.should_compact(50, 100));
+}
+
+#[test]
+fn policy_window() {
+    let window: CompactionWindow = "22-6".parse().expect("unable to parse window");
+    assert!(window.contains(23) && window.contains(0) && window.contains(5));
+    assert!(!window.contains(6) && !window.contains(12) && !window.contains(21));
+
+    let window: CompactionWindow = "1-5".parse().expect("unable to parse window");
+    assert!(window.contains(1) && !window.contains(5));
+
+    assert!("25-3".parse::<CompactionWindow>().is_err());
+    assert!("night".parse::<CompactionWindow>().is_err());
+
+    let policy = CompactionPolicy {
+        min_dead_bytes: 0,
+        window: Some(window),
+        ..CompactionPolicy::default()
+    };
+    let at = |hour: u64| UNIX_EPOCH + Duration::from_secs(hour * 3600);
+    assert!(policy.should_compact_at(1, 0, at(2)));
+    assert!(!policy.should_compact_at(1, 0, at(12)));
+    assert!(policy.should_compact_at(1, 0, at(24 + 4)));
+}
@@ -0,0 +1,112 @@
+use project_3::{
+    export, import, read_entries, EngineConfig, Entry, EntryWriter, Format, Progress, Registry,
+    Result,
+};
+use tempfile::TempDir;
+
+fn entries() -> Vec<Entry> {
+    vec![
+        Entry {
+            key: "a:plain".to_owned(),
+            value: "value".to_owned(),
+        },
+        Entry {
+            key: "a:quoted".to_owned(),
+            value: "comma, \"quotes\" and\nnewline".to_owned(),
+        },
+        Entry {
+            key: "b:other".to_owned(),
+            value: "".to_owned(),
+        },
+    ]
+}
+
+// Should read back exactly what was exported, in both formats
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let registry = Registry::default();
+
+    for format in &[Format::JsonLines, Format::Csv] {
+        let source_dir = TempDir::new().expect("unable to create temporary working directory");
+        let target_dir = TempDir::new().expect("unable to create temporary working directory");
+
+        let mut source = registry.open("kvs", &EngineConfig::new(source_dir.path()))?;
+        import(
+            &mut source,
+            "",
+            entries().into_iter().map(Ok),
+            &mut Progress::default(),
+        )?;
+
+        let mut writer = EntryWriter::new(*format, Vec::new());
+        export(&mut source, "", &mut writer, &mut Progress::default())?;
+        let buf = writer.into_inner()?;
+
+        let read: Vec<Entry> = read_entries(*format, buf.as_slice()).collect::<Result<_>>()?;
+        assert_eq!(read, entries());
+
+        let mut target = registry.open("sled", &EngineConfig::new(target_dir.path()))?;
+        import(
+            &mut target,
+            "",
+            read_entries(*format, buf.as_slice()),
+            &mut Progress::default(),
+        )?;
+        for entry in entries() {
+            assert_eq!(target.get(entry.key)?, Some(entry.value));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn export_with_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = Registry::default().open("kvs", &EngineConfig::new(temp_dir.path()))?;
+    import(
+        &mut engine,
+        "",
+        entries().into_iter().map(Ok),
+        &mut Progress::default(),
+    )?;
+
+    let mut writer = EntryWriter::new(Format::JsonLines, Vec::new());
+    export(&mut engine, "a:", &mut writer, &mut Progress::default())?;
+    let buf = writer.into_inner()?;
+
+    let keys: Vec<String> = read_entries(Format::JsonLines, buf.as_slice())
+        .map(|entry| entry.map(|entry| entry.key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec!["a:plain".to_owned(), "a:quoted".to_owned()]);
+
+    Ok(())
+}
+
+// Should export a prefix holding more keys than are read at a time, in order
+#[test]
+fn export_many_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = Registry::default().open("kvs", &EngineConfig::new(temp_dir.path()))?;
+    let entries = ["a", "b", "c"].iter().flat_map(|prefix| {
+        (0..3000).map(move |i| {
+            Ok(Entry {
+                key: format!("{}:{:04}", prefix, i),
+                value: i.to_string(),
+            })
+        })
+    });
+    import(&mut engine, "", entries, &mut Progress::default())?;
+
+    let mut writer = EntryWriter::new(Format::JsonLines, Vec::new());
+    export(&mut engine, "b:", &mut writer, &mut Progress::default())?;
+    let buf = writer.into_inner()?;
+
+    let keys: Vec<String> = read_entries(Format::JsonLines, buf.as_slice())
+        .map(|entry| entry.map(|entry| entry.key))
+        .collect::<Result<_>>()?;
+    let expected: Vec<String> = (0..3000).map(|i| format!("b:{:04}", i)).collect();
+    assert_eq!(keys, expected);
+
+    Ok(())
+}
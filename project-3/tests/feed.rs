@@ -0,0 +1,157 @@
+use project_3::{Change, ChangeEvent, Client, Error, Feed, Position, Result, SUBSCRIBER_BUFFER};
+use std::time::Duration;
+
+mod common;
+
+fn set(key: &str, value: &str) -> Change {
+    Change::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn at(feed: &Feed, seq: u64) -> Position {
+    Position {
+        feed_id: feed.id(),
+        seq,
+    }
+}
+
+// Should deliver published changes to subscribers whose prefix matches
+#[test]
+fn publish_to_prefix_subscribers() -> Result<()> {
+    let feed = Feed::default();
+    let (backlog, users) = feed.subscribe("user:".to_owned(), None)?;
+    assert!(backlog.is_empty());
+
+    assert_eq!(feed.publish(set("user:1", "alice")), 1);
+    assert_eq!(feed.publish(set("order:1", "book")), 2);
+    assert_eq!(
+        feed.publish(Change::Remove {
+            key: "user:1".to_owned()
+        }),
+        3
+    );
+
+    let events: Vec<ChangeEvent> = users.try_iter().collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].seq, 1);
+    assert_eq!(events[0].change, set("user:1", "alice"));
+    assert_eq!(events[1].seq, 3);
+    assert_eq!(feed.last_seq(), 3);
+    Ok(())
+}
+
+// Should replay the backlog after a given sequence number
+#[test]
+fn resume_after_sequence() -> Result<()> {
+    let feed = Feed::default();
+    for i in 1..=5 {
+        feed.publish(set(&format!("key{}", i), "value"));
+    }
+
+    let (backlog, _events) = feed.subscribe("key".to_owned(), Some(at(&feed, 3)))?;
+    let seqs: Vec<u64> = backlog.iter().map(|event| event.seq).collect();
+    assert_eq!(seqs, vec![4, 5]);
+
+    let (backlog, _events) = feed.subscribe("key".to_owned(), Some(at(&feed, 5)))?;
+    assert!(backlog.is_empty());
+    Ok(())
+}
+
+// Should refuse to resume from a sequence number that was dropped or never issued
+#[test]
+fn sequence_unavailable() -> Result<()> {
+    let feed = Feed::new(2);
+    for i in 1..=5 {
+        feed.publish(set(&format!("key{}", i), "value"));
+    }
+
+    match feed.subscribe(String::new(), Some(at(&feed, 1))) {
+        Err(Error::SequenceUnavailable {
+            requested: 1,
+            oldest: 4,
+            last: 5,
+        }) => {}
+        other => panic!("unexpected result: {:?}", other.map(|(backlog, _)| backlog)),
+    }
+    assert!(feed.subscribe(String::new(), Some(at(&feed, 6))).is_err());
+    assert_eq!(
+        feed.subscribe(String::new(), Some(at(&feed, 3)))?.0.len(),
+        2
+    );
+
+    // The same sequence number in the feed of an earlier run
+    let earlier = Position {
+        feed_id: feed.id() - 1,
+        seq: 3,
+    };
+    assert!(feed.subscribe(String::new(), Some(earlier)).is_err());
+    Ok(())
+}
+
+// Should stream changes made by other clients to a watching client
+#[test]
+fn watch_over_the_wire() -> Result<()> {
+    let server = common::start_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    let mut writer = Client::connect(addr)?;
+    writer.set("user:1".to_owned(), "alice".to_owned())?;
+
+    let start = Client::connect(addr)?
+        .watch(String::new(), None)?
+        .position();
+    assert_eq!(start.seq, 1);
+    let after = Position { seq: 0, ..start };
+    let mut watch = Client::connect(addr)?.watch("user:".to_owned(), Some(after))?;
+    writer.set("order:1".to_owned(), "book".to_owned())?;
+    writer.remove("user:1".to_owned())?;
+
+    let first = watch.next().unwrap()?;
+    assert_eq!(first.seq, 1);
+    assert_eq!(first.change, set("user:1", "alice"));
+    let second = watch.next().unwrap()?;
+    assert_eq!(second.seq, 3);
+    assert_eq!(
+        second.change,
+        Change::Remove {
+            key: "user:1".to_owned()
+        }
+    );
+
+    assert_eq!(watch.position(), Position { seq: 3, ..start });
+
+    let future = Position { seq: 10, ..start };
+    assert!(Client::connect(addr)?
+        .watch(String::new(), Some(future))
+        .is_err());
+    let restarted = Position {
+        feed_id: start.feed_id + 1,
+        ..start
+    };
+    match Client::connect(addr)?.watch(String::new(), Some(restarted)) {
+        Err(Error::SequenceUnavailable { .. }) => {}
+        other => panic!("expected an unavailable sequence, got {:?}", other.is_ok()),
+    }
+    Ok(())
+}
+
+// Should drop a subscriber that falls too far behind instead of buffering for it
+#[test]
+fn lagging_subscriber_dropped() -> Result<()> {
+    let feed = Feed::default();
+    let (_, events) = feed.subscribe(String::new(), None)?;
+    for i in 0..=SUBSCRIBER_BUFFER {
+        feed.publish(set(&format!("key{}", i), "value"));
+    }
+
+    assert_eq!(events.try_iter().count(), SUBSCRIBER_BUFFER);
+    match events.recv_timeout(Duration::from_millis(10)) {
+        Err(Error::SequenceUnavailable { requested, .. }) => {
+            assert_eq!(requested, SUBSCRIBER_BUFFER as u64)
+        }
+        other => panic!("expected an unavailable sequence, got {:?}", other),
+    }
+    Ok(())
+}
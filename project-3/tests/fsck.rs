@@ -0,0 +1,120 @@
+use project_3::fsck::{self, RecordProblem};
+use project_3::{Engine, Error, Result, Store};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use tempfile::TempDir;
+
+fn append(path: &Path, data: &[u8]) {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .expect("unable to open log");
+    file.write_all(data).expect("unable to append to log");
+}
+
+#[test]
+fn check_clean_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let report = fsck::check(temp_dir.path())?;
+    assert!(report.is_clean());
+    assert_eq!(report.live_keys, 1);
+    assert_eq!(report.generations[0].records, 4);
+    assert!(report.dead_bytes > report.live_bytes);
+
+    Ok(())
+}
+
+// Should report where a log is cut off and repair it by keeping the readable records
+#[test]
+fn check_and_repair_truncated_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let valid_len = std::fs::metadata(&log)?.len();
+    append(&log, br#"{"Set":{"key":"key3","val"#);
+
+    assert!(Store::open(temp_dir.path()).is_err());
+
+    let report = fsck::check(temp_dir.path())?;
+    assert!(!report.is_clean());
+    let gen = &report.generations[0];
+    assert_eq!(gen.gen, 1);
+    assert_eq!(gen.records, 2);
+    assert_eq!(gen.problems, vec![(valid_len, RecordProblem::Truncated)]);
+
+    let repaired = fsck::repair(temp_dir.path())?;
+    assert_eq!(repaired.live_keys, 2);
+    assert!(fsck::check(temp_dir.path())?.is_clean());
+
+    let mut store = Store::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should report every corrupt record, reading on from the next record after each
+#[test]
+fn check_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let valid_len = std::fs::metadata(&log)?.len();
+    append(&log, br#"{"Unknown":{}}"#);
+    append(&log, br#"{"Set":{"key":"key2","value":"value2"}}"#);
+    let second_len = std::fs::metadata(&log)?.len();
+    append(&log, br#"{"Set":{"key":"key3","value""#);
+    append(&log, &[0xff; 20000]);
+    append(&log, br#"{"Remove":{"key":"key1"}}"#);
+
+    let report = fsck::check(temp_dir.path())?;
+    let gen = &report.generations[0];
+    assert_eq!(gen.records, 3);
+    assert_eq!(gen.valid_bytes, valid_len);
+    assert_eq!(gen.problems.len(), 2);
+    for ((offset, problem), expected) in gen.problems.iter().zip(&[valid_len, second_len]) {
+        assert!(
+            matches!(problem, RecordProblem::Corrupt(_)),
+            "{:?}",
+            problem
+        );
+        assert_eq!(offset, expected);
+    }
+    assert_eq!(report.live_keys, 1);
+
+    let repaired = fsck::repair(temp_dir.path())?;
+    assert_eq!(repaired.live_keys, 1);
+    let mut store = Store::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn repair_locked_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let _store = Store::open(temp_dir.path())?;
+
+    assert!(matches!(
+        fsck::repair(temp_dir.path()),
+        Err(Error::Locked(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,245 @@
+use project_3::{Auth, Client, Limits, Result, Server, Store};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+use common::TestServer;
+
+struct Response {
+    status: u16,
+    etag: Option<String>,
+    body: Value,
+}
+
+/// Sends one request on its own connection, with extra `headers`.
+fn request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<Value>,
+) -> Result<Response> {
+    let mut stream = TcpStream::connect(addr)?;
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    )?;
+    for (name, value) in headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    write!(stream, "\r\n{}", body)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+
+    let mut etag = None;
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header.split_at(header.find(':').unwrap());
+        if name.eq_ignore_ascii_case("etag") {
+            etag = Some(value[1..].trim().to_owned());
+        }
+    }
+
+    let mut body = String::new();
+    reader.read_to_string(&mut body)?;
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_str(&body)?
+    };
+    Ok(Response { status, etag, body })
+}
+
+/// Serves a new store with the server `configure` returns and the HTTP
+/// gateway on a port of its own, whose address comes second.
+fn start_gateway<F>(configure: F) -> Result<(TestServer, String)>
+where
+    F: FnOnce(Server<Store>) -> Server<Store> + Send + 'static,
+{
+    let http = common::free_addr();
+    let gateway = http.clone();
+    let server = common::start_server(move |server| configure(server.http(gateway)))?;
+    Ok((server, http))
+}
+
+#[test]
+fn http_gateway() -> Result<()> {
+    let (server, http) = start_gateway(|server| server)?;
+    let (addr, http) = (server.addr.as_str(), http.as_str());
+
+    assert_eq!(request(http, "GET", "/health", &[], None)?.status, 200);
+    assert_eq!(
+        request(http, "GET", "/keys/missing", &[], None)?.status,
+        404
+    );
+    assert_eq!(
+        request(http, "DELETE", "/keys/missing", &[], None)?.status,
+        404
+    );
+    assert_eq!(request(http, "POST", "/keys/a", &[], None)?.status, 405);
+
+    let put = request(
+        http,
+        "PUT",
+        "/keys/a%20b",
+        &[],
+        Some(json!({ "value": "1" })),
+    )?;
+    assert_eq!(put.status, 204);
+    let got = request(http, "GET", "/keys/a%20b", &[], None)?;
+    assert_eq!(got.status, 200);
+    assert_eq!(got.body, json!({ "key": "a b", "value": "1" }));
+    assert_eq!(got.etag, put.etag);
+    // Tags only depend on the value, so they survive a rebuild of the server
+    assert_eq!(
+        got.etag.as_deref(),
+        Some("\"d228cb693f1a8caf78912b704e4a4e54\"")
+    );
+
+    // Writes through the gateway reach the other protocols
+    assert_eq!(
+        Client::connect(addr)?.get("a b".to_owned())?,
+        Some("1".to_owned())
+    );
+
+    let etag = got.etag.unwrap();
+    let stale = request(
+        http,
+        "PUT",
+        "/keys/a%20b",
+        &[("If-Match", "\"0000000000000000\"")],
+        Some(json!({ "value": "2" })),
+    )?;
+    assert_eq!(stale.status, 409);
+    let fresh = request(
+        http,
+        "PUT",
+        "/keys/a%20b",
+        &[("If-Match", &etag)],
+        Some(json!({ "value": "2" })),
+    )?;
+    assert_eq!(fresh.status, 204);
+    let create = request(
+        http,
+        "PUT",
+        "/keys/a%20b",
+        &[("If-None-Match", "*")],
+        Some(json!({ "value": "3" })),
+    )?;
+    assert_eq!(create.status, 409);
+    assert_eq!(
+        request(http, "DELETE", "/keys/a%20b", &[("If-Match", &etag)], None)?.status,
+        409
+    );
+    assert_eq!(
+        request(http, "DELETE", "/keys/a%20b", &[], None)?.status,
+        204
+    );
+    assert_eq!(
+        request(http, "PUT", "/keys/a", &[], Some(json!({ "v": 1 })))?.status,
+        400
+    );
+    Ok(())
+}
+
+#[test]
+fn http_pagination() -> Result<()> {
+    let (server, http) = start_gateway(|server| server)?;
+    let http = http.as_str();
+    let mut client = Client::connect(&server.addr)?;
+    for i in 0..25 {
+        client.set(format!("key{:02}", i), i.to_string())?;
+    }
+    client.set("other".to_owned(), "value".to_owned())?;
+
+    let mut keys = Vec::new();
+    let mut path = "/keys?prefix=key&limit=10".to_owned();
+    loop {
+        let page = request(http, "GET", &path, &[], None)?;
+        assert_eq!(page.status, 200);
+        for entry in page.body["entries"].as_array().unwrap() {
+            keys.push(entry["key"].as_str().unwrap().to_owned());
+        }
+        match page.body["next"].as_str() {
+            Some(next) => path = format!("/keys?prefix=key&limit=10&start={}", next),
+            None => break,
+        }
+    }
+    assert_eq!(keys.len(), 25);
+    assert_eq!(keys[0], "key00");
+    assert_eq!(keys[24], "key24");
+
+    let range = request(http, "GET", "/keys?start=key05&end=key08", &[], None)?;
+    assert_eq!(range.body["entries"].as_array().unwrap().len(), 3);
+    assert_eq!(range.body["next"], Value::Null);
+    Ok(())
+}
+
+// With authentication, every route but /health wants a bearer token
+#[test]
+fn http_auth() -> Result<()> {
+    let auth = Auth::default().user("alice", "alice-secret");
+    let (_server, http) = start_gateway(move |server| server.auth(auth))?;
+    let http = http.as_str();
+
+    assert_eq!(request(http, "GET", "/health", &[], None)?.status, 200);
+    assert_eq!(request(http, "GET", "/keys/a", &[], None)?.status, 401);
+    let wrong = [("Authorization", "Bearer guess")];
+    assert_eq!(request(http, "GET", "/keys/a", &wrong, None)?.status, 401);
+    let right = [("Authorization", "Bearer alice-secret")];
+    assert_eq!(request(http, "GET", "/keys/a", &right, None)?.status, 404);
+    Ok(())
+}
+
+// Requests share the server's connection limit, and slow bodies time out
+#[test]
+fn http_limits() -> Result<()> {
+    let limits = Limits {
+        max_connections: Some(1),
+        read_timeout: Some(Duration::from_millis(300)),
+        ..Limits::default()
+    };
+    let (server, http) = start_gateway(move |server| server.limits(limits))?;
+    let http = http.as_str();
+
+    let mut client = Client::connect(&server.addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(request(http, "GET", "/health", &[], None)?.status, 503);
+    drop(client);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(request(http, "GET", "/health", &[], None)?.status, 200);
+
+    // Small bodies are read before the request is handed over
+    let body = json!({ "value": "x".repeat(4096) }).to_string();
+    let (head, tail) = body.split_at(body.len() / 2);
+    let mut stream = TcpStream::connect(http)?;
+    write!(
+        stream,
+        "PUT /keys/key HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        http,
+        body.len(),
+        head
+    )?;
+    thread::sleep(Duration::from_millis(600));
+    stream.write_all(tail.as_bytes())?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    assert_eq!(line.split(' ').nth(1), Some("408"));
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use project_3::{Engine, Result, Store};
+use project_3::{Engine, Error, Result, Sled, Store};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -79,6 +79,68 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// A second writer on the same directory should be refused until the first is dropped
+#[test]
+fn exclusive_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Store::open(temp_dir.path())?;
+
+    assert!(matches!(
+        Store::open(temp_dir.path()),
+        Err(Error::Locked(_))
+    ));
+
+    drop(store);
+    Store::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A read-only store can be opened next to a writer and rejects mutations
+#[test]
+fn read_only_alongside_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = Store::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key2".to_owned(), "value2".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = Store::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.generations.len(), 1);
+    assert_eq!(stats.disk_bytes, stats.generations[0].bytes);
+    let live = stats.live_bytes.expect("live bytes not tracked");
+    let dead = stats.dead_bytes.expect("dead bytes not tracked");
+    assert!(live > 0 && dead > live);
+    assert_eq!(stats.compaction.count, 0);
+    assert_eq!(stats.compaction.last_duration_ms, None);
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -124,3 +186,22 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should list keys in order from a starting key, for both engines
+#[test]
+fn keys_from_start() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn Engine>> = vec![
+        Box::new(Store::open(temp_dir.path().join("kvs"))?),
+        Box::new(Sled::new(sled::open(temp_dir.path().join("sled"))?)),
+    ];
+    for mut engine in engines {
+        for key in &["c", "a", "d", "b"] {
+            engine.set(key.to_string(), "value".to_owned())?;
+        }
+        assert_eq!(engine.keys_from("b", 2)?, vec!["b", "c"]);
+        assert_eq!(engine.keys_from("bb", 10)?, vec!["c", "d"]);
+        assert!(engine.keys_from("e", 10)?.is_empty());
+    }
+    Ok(())
+}
@@ -0,0 +1,188 @@
+use project_3::{Client, Error, Limits, Protocol, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+// Clients beyond the limit are told so, and let in once others leave
+#[test]
+fn max_connections() -> Result<()> {
+    let limits = Limits {
+        max_connections: Some(1),
+        ..Limits::default()
+    };
+    let server = common::start_server(move |server| server.protocol(Protocol::Kvs).limits(limits))?;
+    let addr = server.addr.as_str();
+
+    let mut first = Client::connect(addr)?;
+    first.set("key".to_owned(), "value".to_owned())?;
+    match Client::connect(addr)?.get("key".to_owned()) {
+        Err(Error::Limit(_)) => {}
+        other => panic!("expected a limit error, got {:?}", other),
+    }
+
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut second = Client::connect(addr)?;
+    assert_eq!(second.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Oversized requests are refused, and binary connections stay usable
+#[test]
+fn max_request_size() -> Result<()> {
+    let limits = Limits {
+        max_request_size: 1024,
+        ..Limits::default()
+    };
+    let server = common::start_server(move |server| server.protocol(Protocol::Kvs).limits(limits))?;
+    let addr = server.addr.as_str();
+    let big = "x".repeat(4096);
+
+    let mut binary = Client::connect(addr)?;
+    match binary.set("key".to_owned(), big.clone()) {
+        Err(Error::Limit(_)) => {}
+        other => panic!("expected a limit error, got {:?}", other),
+    }
+    binary.set("key".to_owned(), "small".to_owned())?;
+    assert_eq!(binary.get("key".to_owned())?, Some("small".to_owned()));
+
+    // JSON clients only get the message, and tell the limit by it
+    let mut json = Client::connect_json(addr)?;
+    match json.set("key".to_owned(), big) {
+        Err(Error::Limit(message)) => assert_eq!(message, "request larger than 1024 bytes"),
+        other => panic!("expected a limit error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Idle connections are closed while busy ones stay open
+#[test]
+fn idle_timeout() -> Result<()> {
+    let limits = Limits {
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Limits::default()
+    };
+    let server = common::start_server(move |server| server.protocol(Protocol::Kvs).limits(limits))?;
+    let addr = server.addr.as_str();
+
+    let mut idle = TcpStream::connect(addr)?;
+    let mut busy = Client::connect(addr)?;
+    for _ in 0..5 {
+        thread::sleep(Duration::from_millis(100));
+        busy.set("key".to_owned(), "value".to_owned())?;
+    }
+
+    let mut buf = Vec::new();
+    assert_eq!(idle.read_to_end(&mut buf)?, 0);
+    Ok(())
+}
+
+// Requests must arrive within the read timeout, however steadily they trickle
+#[test]
+fn read_timeout() -> Result<()> {
+    let limits = Limits {
+        read_timeout: Some(Duration::from_millis(300)),
+        ..Limits::default()
+    };
+    let server = common::start_server(move |server| server.protocol(Protocol::Kvs).limits(limits))?;
+
+    let mut slow = TcpStream::connect(server.addr.as_str())?;
+    let mut reader = slow.try_clone()?;
+    let started = Instant::now();
+    let trickle = thread::spawn(move || {
+        let _ = slow.write_all(b"{\"Set\":{\"key\":\"key\",\"value\":\"");
+        for _ in 0..30 {
+            thread::sleep(Duration::from_millis(100));
+            if slow.write_all(b"x").is_err() {
+                break;
+            }
+        }
+    });
+
+    let _ = reader.read_to_end(&mut Vec::new());
+    assert!(started.elapsed() < Duration::from_secs(2));
+    trickle.join().unwrap();
+    Ok(())
+}
+
+// redis clients get the errors redis would send
+#[test]
+fn resp_limits() -> Result<()> {
+    let limits = Limits {
+        max_connections: Some(1),
+        max_request_size: 16,
+        ..Limits::default()
+    };
+    let server =
+        common::start_server(move |server| server.protocol(Protocol::Resp).limits(limits))?;
+    let addr = server.addr.as_str();
+
+    let mut first = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+    let mut refused = BufReader::new(TcpStream::connect(addr)?);
+    let mut line = String::new();
+    refused.read_line(&mut line)?;
+    assert_eq!(line, "-ERR max number of clients reached\r\n");
+
+    first.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$32\r\n")?;
+    let mut reply = String::new();
+    BufReader::new(&first).read_line(&mut reply)?;
+    assert_eq!(reply, "-ERR request larger than 16 bytes\r\n");
+    Ok(())
+}
+
+// Headers and empty arguments count against the request size
+#[test]
+fn resp_header_limits() -> Result<()> {
+    let limits = Limits {
+        max_request_size: 64,
+        ..Limits::default()
+    };
+    let server =
+        common::start_server(move |server| server.protocol(Protocol::Resp).limits(limits))?;
+    let addr = server.addr.as_str();
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*1048576\r\n")?;
+    for _ in 0..16 {
+        stream.write_all(b"$0\r\n\r\n")?;
+    }
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply)?;
+    assert_eq!(reply, "-ERR request larger than 64 bytes\r\n");
+    Ok(())
+}
+
+// The async server applies the same limits
+#[test]
+fn async_limits() -> Result<()> {
+    let limits = Limits {
+        max_connections: Some(1),
+        idle_timeout: Some(Duration::from_millis(300)),
+        max_request_size: 1024,
+        ..Limits::default()
+    };
+    let server = common::start_async_server(move |server| server.limits(limits))?;
+    let addr = server.addr.as_str();
+
+    let mut first = Client::connect(addr)?;
+    match first.set("key".to_owned(), "x".repeat(4096)) {
+        Err(Error::Limit(_)) => {}
+        other => panic!("expected a limit error, got {:?}", other),
+    }
+    first.set("key".to_owned(), "value".to_owned())?;
+    match Client::connect(addr)?.get("key".to_owned()) {
+        Err(Error::Limit(_)) => {}
+        other => panic!("expected a limit error, got {:?}", other),
+    }
+
+    // The idle connection is closed, which lets the next client in
+    thread::sleep(Duration::from_millis(500));
+    assert!(first.get("key".to_owned()).is_err());
+    let mut second = Client::connect(addr)?;
+    assert_eq!(second.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
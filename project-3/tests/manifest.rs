@@ -0,0 +1,59 @@
+use project_3::{EngineConfig, Error, Manifest, Registry, Result, FORMAT_VERSION};
+use tempfile::TempDir;
+
+#[test]
+fn save_and_load_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(Manifest::load(temp_dir.path())?, None);
+
+    let manifest = Manifest::new("sled");
+    manifest.save(temp_dir.path())?;
+
+    let loaded = Manifest::load(temp_dir.path())?.expect("manifest not found");
+    assert_eq!(loaded, manifest);
+    assert_eq!(loaded.format_version, FORMAT_VERSION);
+    assert_ne!(loaded.store_id, Manifest::new("sled").store_id);
+
+    Ok(())
+}
+
+// A bare `engine` file from older releases, which named the engine as clap
+// printed it, is read as is, with a store id that stays the same until saving
+// the manifest replaces the file
+#[test]
+fn upgrade_legacy_engine_file() -> Result<()> {
+    for (marker, engine) in &[("Kvs", "kvs"), ("Sled", "sled")] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        std::fs::write(temp_dir.path().join("engine"), marker)?;
+
+        let manifest = Manifest::load(temp_dir.path())?.expect("legacy engine file ignored");
+        assert_eq!(&manifest.engine, engine);
+        assert_eq!(Manifest::load(temp_dir.path())?, Some(manifest.clone()));
+
+        let registry = Registry::default();
+        drop(registry.open(engine, &EngineConfig::new(temp_dir.path()))?);
+        assert!(temp_dir.path().join("engine").exists());
+
+        manifest.save(temp_dir.path())?;
+        assert!(!temp_dir.path().join("engine").exists());
+        assert_eq!(Manifest::load(temp_dir.path())?, Some(manifest));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn refuse_incompatible_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION + 1,
+        ..Manifest::new("kvs")
+    };
+    manifest.save(temp_dir.path())?;
+
+    let res = Manifest::load(temp_dir.path());
+    assert!(matches!(res, Err(Error::IncompatibleFormat { .. })));
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use project_3::{migrate, summarize, EngineConfig, Error, Manifest, Registry, Result, Summary};
+use tempfile::TempDir;
+
+fn populate(registry: &Registry, engine: &str, dir: &TempDir) -> Result<()> {
+    let mut store = registry.open(engine, &EngineConfig::new(dir.path()))?;
+    // More keys than a migration reads at once
+    for key_id in 0..3000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key42".to_owned())?;
+    store.set("key7".to_owned(), "overwritten".to_owned())?;
+    drop(store);
+
+    Manifest::new(engine).save(dir.path())
+}
+
+fn list_dir(dir: &TempDir) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(dir.path())? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+}
+
+// Should copy every live key into the other engine and record it in the manifest
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let registry = Registry::default();
+
+    for (from, to) in &[("kvs", "sled"), ("sled", "kvs")] {
+        let source = TempDir::new().expect("unable to create temporary working directory");
+        let target = TempDir::new().expect("unable to create temporary working directory");
+        populate(&registry, from, &source)?;
+        let files = list_dir(&source)?;
+
+        let report = migrate(&registry, source.path(), target.path(), to)?;
+        assert_eq!(report.summary.keys, 2999);
+        // The source is only read
+        if *from == "kvs" {
+            assert_eq!(list_dir(&source)?, files);
+        }
+        let manifest = Manifest::load(target.path())?.expect("manifest not written");
+        assert_eq!(&manifest.engine, to);
+
+        let mut engine = registry.open(to, &EngineConfig::new(target.path()))?;
+        assert_eq!(
+            engine.get("key7".to_owned())?,
+            Some("overwritten".to_owned())
+        );
+        assert_eq!(engine.get("key42".to_owned())?, None);
+        assert_eq!(summarize(&mut engine)?, report.summary);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn migrate_into_non_empty_directory() -> Result<()> {
+    let registry = Registry::default();
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let target = TempDir::new().expect("unable to create temporary working directory");
+    populate(&registry, "kvs", &source)?;
+    std::fs::write(target.path().join("leftover"), "data")?;
+
+    let res = migrate(&registry, source.path(), target.path(), "sled");
+    assert!(matches!(res, Err(Error::Migration(_))));
+    assert_eq!(Manifest::load(target.path())?, None);
+
+    Ok(())
+}
+
+// A failed migration leaves nothing behind, so it can be retried
+#[test]
+fn retry_failed_migration() -> Result<()> {
+    let registry = Registry::default();
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let parent = TempDir::new().expect("unable to create temporary working directory");
+    let target = parent.path().join("new");
+    Manifest::new("kvs").save(source.path())?;
+    std::fs::write(source.path().join("1.log"), "not a log")?;
+
+    assert!(migrate(&registry, source.path(), &target, "sled").is_err());
+    assert_eq!(list_dir(&parent)?, Vec::<String>::new());
+
+    std::fs::remove_file(source.path().join("1.log"))?;
+    populate(&registry, "kvs", &source)?;
+    let report = migrate(&registry, source.path(), &target, "sled")?;
+    assert_eq!(report.summary.keys, 2999);
+    assert_eq!(list_dir(&parent)?, vec!["new".to_owned()]);
+    Ok(())
+}
+
+#[test]
+fn migrate_without_manifest() {
+    let registry = Registry::default();
+    let source = TempDir::new().expect("unable to create temporary working directory");
+    let target = TempDir::new().expect("unable to create temporary working directory");
+
+    let res = migrate(&registry, source.path(), &target.path().join("new"), "sled");
+    assert!(matches!(res, Err(Error::Migration(_))));
+}
+
+// The checksum users compare between runs does not depend on the Rust release
+#[test]
+fn stable_checksum() -> Result<()> {
+    let registry = Registry::default();
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = registry.open("kvs", &EngineConfig::new(dir.path()))?;
+    engine.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        summarize(&mut engine)?,
+        Summary {
+            keys: 1,
+            checksum: 0x9b55_ec4b_c9a5_7629,
+        }
+    );
+    Ok(())
+}
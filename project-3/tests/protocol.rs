@@ -0,0 +1,176 @@
+use project_3::protocol::{self, Frame, MAGIC, OP_ERROR, OP_REQUEST, OP_RESPONSE};
+use project_3::{Client, Error, Request, Response, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+
+mod common;
+
+/// The answer to `Remove` as clients predating the binary protocol decode it.
+#[derive(Deserialize, Debug, PartialEq)]
+enum RemoveResponse {
+    Ok(()),
+    Err(String),
+}
+
+// Clients that never heard of the binary protocol keep speaking JSON, and
+// get the response shapes they always did, with errors as their message
+#[test]
+fn json_clients_still_served() -> Result<()> {
+    let server = common::start_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    let mut client = Client::connect(addr)?;
+    client.set("key".to_owned(), "binary".to_owned())?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    serde_json::to_writer(
+        &mut stream,
+        &Request::Get {
+            key: "key".to_owned(),
+        },
+    )?;
+    serde_json::to_writer(
+        &mut stream,
+        &Request::Remove {
+            key: "missing".to_owned(),
+        },
+    )?;
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?);
+    assert_eq!(
+        Value::deserialize(&mut responses)?,
+        json!({ "Ok": "binary" })
+    );
+    assert_eq!(
+        RemoveResponse::deserialize(&mut responses)?,
+        RemoveResponse::Err("Key not found".to_owned())
+    );
+
+    let mut client = Client::connect_json(addr)?;
+    client.set("key".to_owned(), "json".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("json".to_owned()));
+    assert_eq!(client.scan(String::new())?.count(), 1);
+    Ok(())
+}
+
+// Errors keep their variant across either protocol, where JSON tells a
+// missing key apart by its message
+#[test]
+fn typed_errors() -> Result<()> {
+    let server = common::start_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    for mut client in [Client::connect(addr)?, Client::connect_json(addr)?] {
+        match client.remove("missing".to_owned()) {
+            Err(Error::KeyNotFound) => {}
+            other => panic!("expected KeyNotFound, got {:?}", other),
+        }
+        match client.promote() {
+            Err(Error::Replication(_)) => {}
+            other => panic!("expected a replication error, got {:?}", other),
+        }
+    }
+    Ok(())
+}
+
+// A bad frame is answered with an error frame and the connection stays usable
+#[test]
+fn binary_frames_resync_after_errors() -> Result<()> {
+    let server = common::start_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    let mut writer = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(writer.try_clone()?);
+    assert_eq!(
+        protocol::connect(&mut reader, &mut writer)?,
+        protocol::PROTOCOL_VERSION
+    );
+
+    let garbage = Frame {
+        version: protocol::PROTOCOL_VERSION,
+        opcode: OP_REQUEST,
+        id: 7,
+        payload: vec![0xff; 3],
+    };
+    garbage.write(&mut writer)?;
+    let wrong_version = Frame {
+        version: 9,
+        ..Frame::encode(OP_REQUEST, 8, &Request::Stats)?
+    };
+    wrong_version.write(&mut writer)?;
+    let request = Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    };
+    Frame::encode(OP_REQUEST, 9, &request)?.write(&mut writer)?;
+
+    let rejected = Frame::read(&mut reader)?.expect("error frame");
+    assert_eq!((rejected.opcode, rejected.id), (OP_ERROR, 7));
+    let rejected = Frame::read(&mut reader)?.expect("error frame");
+    assert_eq!((rejected.opcode, rejected.id), (OP_ERROR, 8));
+    let response = Frame::read(&mut reader)?.expect("response frame");
+    assert_eq!((response.opcode, response.id), (OP_RESPONSE, 9));
+    assert!(matches!(response.decode()?, Response::Ok));
+
+    let mut client = Client::connect(addr)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn handshake_without_common_version() -> Result<()> {
+    let server = common::start_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(MAGIC)?;
+    stream.write_all(&[200, 250])?;
+
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer)?;
+    assert_eq!(&answer[..4], MAGIC);
+    assert_eq!(answer[4..], [0]);
+    Ok(())
+}
+
+// Pipelined results come back in request order over either protocol
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let server = common::start_server(|server| server)?;
+    let addr = server.addr.as_str();
+
+    for mut client in [Client::connect(addr)?, Client::connect_json(addr)?] {
+        let pairs: Vec<_> = (0..3000)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        client.set_many(pairs)?;
+
+        let mut pipeline = client.pipeline();
+        pipeline.get("key7".to_owned())?;
+        pipeline.remove("key7".to_owned())?;
+        pipeline.remove("missing".to_owned())?;
+        pipeline.set("key7".to_owned(), "again".to_owned())?;
+        let results = pipeline.finish()?;
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().ok(), Some(&Some("value7".to_owned())));
+        assert!(results[1].is_ok());
+        assert!(results[2].is_err());
+        assert!(results[3].is_ok());
+
+        let keys = vec![
+            "key0".to_owned(),
+            "missing".to_owned(),
+            "key2999".to_owned(),
+        ];
+        assert_eq!(
+            client.get_many(keys)?,
+            vec![
+                Some("value0".to_owned()),
+                None,
+                Some("value2999".to_owned())
+            ]
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,446 @@
+use assert_cmd::prelude::*;
+use project_3::protocol::{self, Frame, OP_REQUEST};
+use project_3::raft::{
+    ClusterStatus, Command as RaftCommand, Entry, RaftConfig, RaftMessage, RaftResponse, Role,
+    SnapshotMeta,
+};
+use project_3::{Auth, Client, Engine, Error, Request, Response, Result, Server, Store};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+const TOKEN: &str = "cluster-secret";
+
+/// Kills the member processes when the test ends, even if it fails.
+struct Members(Vec<(String, Child)>);
+
+impl Members {
+    fn kill(&mut self, addr: &str) {
+        let position = self.0.iter().position(|(a, _)| a == addr).unwrap();
+        let (_, mut child) = self.0.remove(position);
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    }
+}
+
+impl Drop for Members {
+    fn drop(&mut self) {
+        for (_, child) in &mut self.0 {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Speaks to a member as the leader of a cluster that only exists in the test.
+struct FakeLeader {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    id: u32,
+}
+
+impl FakeLeader {
+    fn connect(addr: &str, token: &str) -> Result<FakeLeader> {
+        let stream = TcpStream::connect(addr)?;
+        let mut leader = FakeLeader {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            id: 0,
+        };
+        protocol::connect(&mut leader.reader, &mut leader.writer)?;
+        let token = token.to_owned();
+        match leader.send(Request::Auth { token })? {
+            Response::Ok => Ok(leader),
+            other => panic!("expected to authenticate, got {:?}", other),
+        }
+    }
+
+    fn send(&mut self, request: Request) -> Result<Response> {
+        self.id += 1;
+        Frame::encode(OP_REQUEST, self.id, &request)?.write(&mut self.writer)?;
+        self.writer.flush()?;
+        Frame::read(&mut self.reader)?
+            .expect("connection closed")
+            .decode()
+    }
+
+    fn call(&mut self, message: RaftMessage) -> Result<RaftResponse> {
+        match self.send(Request::Raft(message))? {
+            Response::Raft(response) => Ok(response),
+            Response::Err(err) => Err(err.into()),
+            other => panic!("expected a Raft response, got {:?}", other),
+        }
+    }
+}
+
+/// Runs a cluster member in-process, its users being the cluster and alice.
+fn start_member(addr: &str, dir: &Path, members: Vec<String>) -> Result<()> {
+    let store = Store::open(dir)?;
+    let config = RaftConfig::new(addr, dir.join("raft"), |dir| {
+        Ok(Box::new(Store::open_read_only(dir)?) as Box<dyn Engine>)
+    })
+    .members(members)
+    .snapshot_threshold(10)
+    .snapshot_chunk(64);
+    let server = Server::new(store)
+        .auth(
+            Auth::default()
+                .user("cluster", TOKEN)
+                .user("alice", "alice-token"),
+        )
+        .token(TOKEN.to_owned())
+        .cluster(config);
+    let listen = addr.to_owned();
+    thread::spawn(move || server.run(listen));
+    common::wait_until_accepting(addr);
+    Ok(())
+}
+
+fn status(addr: &str) -> Result<ClusterStatus> {
+    Client::connect_with_auth(addr, TOKEN.to_owned())?.cluster_status()
+}
+
+/// Waits until the member at `addr` applied the entry at `index`.
+fn wait_applied(addr: &str, index: u64) -> Result<ClusterStatus> {
+    for _ in 0..50 {
+        let status = status(addr)?;
+        if status.last_applied >= index {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{} did not apply entry {}", addr, index);
+}
+
+fn scan(addr: &str) -> Result<Vec<(String, String)>> {
+    let mut client = Client::connect_with_auth(addr, TOKEN.to_owned())?;
+    let pairs = client.scan(String::new())?.collect();
+    pairs
+}
+
+fn pair(key: &str, value: &str) -> (String, String) {
+    (key.to_owned(), value.to_owned())
+}
+
+fn set(index: u64, term: u64, key: &str, value: &str) -> Entry {
+    Entry {
+        index,
+        term,
+        command: RaftCommand::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        },
+    }
+}
+
+fn wait_for_leader(addrs: &[&str]) -> String {
+    for _ in 0..100 {
+        for addr in addrs {
+            let status = status(addr);
+            if let Ok(status) = status {
+                if status.role == Role::Leader {
+                    return addr.to_string();
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("no leader elected among {:?}", addrs);
+}
+
+// Writes sent to any member should survive the loss of the leader
+#[test]
+fn cluster_failover() -> Result<()> {
+    let addrs: Vec<String> = (0..3).map(|_| common::free_addr()).collect();
+    let addrs: Vec<&str> = addrs.iter().map(String::as_str).collect();
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+
+    let mut members = Members(Vec::new());
+    for (addr, dir) in addrs.iter().zip(&dirs) {
+        let mut command = Command::cargo_bin("kvs-server").unwrap();
+        command.args([
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--cluster",
+            "--token",
+            TOKEN,
+        ]);
+        for peer in addrs.iter().filter(|peer| *peer != addr) {
+            command.args(["--peer", peer]);
+        }
+        let child = command.current_dir(dir).spawn().unwrap();
+        members.0.push((addr.to_string(), child));
+    }
+
+    let leader = wait_for_leader(&addrs);
+    let followers: Vec<&str> = addrs.iter().cloned().filter(|a| *a != leader).collect();
+
+    // Followers redirect the client to the leader
+    let connect = |addr| Client::connect_with_auth(addr, TOKEN.to_owned());
+    connect(followers[0])?.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        connect(followers[1])?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    members.kill(&leader);
+    let new_leader = wait_for_leader(&followers);
+    assert_ne!(new_leader, leader);
+
+    let mut client = connect(followers[0])?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        connect(followers[1])?.get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// A member added after the log was compacted should catch up from a snapshot
+#[test]
+fn snapshot_catch_up() -> Result<()> {
+    let (leader_addr, member_addr) = (common::free_addr(), common::free_addr());
+    let (leader_addr, member_addr) = (leader_addr.as_str(), member_addr.as_str());
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let member_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    start_member(leader_addr, leader_dir.path(), vec![leader_addr.to_owned()])?;
+    start_member(member_addr, member_dir.path(), vec![])?;
+    wait_for_leader(&[leader_addr]);
+
+    let mut client = Client::connect_with_auth(leader_addr, TOKEN.to_owned())?;
+    for i in 0..50 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(client.cluster_status()?.snapshot_index >= 10);
+
+    let status = client.add_member(member_addr.to_owned())?;
+    let mut expected = vec![leader_addr, member_addr];
+    expected.sort();
+    assert_eq!(status.members, expected);
+
+    // The snapshot is sent in many small chunks
+    let mut member = Client::connect_with_auth(member_addr, TOKEN.to_owned())?;
+    let status = member.cluster_status()?;
+    assert_eq!(status.role, Role::Follower);
+    assert_eq!(status.leader.as_deref(), Some(leader_addr));
+    assert!(status.snapshot_index >= 10);
+    // Scans are served from the member's own engine, which catches up shortly
+    for _ in 0..50 {
+        if member.scan("key".to_owned())?.count() == 50 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(member.scan("key".to_owned())?.count(), 50);
+
+    client.set("key50".to_owned(), "value50".to_owned())?;
+    assert_eq!(member.get("key50".to_owned())?, Some("value50".to_owned()));
+    member.remove("key50".to_owned())?;
+    match member.remove("key50".to_owned()) {
+        Err(Error::KeyNotFound) => {}
+        other => panic!("expected the key not to be found, got {:?}", other),
+    }
+
+    let status = client.remove_member(member_addr.to_owned())?;
+    assert_eq!(status.members, vec![leader_addr]);
+    client.set("key51".to_owned(), "value51".to_owned())?;
+    Ok(())
+}
+
+// Cluster members must authenticate each other with the server's token
+#[test]
+fn cluster_requires_token() -> Result<()> {
+    let addr = common::free_addr();
+    let addr = addr.as_str();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = RaftConfig::new(addr, temp_dir.path().join("raft"), |dir| {
+        Ok(Box::new(Store::open_read_only(dir)?) as Box<dyn Engine>)
+    });
+    match Server::new(Store::open(temp_dir.path())?)
+        .cluster(config)
+        .run(addr)
+    {
+        Err(Error::Auth(_)) => {}
+        other => panic!("expected an authentication error, got {:?}", other),
+    }
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--cluster"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// A follower drops the entries a new leader replaces, and only takes Raft
+// messages from cluster members
+#[test]
+fn log_conflict_truncation() -> Result<()> {
+    let (addr, leader_addr) = (common::free_addr(), common::free_addr());
+    let (addr, leader_addr) = (addr.as_str(), leader_addr.as_str());
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    start_member(
+        addr,
+        temp_dir.path(),
+        vec![leader_addr.to_owned(), addr.to_owned()],
+    )?;
+
+    let term = status(addr)?.term + 10;
+    let heartbeat = RaftMessage::AppendEntries {
+        term,
+        leader: leader_addr.to_owned(),
+        prev_log_index: 0,
+        prev_log_term: 0,
+        entries: vec![],
+        leader_commit: 0,
+    };
+    let mut alice = FakeLeader::connect(addr, "alice-token")?;
+    match alice.call(heartbeat) {
+        Err(Error::PermissionDenied(_)) => {}
+        other => panic!("expected the permission to be denied, got {:?}", other),
+    }
+
+    let mut leader = FakeLeader::connect(addr, TOKEN)?;
+    let response = leader.call(RaftMessage::AppendEntries {
+        term,
+        leader: leader_addr.to_owned(),
+        prev_log_index: 0,
+        prev_log_term: 0,
+        entries: vec![
+            set(1, term, "a", "1"),
+            set(2, term, "b", "1"),
+            set(3, term, "c", "1"),
+        ],
+        leader_commit: 1,
+    })?;
+    assert!(matches!(
+        response,
+        RaftResponse::Append {
+            success: true,
+            match_index: 3,
+            ..
+        }
+    ));
+
+    // A leader of the next term overwrites the uncommitted entries 2 and 3
+    let response = leader.call(RaftMessage::AppendEntries {
+        term: term + 1,
+        leader: leader_addr.to_owned(),
+        prev_log_index: 1,
+        prev_log_term: term,
+        entries: vec![set(2, term + 1, "b", "2")],
+        leader_commit: 2,
+    })?;
+    assert!(matches!(
+        response,
+        RaftResponse::Append {
+            success: true,
+            match_index: 2,
+            ..
+        }
+    ));
+    let response = leader.call(RaftMessage::AppendEntries {
+        term: term + 1,
+        leader: leader_addr.to_owned(),
+        prev_log_index: 3,
+        prev_log_term: term,
+        entries: vec![],
+        leader_commit: 2,
+    })?;
+    assert!(matches!(
+        response,
+        RaftResponse::Append {
+            success: false,
+            match_index: 2,
+            ..
+        }
+    ));
+
+    wait_applied(addr, 2)?;
+    assert_eq!(scan(addr)?, vec![pair("a", "1"), pair("b", "2")]);
+    Ok(())
+}
+
+// A follower installs a snapshot received in chunks in place of its state
+#[test]
+fn snapshot_install() -> Result<()> {
+    let (addr, leader_addr) = (common::free_addr(), common::free_addr());
+    let (addr, leader_addr) = (addr.as_str(), leader_addr.as_str());
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let members = vec![leader_addr.to_owned(), addr.to_owned()];
+    start_member(addr, temp_dir.path(), members.clone())?;
+
+    let term = status(addr)?.term + 10;
+    let mut leader = FakeLeader::connect(addr, TOKEN)?;
+    leader.call(RaftMessage::AppendEntries {
+        term,
+        leader: leader_addr.to_owned(),
+        prev_log_index: 0,
+        prev_log_term: 0,
+        entries: vec![set(1, term, "stale", "1")],
+        leader_commit: 1,
+    })?;
+    wait_applied(addr, 1)?;
+
+    let meta = SnapshotMeta {
+        index: 20,
+        term,
+        members: members.into_iter().collect(),
+    };
+    let mut chunk = |offset, pairs, done| -> Result<bool> {
+        let response = leader.call(RaftMessage::InstallSnapshot {
+            term,
+            leader: leader_addr.to_owned(),
+            meta: meta.clone(),
+            offset,
+            pairs,
+            done,
+        })?;
+        match response {
+            RaftResponse::Snapshot { success, .. } => Ok(success),
+            other => panic!("expected a snapshot response, got {:?}", other),
+        }
+    };
+
+    assert!(chunk(0, vec![pair("k0", "0"), pair("k1", "1")], false)?);
+    // A chunk that does not follow the ones received is refused
+    assert!(!chunk(3, vec![pair("k3", "3")], true)?);
+    assert!(chunk(0, vec![pair("k0", "0"), pair("k1", "1")], false)?);
+    assert!(chunk(2, vec![pair("k2", "2")], true)?);
+
+    let status = wait_applied(addr, 20)?;
+    assert_eq!(status.snapshot_index, 20);
+    assert_eq!(
+        scan(addr)?,
+        vec![pair("k0", "0"), pair("k1", "1"), pair("k2", "2")]
+    );
+
+    let response = leader.call(RaftMessage::AppendEntries {
+        term,
+        leader: leader_addr.to_owned(),
+        prev_log_index: 20,
+        prev_log_term: term,
+        entries: vec![set(21, term, "k3", "3")],
+        leader_commit: 21,
+    })?;
+    assert!(matches!(
+        response,
+        RaftResponse::Append {
+            success: true,
+            match_index: 21,
+            ..
+        }
+    ));
+    Ok(())
+}
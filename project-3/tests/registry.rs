@@ -0,0 +1,98 @@
+use project_3::{Engine, EngineConfig, Error, Registry, Result, Stats};
+use std::path::Path;
+use tempfile::TempDir;
+
+// Should open every built-in engine by name
+#[test]
+fn open_builtin_engines() -> Result<()> {
+    let registry = Registry::default();
+
+    for name in &["kvs", "sled"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = registry.open(name, &EngineConfig::new(temp_dir.path()))?;
+
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn open_unknown_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let res = Registry::default().open("unknown", &EngineConfig::new(temp_dir.path()));
+    assert!(matches!(res, Err(Error::UnknownEngine(_))));
+}
+
+// Wrappers are applied on top of the opened engine
+#[test]
+fn read_only_wrapper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let registry = Registry::default();
+    let config = EngineConfig::new(temp_dir.path());
+
+    let mut engine = registry.open("kvs", &config)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+
+    let mut engine = registry.build("kvs", &["read-only"], &config)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        engine.set("key1".to_owned(), "value2".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(Error::ReadOnly)
+    ));
+
+    Ok(())
+}
+
+// A custom engine can be registered and composed with the built-in wrappers
+#[test]
+fn register_custom_engine() -> Result<()> {
+    struct Constant;
+
+    impl Engine for Constant {
+        fn set(&mut self, _key: String, _value: String) -> Result<()> {
+            Ok(())
+        }
+        fn get(&mut self, _key: String) -> Result<Option<String>> {
+            Ok(Some("constant".to_owned()))
+        }
+        fn remove(&mut self, _key: String) -> Result<()> {
+            Ok(())
+        }
+        fn keys(&mut self) -> Result<Vec<String>> {
+            Ok(Vec::new())
+        }
+        fn backup(&mut self, _target: &Path) -> Result<()> {
+            Ok(())
+        }
+        fn stats(&mut self) -> Result<Stats> {
+            Ok(Stats::default())
+        }
+        fn compact(&mut self) -> Result<()> {
+            Ok(())
+        }
+        fn pause_compaction(&mut self, _paused: bool) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut registry = Registry::default();
+    registry.register_engine("constant", |_| Ok(Box::new(Constant)));
+
+    let mut engine = registry.build(
+        "constant",
+        &["read-only"],
+        &EngineConfig::new(temp_dir.path()),
+    )?;
+    assert_eq!(engine.get("any".to_owned())?, Some("constant".to_owned()));
+    assert!(engine.set("any".to_owned(), "value".to_owned()).is_err());
+
+    Ok(())
+}
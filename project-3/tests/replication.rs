@@ -0,0 +1,65 @@
+use project_3::{Client, Engine, Result, Server, Sled};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+mod common;
+
+fn wait_for(addr: &str, key: &str, expected: Option<&str>) -> Result<()> {
+    let mut client = Client::connect(addr)?;
+    for _ in 0..50 {
+        if client.get(key.to_owned())?.as_deref() == expected {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{} never became {:?} on {}", key, expected, addr);
+}
+
+// Should drop local keys missing from the snapshot, then follow the primary's changes
+#[test]
+fn replica_converges_on_primary() -> Result<()> {
+    let server = common::start_server(|server| server)?;
+    let primary_addr = server.addr.as_str();
+    let replica_addr = common::free_addr();
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut primary = Client::connect(primary_addr)?;
+    primary.set("shared".to_owned(), "primary".to_owned())?;
+    primary.set("primary".to_owned(), "primary".to_owned())?;
+    let mut replica_store = Sled::new(sled::open(replica_dir.path())?);
+    replica_store.set("shared".to_owned(), "stale".to_owned())?;
+    replica_store.set("local".to_owned(), "stale".to_owned())?;
+    replica_store.set("another".to_owned(), "stale".to_owned())?;
+    replica_store.set("zzz".to_owned(), "stale".to_owned())?;
+
+    let follow = primary_addr.to_owned();
+    let listen = replica_addr.clone();
+    thread::spawn(move || Server::new(replica_store).replica_of(follow).run(listen));
+    common::wait_until_accepting(&replica_addr);
+    let replica_addr = replica_addr.as_str();
+
+    wait_for(replica_addr, "shared", Some("primary"))?;
+    wait_for(replica_addr, "primary", Some("primary"))?;
+    wait_for(replica_addr, "local", None)?;
+    wait_for(replica_addr, "another", None)?;
+    wait_for(replica_addr, "zzz", None)?;
+
+    for i in 0..100 {
+        primary.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    primary.remove("shared".to_owned())?;
+    wait_for(replica_addr, "shared", None)?;
+    wait_for(replica_addr, "key99", Some("value99"))?;
+
+    let mut replica = Client::connect(replica_addr)?;
+    assert!(replica.set("key".to_owned(), "value".to_owned()).is_err());
+    replica.promote()?;
+    replica.set("key".to_owned(), "value".to_owned())?;
+
+    // Changes on the old primary no longer reach the promoted replica
+    primary.set("key0".to_owned(), "changed".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(replica.get("key0".to_owned())?, Some("value0".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use project_3::{Protocol, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+mod common;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+/// A minimal RESP2 client.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: &str) -> Result<RespClient> {
+        let writer = TcpStream::connect(addr)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(RespClient { reader, writer })
+    }
+
+    fn send(&mut self, args: &[&str]) -> Result<()> {
+        let mut command = format!("*{}\r\n", args.len());
+        for arg in args {
+            command.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.writer.write_all(command.as_bytes())?;
+        Ok(())
+    }
+
+    fn call(&mut self, args: &[&str]) -> Result<Reply> {
+        self.send(args)?;
+        self.read()
+    }
+
+    fn read(&mut self) -> Result<Reply> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let (kind, rest) = line.trim_end().split_at(1);
+        Ok(match kind {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut data = vec![0; len as usize + 2];
+                    self.reader.read_exact(&mut data)?;
+                    data.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(data)?))
+                }
+            },
+            "*" => Reply::Array(
+                (0..rest.parse::<usize>().unwrap())
+                    .map(|_| self.read())
+                    .collect::<Result<_>>()?,
+            ),
+            _ => panic!("unexpected reply {:?}", line),
+        })
+    }
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let server = common::start_server(|server| server.protocol(Protocol::Resp))?;
+    let addr = server.addr.as_str();
+    let mut client = RespClient::connect(addr)?;
+
+    assert_eq!(client.call(&["PING"])?, Reply::Simple("PONG".to_owned()));
+    assert_eq!(
+        client.call(&["set", "a", "1"])?,
+        Reply::Simple("OK".to_owned())
+    );
+    assert_eq!(client.call(&["GET", "a"])?, bulk("1"));
+    assert_eq!(client.call(&["GET", "missing"])?, Reply::Bulk(None));
+    assert_eq!(
+        client.call(&["MSET", "b", "2", "c", "3", "other", "4"])?,
+        Reply::Simple("OK".to_owned())
+    );
+    assert_eq!(
+        client.call(&["MGET", "a", "missing", "c"])?,
+        Reply::Array(vec![bulk("1"), Reply::Bulk(None), bulk("3")])
+    );
+    assert_eq!(
+        client.call(&["EXISTS", "a", "b", "missing"])?,
+        Reply::Integer(2)
+    );
+    assert_eq!(
+        client.call(&["KEYS", "[a-c]"])?,
+        Reply::Array(vec![bulk("a"), bulk("b"), bulk("c")])
+    );
+    assert_eq!(client.call(&["DEL", "a", "missing"])?, Reply::Integer(1));
+    assert_eq!(client.call(&["EXISTS", "a"])?, Reply::Integer(0));
+
+    match client.call(&["INFO", "keyspace"])? {
+        Reply::Bulk(Some(info)) => assert!(info.contains("db0:keys=3"), "{}", info),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match client.call(&["GET"])? {
+        Reply::Error(message) => assert!(message.contains("wrong number of arguments")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    match client.call(&["FLUSHALL"])? {
+        Reply::Error(message) => assert!(message.starts_with("ERR unknown command")),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+
+    // Patterns that backtrack badly still answer at once
+    client.call(&["SET", &"a".repeat(64), "1"])?;
+    assert_eq!(
+        client.call(&["KEYS", "*a*a*a*a*a*a*a*a*a*a*a*a*b"])?,
+        Reply::Array(vec![])
+    );
+    Ok(())
+}
+
+// Walks every key with SCAN, with commands pipelined before reading replies
+#[test]
+fn resp_scan_and_pipelining() -> Result<()> {
+    let server = common::start_server(|server| server.protocol(Protocol::Resp))?;
+    let addr = server.addr.as_str();
+    let mut client = RespClient::connect(addr)?;
+
+    for i in 0..25 {
+        client.send(&["SET", &format!("key{:02}", i), "value"])?;
+    }
+    client.send(&["SET", "skip", "value"])?;
+    for _ in 0..26 {
+        assert_eq!(client.read()?, Reply::Simple("OK".to_owned()));
+    }
+
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        let reply = client.call(&["SCAN", &cursor, "MATCH", "key*", "COUNT", "7"])?;
+        match reply {
+            Reply::Array(mut parts) if parts.len() == 2 => {
+                if let Reply::Array(page) = parts.pop().unwrap() {
+                    keys.extend(page);
+                }
+                cursor = match parts.pop().unwrap() {
+                    Reply::Bulk(Some(cursor)) => cursor,
+                    reply => panic!("unexpected cursor {:?}", reply),
+                };
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys.len(), 25);
+
+    // Inline commands work too, as sent by telnet
+    client.writer.write_all(b"GET key00\r\n")?;
+    assert_eq!(client.read()?, bulk("value"));
+    Ok(())
+}
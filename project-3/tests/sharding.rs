@@ -0,0 +1,107 @@
+use project_3::{
+    rebalance, Addr, Auth, Connector, HashRing, Result, ShardedClient, DEFAULT_VNODES,
+};
+use std::collections::HashMap;
+
+mod common;
+
+fn keys() -> Vec<String> {
+    (0..1000).map(|i| format!("key{}", i)).collect()
+}
+
+// Should spread keys over all nodes and route them the same way every time
+#[test]
+fn ring_distribution() {
+    let ring = HashRing::with_nodes(160, &["a:1", "b:1", "c:1"]);
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for key in keys() {
+        *counts.entry(ring.node_for(&key).unwrap()).or_default() += 1;
+    }
+
+    assert_eq!(counts.len(), 3);
+    for count in counts.values() {
+        assert!(*count > 200, "unbalanced ring: {:?}", counts);
+    }
+
+    let same = HashRing::with_nodes(160, &["c:1", "a:1", "b:1"]);
+    for key in keys() {
+        assert_eq!(ring.node_for(&key), same.node_for(&key));
+    }
+    assert_eq!(HashRing::new(160).node_for("key"), None);
+}
+
+// Should only move keys onto an added node, and only off a removed one
+#[test]
+fn ring_minimal_movement() {
+    let before = HashRing::with_nodes(160, &["a:1", "b:1", "c:1"]);
+    let mut after = before.clone();
+    after.add("d:1");
+
+    let mut moved = 0;
+    for key in keys() {
+        let (old, new) = (
+            before.node_for(&key).unwrap(),
+            after.node_for(&key).unwrap(),
+        );
+        if old != new {
+            assert_eq!(new, "d:1");
+            moved += 1;
+        }
+    }
+    assert!(moved > 100 && moved < 400, "moved {} keys", moved);
+
+    after.remove("d:1");
+    for key in keys() {
+        assert_eq!(before.node_for(&key), after.node_for(&key));
+    }
+    assert_eq!(after.nodes(), vec!["a:1", "b:1", "c:1"]);
+}
+
+// Should keep every key reachable after a node is added and keys are rebalanced,
+// on servers requiring a token
+#[test]
+fn sharded_client_rebalance() -> Result<()> {
+    let mut servers = Vec::new();
+    for _ in 0..3 {
+        let auth = Auth::default().user("shards", "secret");
+        servers.push(common::start_server(move |server| server.auth(auth))?);
+    }
+    let nodes: Vec<&str> = servers.iter().map(|server| server.addr.as_str()).collect();
+
+    let connector = Connector::new().token("secret".to_owned());
+    let old_nodes = &nodes[..2];
+    assert!(ShardedClient::connect(old_nodes)?
+        .set("key0".to_owned(), "value0".to_owned())
+        .is_err());
+    let mut client = ShardedClient::with_connector(old_nodes, DEFAULT_VNODES, connector.clone())?;
+    for i in 0..200 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    assert!(client.remove("key0".to_owned()).is_err());
+
+    let report = rebalance(old_nodes, &nodes, DEFAULT_VNODES, &connector)?;
+    assert_eq!(report.scanned, 199);
+    assert!(report.moved > 0 && report.moved < 199);
+
+    let mut client = ShardedClient::with_connector(&nodes, DEFAULT_VNODES, connector.clone())?;
+    assert_eq!(client.get("key0".to_owned())?, None);
+    for i in 1..200 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // Each key lives on exactly one server
+    let held: usize = nodes
+        .iter()
+        .map(|node| {
+            let mut client = connector.connect(&Addr::Tcp(node.to_string()))?;
+            let count = client.scan(String::new())?.count();
+            Ok(count)
+        })
+        .sum::<Result<usize>>()?;
+    assert_eq!(held, 199);
+    Ok(())
+}
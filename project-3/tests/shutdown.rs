@@ -0,0 +1,121 @@
+use assert_cmd::prelude::*;
+use project_3::{AsyncServer, Client, Engine, Result, Server, Store};
+use std::process::Command;
+use std::thread;
+use tempfile::TempDir;
+
+mod common;
+
+// Shutting down lets idle and streaming connections go, then releases the store
+#[test]
+fn shutdown_handle_stops_server() -> Result<()> {
+    let addr = common::free_addr();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = Server::new(Store::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let listen = addr.clone();
+    let running = thread::spawn(move || server.run(listen));
+    common::wait_until_accepting(&addr);
+    let addr = addr.as_str();
+
+    let mut client = Client::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let mut watch = Client::connect(addr)?.watch(String::new(), None)?;
+
+    handle.shutdown();
+    running.join().expect("server thread panicked")?;
+    assert!(watch.next().is_none());
+    assert!(client.get("key".to_owned()).is_err());
+
+    let mut store = Store::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// SIGTERM makes kvs-server exit cleanly instead of dying mid-write
+#[cfg(unix)]
+#[test]
+fn sigterm_stops_server() -> Result<()> {
+    let addr = common::free_addr();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(&addr);
+
+    let set = Client::connect(&addr)
+        .and_then(|mut client| client.set("key".to_owned(), "value".to_owned()));
+    let killed = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .expect("unable to run kill");
+    let exited = server.wait()?;
+    set?;
+    assert!(killed.success());
+    assert!(exited.success());
+
+    let mut store = Store::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The async server drains and syncs the same way
+#[test]
+fn shutdown_handle_stops_async_server() -> Result<()> {
+    let addr = common::free_addr();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = AsyncServer::new(Store::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let listen = addr.clone();
+    let running = thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(server.run(listen))
+    });
+    common::wait_until_accepting(&addr);
+    let addr = addr.as_str();
+
+    let mut client = Client::connect(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    handle.shutdown();
+    running.join().expect("server thread panicked")?;
+    assert!(client.get("key".to_owned()).is_err());
+
+    let mut store = Store::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// SIGTERM stops kvs-server --async cleanly too
+#[cfg(unix)]
+#[test]
+fn sigterm_stops_async_server() -> Result<()> {
+    let addr = common::free_addr();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--engine", "kvs", "--async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    common::wait_until_accepting(&addr);
+
+    let set = Client::connect(&addr)
+        .and_then(|mut client| client.set("key".to_owned(), "value".to_owned()));
+    let killed = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .expect("unable to run kill");
+    let exited = server.wait()?;
+    set?;
+    assert!(killed.success());
+    assert!(exited.success());
+
+    let mut store = Store::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
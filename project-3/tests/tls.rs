@@ -0,0 +1,137 @@
+use assert_cmd::prelude::*;
+use project_3::{Client, ClientTls, Error, Result, Server, ServerTls, Store};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+mod common;
+
+use common::TestServer;
+
+/// A certificate authority whose certificates are written as PEM files.
+struct Ca {
+    cert: Certificate,
+    key: KeyPair,
+    path: PathBuf,
+}
+
+impl Ca {
+    fn new(dir: &Path, name: &str) -> Ca {
+        let mut params = CertificateParams::new(Vec::new()).expect("CA parameters");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().expect("CA key");
+        let cert = params.self_signed(&key).expect("CA certificate");
+        let path = dir.join(format!("{}.pem", name));
+        fs::write(&path, cert.pem()).expect("unable to write the CA certificate");
+        Ca { cert, key, path }
+    }
+
+    /// Issues a certificate for `names`, returning its chain and key files.
+    fn issue(&self, dir: &Path, name: &str, names: &[&str]) -> (PathBuf, PathBuf) {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let params = CertificateParams::new(names).expect("certificate parameters");
+        let key = KeyPair::generate().expect("certificate key");
+        let cert = params
+            .signed_by(&key, &self.cert, &self.key)
+            .expect("certificate");
+        let cert_path = dir.join(format!("{}.pem", name));
+        let key_path = dir.join(format!("{}-key.pem", name));
+        fs::write(&cert_path, cert.pem()).expect("unable to write the certificate");
+        fs::write(&key_path, key.serialize_pem()).expect("unable to write the key");
+        (cert_path, key_path)
+    }
+}
+
+fn start_server(tls: ServerTls) -> Result<TestServer> {
+    common::start_server(move |server| server.tls(tls))
+}
+
+// Clients trusting the server's CA talk to it over TLS
+#[test]
+fn tls_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let (cert, key) = ca.issue(temp_dir.path(), "server", &["127.0.0.1", "localhost"]);
+    let server = start_server(ServerTls::load(&cert, &key, None)?)?;
+    let addr = server.addr.as_str();
+
+    let tls = ClientTls::load(&ca.path, None)?;
+    let mut client = Client::connect_tls(addr, &tls)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    let mut client = Client::connect_tls(&addr.replace("127.0.0.1", "localhost"), &tls)?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr, "--ca"])
+        .arg(&ca.path)
+        .env_remove("KVS_TOKEN")
+        .assert()
+        .success()
+        .stdout("value\n");
+
+    let plain = Client::connect(addr).and_then(|mut client| client.get("key".to_owned()));
+    assert!(plain.is_err());
+    Ok(())
+}
+
+// A server certified by another CA is refused
+#[test]
+fn untrusted_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let other = Ca::new(temp_dir.path(), "other");
+    let (cert, key) = ca.issue(temp_dir.path(), "server", &["127.0.0.1"]);
+    let server = start_server(ServerTls::load(&cert, &key, None)?)?;
+    let addr = server.addr.as_str();
+
+    match Client::connect_tls(addr, &ClientTls::load(&other.path, None)?) {
+        Err(Error::Tls(_)) => {}
+        Err(err) => panic!("expected a TLS error, got {:?}", err),
+        Ok(_) => panic!("trusted a server certified by another CA"),
+    }
+    Ok(())
+}
+
+// With a client CA, only clients presenting a certificate it signed get in
+#[test]
+fn mutual_tls() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let (cert, key) = ca.issue(temp_dir.path(), "server", &["127.0.0.1"]);
+    let (client_cert, client_key) = ca.issue(temp_dir.path(), "client", &["client"]);
+    let tls = ServerTls::load(&cert, &key, Some(&ca.path))?;
+    let server = start_server(tls)?;
+    let addr = server.addr.as_str();
+
+    let anonymous = ClientTls::load(&ca.path, None)?;
+    let refused = Client::connect_tls(addr, &anonymous)
+        .and_then(|mut client| client.set("key".to_owned(), "value".to_owned()));
+    assert!(refused.is_err());
+
+    let identified = ClientTls::load(&ca.path, Some((&client_cert, &client_key)))?;
+    let mut client = Client::connect_tls(addr, &identified)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The plain HTTP gateway is not served next to TLS
+#[test]
+fn tls_excludes_http_gateway() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let ca = Ca::new(temp_dir.path(), "ca");
+    let (cert, key) = ca.issue(temp_dir.path(), "server", &["127.0.0.1"]);
+    let server = Server::new(Store::open(temp_dir.path())?)
+        .tls(ServerTls::load(&cert, &key, None)?)
+        .http(common::free_addr());
+    match server.run(common::free_addr()) {
+        Err(Error::Tls(_)) => {}
+        other => panic!("expected a TLS error, got {:?}", other),
+    }
+    Ok(())
+}
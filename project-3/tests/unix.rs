@@ -0,0 +1,68 @@
+#![cfg(unix)]
+
+use assert_cmd::prelude::*;
+use project_3::{Client, Error, Result, Server, Store};
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixListener;
+use std::process::Command;
+use std::thread;
+use tempfile::TempDir;
+
+mod common;
+
+// Clients on the same host reach the server through a Unix socket
+#[test]
+fn unix_roundtrip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    let server = Server::new(Store::open(temp_dir.path())?);
+    let socket = path.clone();
+    thread::spawn(move || server.run_unix(socket));
+    common::wait_until_accepting_unix(&path);
+
+    let mut client = Client::connect_unix(&path)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr"])
+        .arg(format!("unix:{}", path.display()))
+        .env_remove("KVS_TOKEN")
+        .assert()
+        .success()
+        .stdout("value\n");
+    Ok(())
+}
+
+// A socket file left behind is replaced, and removed again on shutdown,
+// while any other file in the way is kept
+#[test]
+fn socket_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    fs::write(&path, "precious")?;
+    match Server::new(Store::open(temp_dir.path())?).run_unix(&path) {
+        Err(Error::IO(err)) if err.kind() == io::ErrorKind::AddrInUse => {}
+        other => panic!("expected the address in use, got {:?}", other),
+    }
+    assert_eq!(fs::read_to_string(&path)?, "precious");
+    fs::remove_file(&path)?;
+    drop(UnixListener::bind(&path)?);
+
+    let server = Server::new(Store::open(temp_dir.path())?);
+    let handle = server.shutdown_handle();
+    let socket = path.clone();
+    let running = thread::spawn(move || server.run_unix(socket));
+    common::wait_until_accepting_unix(&path);
+
+    let mut client = Client::connect_unix(&path)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+
+    handle.shutdown();
+    running.join().expect("server thread panicked")?;
+    assert!(!path.exists());
+    assert!(Client::connect_unix(&path).is_err());
+    Ok(())
+}